@@ -3,7 +3,9 @@ use proc_macro2::{Span, TokenStream as TokenStream2};
 
 use quote::{format_ident, quote, quote_spanned};
 use syn::spanned::Spanned;
-use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+use std::collections::BTreeMap;
+
+use syn::{parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Error, Fields, Generics, GenericParam, Lit, Meta, NestedMeta};
 
 
 macro_rules! derive_error {
@@ -14,171 +16,112 @@ macro_rules! derive_error {
     };
 }
 
-#[proc_macro_derive(NetworkSerializable)]
-pub fn derive_is_variant(input: TokenStream) -> TokenStream {
+/// Derive the `NetworkSerializable` trait on a struct or an enum.
+///
+/// Fields are written one after the other, and each field reads back exactly the bytes it wrote.
+/// Enums are prefixed by the id of the variant, as a u64.
+/// By default, ids follow the declaration order, so reordering variants breaks compatibility between builds.
+/// Variants accept a `#[network(id = N)]` attribute to fix their id, and the following variants count up from it.
+///
+/// A schema hash is also generated from the ids and the field types, to detect peers built with different messages.
+/// Recursive messages (holding themselves through a box or a vector) are hashed by name where they recurse.
+/// The generated code uses `NetworkSerializable`, `NetworkUnserializeError`, `ByteWriter`, `ByteReader` and `guarded_schema_hash` :
+/// they must be in scope where the trait is derived.
+///
+/// Fields accept the following attributes :
+/// - `#[network(skip)]` : the field is never sent, and is set to `Default::default()` when received.
+/// - `#[network(default)]` : the field is sent, but if the received data stops before it,
+///   it is set to `Default::default()`. This allows to add fields at the end of a message without breaking older peers.
+///   Only the last sent fields can have it : a field after it must have it too, or be skipped.
+///   The end of the data is the end of the whole message, so it only works on the message that is sent :
+///   a type held in another message only gets its defaults if nothing of the outer message follows it.
+#[proc_macro_derive(NetworkSerializable, attributes(network))]
+pub fn derive_network_serializable(input: TokenStream) -> TokenStream {
     // See https://doc.servo.org/syn/derive/struct.DeriveInput.html
     let input: DeriveInput = parse_macro_input!(input as DeriveInput);
-    
+
     // get enum name
-    let ref name = input.ident;
-    let ref data = input.data;
+    let name = &input.ident;
+    let data = &input.data;
+    let generics = add_trait_bounds(input.generics.clone());
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
     // data is of type syn::Data
     // See https://doc.servo.org/syn/enum.Data.html
-    let (size_func, serialize_func, deserialize_func) = match data {
+    let (size_func, serialize_func, deserialize_func, schema_func) = match data {
         // Only if data is an enum, we do parsing
         Data::Enum(enum_data) => {
 
             // data_enum is of type syn::DataEnum
             // https://doc.servo.org/syn/struct.DataEnum.html
 
-            // assign an id to each variant
-            let mut variant_id: usize = 0;
-
             // size function : returns size of the data of the enum
             let mut size_func_internal = TokenStream2::new();
-            // serialize function : returns Vec<u8> being the raw data.
+            // serialize function : writes the raw data in the buffer.
             let mut serialize_func_internal = TokenStream2::new();
-            // deserialize function : returns Self from id and Vec<u8>
+            // deserialize function : returns Self from id and the reader
             let mut deserialize_func_internal = TokenStream2::new();
+            // schema of each variant, sorted by id so reordering variants with explicit ids keeps the hash
+            let mut schemas = BTreeMap::new();
+            let mut next_id: u64 = 0;
 
             // Iterate over enum variants
             // `variants` if of type `Punctuated` which implements IntoIterator
             //
             // https://doc.servo.org/syn/punctuated/struct.Punctuated.html
             // https://doc.servo.org/syn/struct.Variant.html
-            for variant in &enum_data.variants {
+            for variant in enum_data.variants.iter() {
+
+                // an id is assigned to each variant, in declaration order or from the attribute
+                let variant_id = match variant_id(&variant.attrs, next_id) {
+                    Ok(id) => id,
+                    Err(e) => return e.to_compile_error().into(),
+                };
+                next_id = variant_id.wrapping_add(1);
 
                 // Variant's name
-                let ref variant_name = variant.ident;
+                let variant_name = &variant.ident;
 
                 // Variant can have unnamed fields like `Variant(i32, i64)`
                 // Variant can have named fields like `Variant {x: i32, y: i32}`
                 // Variant can be named Unit like `Variant`
-                let fields_in_variant = match &variant.fields {
-                    Fields::Unnamed(_) => quote_spanned! {variant.span()=> (..) },
-                    Fields::Unit => quote_spanned! { variant.span()=> },
-                    Fields::Named(_) => quote_spanned! {variant.span()=> {..} },
+                let fields = match FieldsCode::new(&variant.fields) {
+                    Ok(fields) => fields,
+                    Err(e) => return e.to_compile_error().into(),
                 };
+                let pattern = &fields.pattern;
+                let size = &fields.size;
+                let serialize = &fields.serialize;
+                let deserialize = &fields.deserialize;
+                let construct = &fields.construct;
+
+                if schemas.insert(variant_id, (fields.schema.clone(), fields.schema_fields.clone())).is_some() {
+                    return Error::new(variant.span(), format!("network id {variant_id} is used by another variant"))
+                        .to_compile_error()
+                        .into();
+                }
 
                 // size match arm for the current variant (compute size from fields)
-                let variant_size = match &variant.fields {
-                    Fields::Unit => quote!{std::mem::size_of::<u64>()}, // no fields, message of size usize (id)
-                    Fields::Unnamed(fields) => {
-                        let mut field_sum = quote!{std::mem::size_of::<u64>()};
-                        for field in fields.unnamed.iter() {
-                            field_sum.extend(quote!{+ std::mem::size_of::<#field>()})
-                        }
-                        field_sum
-                    },
-                    Fields::Named(fields) => {
-                        let mut field_sum = quote!{std::mem::size_of::<u64>()};
-                        for field in fields.named.iter() {
-                            field_sum.extend(quote!{+ std::mem::size_of::<#field>()})
-                        }
-                        field_sum
-                    },
-                };
                 size_func_internal.extend(quote_spanned! {variant.span()=>
-                    #name::#variant_name #fields_in_variant => #variant_size,
+                    Self::#variant_name #pattern => std::mem::size_of::<u64>() #size,
                 });
 
-                serialize_func_internal.extend(match &variant.fields {
-                    Fields::Unit => quote_spanned! {variant.span()=>
-                        #name::#variant_name => NetworkSerializable::serialize(#variant_id as u64),
-                    }, // no fields, message of size 0
-                    Fields::Unnamed(fields) => {
-                        let field_name: Vec<_> = fields.unnamed.iter().enumerate().map(|(i, _)| {
-                            format_ident!("field{}", i)
-                        }).collect();
-                        
-                        quote_spanned! {variant.span()=>
-                            #name::#variant_name (#(#field_name,)*) => {
-                                let mut result = Vec::with_capacity(self.size());
-                                result.append(&mut NetworkSerializable::serialize(#variant_id as u64));
-                        
-                                #(
-                                    result.append(&mut NetworkSerializable::serialize(#field_name));
-                                )*
-                        
-                                result
-                            },
-                        }
-                    },
-                    Fields::Named(fields) => {
-                        let field_name: Vec<_> = fields.named.iter().enumerate().map(|(i, _)| {
-                            format_ident!("field{}", i)
-                        }).collect();
-                        
-                        quote_spanned! {variant.span()=>
-                            #name::#variant_name (#(#field_name,)*) => {
-                                let mut result = Vec::with_capacity(self.size());
-                                result.append(&mut NetworkSerializable::serialize(#variant_id as u64));
-                        
-                                #(
-                                    result.append(&mut NetworkSerializable::serialize(#field_name));
-                                )*
-                        
-                                result
-                            },
-                        }
+                serialize_func_internal.extend(quote_spanned! {variant.span()=>
+                    Self::#variant_name #pattern => {
+                        NetworkSerializable::serialize_into(&#variant_id, buffer);
+                        #serialize
                     },
                 });
 
-                deserialize_func_internal.extend(match &variant.fields {
-                    Fields::Unit => quote_spanned! {variant.span()=>
-                        #variant_id => Ok(#name::#variant_name),
-                    }, // no fields, message of size 0
-                    Fields::Unnamed(fields) => {
-                        let field_name: Vec<_> = fields.unnamed.iter().enumerate().map(|(i, _)| {
-                            format_ident!("field{}", i)
-                        }).collect();
-                        let field_types: Vec<_> = fields.unnamed.iter().collect();
-                        
-                        quote_spanned! {variant.span()=>
-                            #variant_id => {
-                                let mut offset = std::mem::size_of::<u64>();;
-                        
-                                #(
-                                    let #field_name = match NetworkSerializable::deserialize((&data[offset..offset+std::mem::size_of::<#field_types>()]).to_vec()) {
-                                        Ok(result) => result,
-                                        Err(e) => return Err(e),
-                                    };
-                                    offset += std::mem::size_of::<#field_types>();
-                                )*
-                        
-                                Ok(#name::#variant_name (#(#field_name,)*))
-                            },
-                        }
-                    },
-                    Fields::Named(fields) => {
-                        let field_name: Vec<_> = fields.named.iter().enumerate().map(|(i, _)| {
-                            format_ident!("field{}", i)
-                        }).collect();
-                        let field_types: Vec<_> = fields.named.iter().collect();
-                        
-                        quote_spanned! {variant.span()=>
-                            #variant_id => {
-                                let mut offset = std::mem::size_of::<u64>(); // we read the id !
-                                
-                                #(
-                                    let #field_name = match NetworkSerializable::deserialize((&data[offset..offset+std::mem::size_of::<#field_types>()]).to_vec()) {
-                                        Ok(result) => result,
-                                        Err(e) => return Err(e),
-                                    };
-                                    offset += std::mem::size_of::<#field_types>();
-                                )*
-                                
-                                Ok(#name::#variant_name (#(#field_name,)*))
-                            },
-                        }
+                deserialize_func_internal.extend(quote_spanned! {variant.span()=>
+                    #variant_id => {
+                        #deserialize
+                        Self::#variant_name #construct
                     },
                 });
-
-                variant_id += 1;
             }
             (quote!{
-                match &self {
+                match self {
                     #size_func_internal
                 }
             },
@@ -188,157 +131,293 @@ pub fn derive_is_variant(input: TokenStream) -> TokenStream {
                 }
             },
             quote!{
+                let start = reader.position();
                 // read enum id in the data !
-                let id: u64 = match NetworkSerializable::deserialize((&data[0..std::mem::size_of::<u64>()]).try_into().unwrap()) {
-                    Ok(result) => result,
-                    Err(e) => return Err(NetworkUnserializeError::IncompleteData),
-                };
-                match id as usize {
+                let (id, _) = <u64 as NetworkSerializable>::deserialize_from(reader)?;
+                let result = match id {
                     #deserialize_func_internal
-                    _ => Err(NetworkUnserializeError::InvalidId),
+                    _ => return Err(NetworkUnserializeError::InvalidId),
+                };
+                Ok((result, reader.position() - start))
+            },
+            {
+                let mut schema = String::from("enum");
+                let mut schema_fields = TokenStream2::new();
+                for (id, (variant_schema, variant_schema_fields)) in schemas.iter() {
+                    schema.push_str(&format!(" {id}:{variant_schema}"));
+                    schema_fields.extend(variant_schema_fields.clone());
                 }
+                schema_hash_func(&schema, schema_fields)
             })
-            
+
         },
         Data::Struct(struct_data) => {
 
-            // serialize function : returns Vec<u8> being the raw data.
-            let mut serialize_func_internal = TokenStream2::new();
-            // deserialize function : returns Self from id and Vec<u8>
-            let mut deserialize_func_internal = TokenStream2::new();
-
-            // size match arm for the current variant (compute size from fields)
-            let struct_size = match &struct_data.fields {
-                Fields::Unit => quote!{0}, // no fields, message of size 0 (no need to have variant id)
-                Fields::Unnamed(fields) => {
-                    let mut field_sum = quote!{0};
-                    for field in fields.unnamed.iter() {
-                        field_sum.extend(quote!{+ std::mem::size_of::<#field>()})
-                    }
-                    field_sum
-                },
-                Fields::Named(fields) => {
-                    let mut field_sum = quote!{0};
-                    for field in fields.named.iter() {
-                        field_sum.extend(quote!{+ std::mem::size_of::<#field>()})
-                    }
-                    field_sum
-                },
+            let fields = match FieldsCode::new(&struct_data.fields) {
+                Ok(fields) => fields,
+                Err(e) => return e.to_compile_error().into(),
             };
+            let pattern = &fields.pattern;
+            let size = &fields.size;
+            let serialize = &fields.serialize;
+            let deserialize = &fields.deserialize;
+            let construct = &fields.construct;
 
-            serialize_func_internal.extend(match &struct_data.fields {
-                Fields::Unit => {quote!{}}, // no fields, message of size 0
-                Fields::Unnamed(fields) => {
-                    // serialize all the fields
-                    let field_name: Vec<_> = fields.unnamed.iter().enumerate().map(|(i, _)| {
-                        quote!(self.#i)
-                    }).collect();
-                    
-                    quote! {
-                        let mut result = Vec::with_capacity(self.size());
-                
-                        #(
-                            result.append(&mut NetworkSerializable::serialize(#field_name));
-                        )*
-                
-                        result
-                    }
-                },
-                Fields::Named(fields) => {
-
-                    
-                    let field_name: Vec<_> = fields.named.iter().map(|name| {
-                        quote!{#name}
-                    }).collect();
-                    
-                    quote! {
-                        let mut result = Vec::with_capacity(self.size());
-                        
-                        #(
-                            result.append(&mut NetworkSerializable::serialize(self.#field_name));
-                        )*
-                        
-                        result
-                    }
-                },
-            });
-
-            
-            deserialize_func_internal.extend(match &struct_data.fields {
-                Fields::Unit => quote! {
-                    #name {}
-                }, // no fields, message of size 0
-                Fields::Unnamed(fields) => {
-                    let field_names: Vec<_> = fields.unnamed.iter().enumerate().map(|(i, _)| {
-                        format_ident!("field{}", i)
-                    }).collect();
-                    let field_types: Vec<_> = fields.unnamed.iter().collect();
-                    
-                    quote! {
-                        let mut offset = std::mem::size_of::<u64>();;
-                    
-                        #(
-                            let #field_names = match NetworkSerializable::deserialize((&data[offset..offset+std::mem::size_of::<#field_types>()]).to_vec()) {
-                                Ok(result) => result,
-                                Err(e) => return Err(e),
-                            };
-                            offset += std::mem::size_of::<#field_types>();
-                        )*
-                
-                        Ok(#name (#(#field_names,)*))
-                    }
-                },
-                Fields::Named(fields) => {
-                    let field_names: Vec<_> = fields.named.iter().map(|name| {
-                        quote!{#name}
-                    }).collect();
-                    let field_types: Vec<_> = fields.named.iter().collect();
-                    
-                    
-                    quote! {
-                        let mut offset = std::mem::size_of::<u64>(); // we read the id !
-                        
-                        #(
-                            let #field_names = match NetworkSerializable::deserialize((&data[offset..offset+std::mem::size_of::<#field_types>()]).to_vec()) {
-                                Ok(result) => result,
-                                Err(e) => return Err(e),
-                            };
-                            offset += std::mem::size_of::<#field_types>();
-                        )*
-                        
-                        Ok(#name{
-                            #(
-                                #field_names,
-                            )*
-                        })
-                    }
-                },
-            });
-
-
-            (struct_size, serialize_func_internal, deserialize_func_internal)
+            (quote!{
+                let Self #pattern = self;
+                0 #size
+            },
+            quote!{
+                let Self #pattern = self;
+                #serialize
+            },
+            quote!{
+                let start = reader.position();
+                #deserialize
+                Ok((Self #construct, reader.position() - start))
+            },
+            schema_hash_func(&format!("struct {}", fields.schema), fields.schema_fields.clone()))
 
         },
-        _ => return derive_error!("IsVariant is only implemented for enums and structs"),
+        _ => return derive_error!("NetworkSerializable is only implemented for enums and structs"),
     };
-    
-    
+
+
+    let name_string = name.to_string();
     let expanded = quote! {
-        impl NetworkSerializable for #name {
+        impl #impl_generics NetworkSerializable for #name #ty_generics #where_clause {
             fn size(&self) -> usize {
                 #size_func
             }
-            fn serialize(self) -> Vec<u8> {
+            #[allow(unused_variables)]
+            fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
                 #serialize_func
             }
-            fn deserialize(data: Vec<u8>) -> Result<Self, NetworkUnserializeError> {
+            #[allow(unused_variables)]
+            fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
                 #deserialize_func
             }
-            
+            fn schema_hash() -> u64 {
+                guarded_schema_hash::<Self, _>(#name_string, || {
+                    #schema_func
+                })
+            }
+
         }
     };
-    
+
     TokenStream::from(expanded)
 
 }
 
+/// Add a `NetworkSerializable` bound on every type parameter.
+fn add_trait_bounds(mut generics: Generics) -> Generics {
+    for param in &mut generics.params {
+        if let GenericParam::Type(ref mut type_param) = *param {
+            type_param.bounds.push(parse_quote!(NetworkSerializable));
+        }
+    }
+    generics
+}
+
+/// What to do with a field, parsed from the `#[network(...)]` attributes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FieldMode {
+    /// Field is sent and must be received.
+    Default,
+    /// Field is never sent.
+    Skip,
+    /// Field is sent, but can be missing at the end of the received data.
+    Optional,
+}
+
+/// Get all the options in the `#[network(...)]` attributes.
+fn network_options(attributes: &[Attribute]) -> Result<Vec<Meta>, Error> {
+    let mut result = Vec::new();
+    for attribute in attributes.iter().filter(|attr| attr.path.is_ident("network")) {
+        match attribute.parse_meta()? {
+            Meta::List(list) => for nested in list.nested {
+                match nested {
+                    NestedMeta::Meta(meta) => result.push(meta),
+                    NestedMeta::Lit(lit) => return Err(Error::new(lit.span(), "expected a network option")),
+                }
+            },
+            meta => return Err(Error::new(meta.span(), "expected `#[network(...)]`")),
+        }
+    }
+    Ok(result)
+}
+
+fn field_mode(field: &syn::Field) -> Result<FieldMode, Error> {
+    let mut mode = FieldMode::Default;
+    for option in network_options(&field.attrs)? {
+        mode = match &option {
+            Meta::Path(path) if path.is_ident("skip") => FieldMode::Skip,
+            Meta::Path(path) if path.is_ident("default") => FieldMode::Optional,
+            _ => return Err(Error::new(option.span(), "unknown network field attribute, expected `skip` or `default`")),
+        };
+    }
+    Ok(mode)
+}
+
+/// Get the id of a variant from the `#[network(id = N)]` attribute, or use the given default.
+fn variant_id(attributes: &[Attribute], default: u64) -> Result<u64, Error> {
+    let mut id = default;
+    for option in network_options(attributes)? {
+        id = match &option {
+            Meta::NameValue(name_value) if name_value.path.is_ident("id") => match &name_value.lit {
+                Lit::Int(value) => value.base10_parse()?,
+                lit => return Err(Error::new(lit.span(), "network id must be an integer")),
+            },
+            _ => return Err(Error::new(option.span(), "unknown network variant attribute, expected `id = N`")),
+        };
+    }
+    Ok(id)
+}
+
+/// Body of the `schema_hash` function.
+/// The schema description is hashed at compile time, then the hashes of the field types are mixed in at run time.
+/// Containers mix in the hashes of the types they hold (see `NetworkSerializable::schema_hash`), so changes in nested messages,
+/// held directly, in containers or through generic parameters, change the hash as well.
+fn schema_hash_func(schema: &str, schema_fields: TokenStream2) -> TokenStream2 {
+    let hash = fnv1a(schema.as_bytes());
+    quote!{
+        let mut hash: u64 = #hash;
+        #schema_fields
+        hash
+    }
+}
+
+/// FNV-1a, 64 bits version. Simple, and stable across compilers (unlike the std hasher).
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Generated code for a set of fields, either from a struct or an enum variant.
+/// Fields are bound to `field0`, `field1`, ... in the patterns.
+struct FieldsCode {
+    /// Pattern to destructure the fields, without the type or variant name.
+    pattern: TokenStream2,
+    /// Sum of the sizes of the fields, starting with a `+`.
+    size: TokenStream2,
+    /// Statements writing the fields into `buffer`.
+    serialize: TokenStream2,
+    /// Statements reading the fields from `reader`.
+    deserialize: TokenStream2,
+    /// Construction of the fields once deserialized, without the type or variant name.
+    construct: TokenStream2,
+    /// Description of the sent fields, for the schema hash.
+    schema: String,
+    /// Statements mixing the schema hash of each sent field type into `hash`.
+    schema_fields: TokenStream2,
+}
+
+impl FieldsCode {
+    fn new(fields: &Fields) -> Result<FieldsCode, Error> {
+        let mut size = TokenStream2::new();
+        let mut serialize = TokenStream2::new();
+        let mut deserialize = TokenStream2::new();
+        let mut bindings = Vec::new();
+        let mut schema = String::from("(");
+        let mut schema_fields = TokenStream2::new();
+        // a missing field is only detected at the end of the data, so no field must be required after it
+        let mut optional_seen = false;
+
+        for (i, field) in fields.iter().enumerate() {
+            let binding = format_ident!("field{}", i);
+            let field_type = &field.ty;
+            let mode = field_mode(field)?;
+            match mode {
+                FieldMode::Optional => optional_seen = true,
+                FieldMode::Default if optional_seen => return Err(Error::new(field.span(), "fields after a `#[network(default)]` field must be `#[network(default)]` or `#[network(skip)]`")),
+                _ => {},
+            }
+            match mode {
+                FieldMode::Skip => {
+                    bindings.push(quote!{ _ });
+                    deserialize.extend(quote_spanned! {field.span()=>
+                        let #binding: #field_type = Default::default();
+                    });
+                },
+                mode => {
+                    bindings.push(quote!{ #binding });
+                    schema.push_str(&quote!{ #field_type }.to_string());
+                    schema.push_str(match mode {
+                        FieldMode::Optional => "?,",
+                        _ => ",",
+                    });
+                    schema_fields.extend(quote_spanned! {field.span()=>
+                        hash = (hash ^ <#field_type as NetworkSerializable>::schema_hash()).wrapping_mul(0x100000001b3);
+                    });
+                    size.extend(quote_spanned! {field.span()=>
+                        + NetworkSerializable::size(#binding)
+                    });
+                    serialize.extend(quote_spanned! {field.span()=>
+                        NetworkSerializable::serialize_into(#binding, buffer);
+                    });
+                    deserialize.extend(match mode {
+                        FieldMode::Optional => quote_spanned! {field.span()=>
+                            let #binding: #field_type = match reader.is_empty() {
+                                true => Default::default(),
+                                false => <#field_type as NetworkSerializable>::deserialize_from(reader)?.0,
+                            };
+                        },
+                        _ => quote_spanned! {field.span()=>
+                            let (#binding, _) = <#field_type as NetworkSerializable>::deserialize_from(reader)?;
+                        },
+                    });
+                },
+            }
+        }
+
+        let field_names: Vec<_> = (0..fields.len()).map(|i| format_ident!("field{}", i)).collect();
+        let (pattern, construct) = match fields {
+            Fields::Unit => (quote!{}, quote!{}),
+            Fields::Unnamed(_) => (
+                quote!{ (#(#bindings,)*) },
+                quote!{ (#(#field_names,)*) },
+            ),
+            Fields::Named(named) => {
+                let idents: Vec<_> = named.named.iter().map(|field| field.ident.clone()).collect();
+                (
+                    quote!{ { #(#idents: #bindings,)* } },
+                    quote!{ { #(#idents: #field_names,)* } },
+                )
+            },
+        };
+
+        schema.push(')');
+
+        Ok(FieldsCode { pattern, size, serialize, deserialize, construct, schema, schema_fields })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::FieldsNamed;
+
+    fn fields_code(fields: FieldsNamed) -> Result<FieldsCode, Error> {
+        FieldsCode::new(&Fields::Named(fields))
+    }
+
+    #[test]
+    fn default_fields_only_at_the_end() {
+        assert!(fields_code(parse_quote!({ a: u8, #[network(default)] b: u8, #[network(default)] c: u8 })).is_ok());
+        // skipped fields are not sent, so they can follow
+        assert!(fields_code(parse_quote!({ a: u8, #[network(default)] b: u8, #[network(skip)] c: u8 })).is_ok());
+        assert!(fields_code(parse_quote!({ #[network(default)] a: u8, b: u8 })).is_err());
+        assert!(fields_code(parse_quote!({ #[network(default)] a: u8, #[network(skip)] b: u8, c: u8 })).is_err());
+    }
+
+    #[test]
+    fn unknown_attributes_are_refused() {
+        assert!(fields_code(parse_quote!({ #[network(sometimes)] a: u8 })).is_err());
+        assert!(fields_code(parse_quote!({ #[network] a: u8 })).is_err());
+    }
+}
@@ -109,6 +109,11 @@ impl Transform {
         self.recompute_world_pos();
     }
 
+    pub fn set_scale(&mut self, scale: Vector3<f32>) {
+        self.scale = scale;
+        self.recompute_world_pos();
+    }
+
     pub fn scale_axis(&mut self, s: Vector3<f32>) {
         self.scale.mul_element_wise(s);
         self.recompute_world_pos();
@@ -142,6 +147,10 @@ impl Transform {
         self.rotation
     }
 
+    pub fn get_scale(&self) -> Vector3<f32> {
+        self.scale
+    }
+
     pub fn euler(&self) -> Euler<Rad<f32>> {
         Euler::from(self.rotation)
     }
@@ -4,11 +4,45 @@ mod packet;
 mod buffer;
 mod serialization;
 mod default_messages;
+mod protocol;
+mod channels;
+mod fragments;
+mod transport;
+mod conditioner;
+mod heartbeat;
+mod reconnect;
+mod encryption;
+mod priority;
+mod rpc;
+mod replication;
+mod prediction;
+mod interpolation;
+mod clock;
+mod lag_compensation;
+mod rollback;
+mod stats;
 
 pub use server::*;
 pub use client::*;
 pub use packet::*;
+pub use buffer::{FrameError, DEFAULT_MAX_PACKET_SIZE, DEFAULT_MAX_QUEUED_BYTES};
+pub use fragments::{FragmentStats, MAX_DATAGRAM_SIZE, DEFAULT_REASSEMBLY_TIMEOUT, DEFAULT_REASSEMBLY_MEMORY};
 pub use serialization::*;
+pub use protocol::*;
+pub use transport::*;
+pub use conditioner::*;
+pub use heartbeat::{ConnectionQuality, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_IDLE_TIMEOUT};
+pub use channels::{Channel, DEFAULT_RESEND_DELAY};
+pub use reconnect::{ReconnectPolicy, DEFAULT_CONNECT_TIMEOUT};
+pub use priority::{Priority, DEFAULT_UNRELIABLE_MAX_AGE};
+pub use rpc::{Rpc, RpcCall, RpcError, DEFAULT_RPC_TIMEOUT};
+pub use replication::{Replicated, Viewer, Networked};
+pub use prediction::{Predicted, Prediction, Authority, DEFAULT_SMOOTHING_TICKS};
+pub use clock::{NetworkTime, DEFAULT_TICK_RATE};
+pub use lag_compensation::{LagCompensated, LagCompensation, PastEntity, RayHit, DEFAULT_LAG_HISTORY};
+pub use stats::{NetworkStats, ConnectionStats, TrafficStats, MessageType};
+pub use rollback::{RollbackSession, RollbackHandler, RollbackPlayer, DEFAULT_INPUT_DELAY, DEFAULT_MAX_PREDICTION, DEFAULT_CHECKSUM_INTERVAL};
+pub use interpolation::{Interpolated, InterpolationStats, DEFAULT_INTERPOLATION_DELAY, DEFAULT_MAX_EXTRAPOLATION, DEFAULT_MAX_SNAPSHOTS};
 pub(crate) use default_messages::*;
 /*
 A lot of network code is a first implementation, and could be refactored in a better way.
@@ -20,6 +54,26 @@ packets are what are sent through the network. Even if what is really sent are u
 packets are nice wrappers around those to easely create, write, read, parse them from any struct implementing :
 NetworkSerializable is a trait on every object that need to be sent through the network.
 network buffer is a wrapper arround a u8 buffer to easely read packets out of raw io streams
+channels put a header in front of udp packets, to ack them and resend / reorder them depending on the channel
+messages sent during a tick are queued, and flushed at the end of the update : several of them go in the same datagram,
+and tcp bytes the os could not take are written on the next flush
+priorities and the bandwidth budget of a connection decide which udp messages go first, wait, or get dropped when too old
+rpc calls are requests sent over tcp with an id, answered by a responder on the peer, and the answer (or the timeout) runs a callback
+replication mirrors the entities with a Replicated component on the clients : each client gets the networked components
+that changed since the last snapshot it acknowledged, for the entities relevant to it (close to its viewers, owned by it...)
+prediction runs the inputs of the client right away on a predicted state, and corrects it when the server state disagrees
+interpolation shows remote entities a little in the past on the timeline of the server, between the transforms it sent
+fragments split udp packets bigger than the MTU in several datagrams, and rebuild them on the other side
+transports are what actually move the bytes : os sockets, or an in memory loopback network to run everything in one process
+the conditioner wraps a transport to add latency, loss, and other bad network conditions for testing
+heartbeats ping the peer to measure the round trip time and loss, and drop connections that went silent
+encryption agrees on keys during the handshake, then encrypts and authenticates the packets of secure connections
+the server puts its time in its pongs, and its tick in the packets it sends : the client estimates the server clock from them
+lag compensation remembers where the entities were, so the server can check a shot against what the shooter saw
+rollback sessions are peer to peer without server : peers exchange their inputs, predict the missing ones, and go back
+to re-run the game when a prediction was wrong. Checksums of the state tell when peers desynchronized
+network stats count the bytes, packets and messages of each connection, and are copied in a singleton on each update
+the reconnect policy tells the client how to try again, waiting longer after each failure
 That's it ! to send data, it goes :
 
 struct / enum -> packet -> (client / server).send(packet) -> OS AND RAW CONNECTION
@@ -1,168 +1,363 @@
-use std::{net::{TcpStream, UdpSocket, SocketAddr}, io::Read, mem::size_of};
-use super::packet::Packet;
-
-/// Wrapper arround a u8 buffer to ease reading on tcp streams
-pub struct TcpBuffer{
-    buffer: [u8; 256],
-    read_pointer: usize, // could use u8 but makes type coercion forced insome functions
-    remaining_data_size: usize,
+use std::{net::SocketAddr, io::{self, Read, Write}, fmt};
+use crate::{ByteReader, NetworkSerializable};
+
+use super::{
+    packet::{Packet, PacketHeader},
+    channels::ChannelHeader,
+    fragments::{Reassembler, FragmentStats, MAX_DATAGRAM_SIZE, DEFAULT_REASSEMBLY_TIMEOUT, DEFAULT_REASSEMBLY_MEMORY},
+};
+
+/// Size of the chunks read from the os at once. Packets can be bigger, they are rebuilt over several reads.
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Default maximum size of a packet body. A peer announcing a bigger packet gets disconnected.
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 1 << 20;
+
+/// Default number of bytes that can wait to be sent to a peer before it is reported as too slow.
+pub const DEFAULT_MAX_QUEUED_BYTES: usize = 1 << 20;
+
+/// Errors while reading packets from a tcp stream.
+/// All of them mean the stream can't be used anymore, and the connection should be closed.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The os reported an error on the stream.
+    Io(std::io::Error),
+    /// The peer closed the connection.
+    ConnectionClosed,
+    /// The peer announced a packet bigger than the allowed maximum.
+    PacketTooLarge {
+        size: u64,
+        max: usize,
+    },
+    /// A packet of an encrypted connection failed its integrity check.
+    Corrupted,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "io error : {e}"),
+            FrameError::ConnectionClosed => write!(f, "connection closed by peer"),
+            FrameError::PacketTooLarge { size, max } => write!(f, "packet of {size} bytes exceeds the maximum of {max} bytes"),
+            FrameError::Corrupted => write!(f, "packet failed the integrity check"),
+        }
+    }
+}
+
+/// Streaming decoder that rebuilds packets out of a tcp stream.
+/// Received bytes are only kept until their packet is complete, and the announced packet size is checked
+/// against the maximum before waiting for the body, so a peer can't make us allocate more than it actually sends.
+pub struct TcpBuffer {
+    /// bytes received but not yet made into packets
+    pending: Vec<u8>,
+    /// header of the packet being received, once we read it
+    header: Option<PacketHeader>,
+    max_packet_size: usize,
 }
 
 impl TcpBuffer {
-    pub fn new() -> TcpBuffer {
-        TcpBuffer { buffer: [0; 256], read_pointer: 0, remaining_data_size: 0 }
-    }
-
-    /// returns true if there was any new data to read
-    pub fn read_tcp(&mut self, stream: &mut TcpStream) -> Result<bool, std::io::Error> {
-        self.remaining_data_size = match stream.read(&mut self.buffer) {
-            Ok(data_size) => data_size,
-            Err(e) => {
-                // ! non blocking sockets can throw errors if they found nothing. Prevent this error, throw the rest
-                match e.kind() {
-                    std::io::ErrorKind::WouldBlock => return Ok(false),
-                    _ => return Err(e),
+    pub fn new(max_packet_size: usize) -> TcpBuffer {
+        TcpBuffer {
+            pending: Vec::new(),
+            header: None,
+            max_packet_size,
+        }
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// Read all the available data on the stream, and returns the packets that were completed.
+    pub fn read_packets<R: Read>(&mut self, stream: &mut R) -> Result<Vec<Packet>, FrameError> {
+        let mut result = Vec::new();
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            match stream.read(&mut chunk) {
+                // the peer closed the connection : give the last packets, we will get the error on the next read
+                Ok(0) => match result.is_empty() {
+                    true => return Err(FrameError::ConnectionClosed),
+                    false => break,
+                },
+                Ok(amount) => self.push_bytes(&chunk[..amount], &mut result)?,
+                Err(e) => {
+                    // ! non blocking sockets can throw errors if they found nothing. Prevent this error, throw the rest
+                    match e.kind() {
+                        std::io::ErrorKind::WouldBlock => break,
+                        std::io::ErrorKind::Interrupted => continue,
+                        _ => return Err(FrameError::Io(e)),
+                    }
                 }
             }
-        };
-        // new data, so reset the read pointer
-        self.read_pointer = 0;
-        Ok(self.remaining_data_size != 0) // send true if we managed to read any data
+        }
+        Ok(result)
     }
 
-    pub fn get_bool(&mut self) -> Result<bool, ()> {
-        if size_of::<bool>() > self.remaining_data_size {
-            Err(())
-        }
-        else {
-            let result = self.buffer[self.read_pointer] != 0;
-            self.read_pointer += size_of::<bool>();
-            self.remaining_data_size -= size_of::<bool>();
-            Ok(result)
+    /// Add received bytes to the buffer, and push the packets they completed.
+    pub fn push_bytes(&mut self, bytes: &[u8], packets: &mut Vec<Packet>) -> Result<(), FrameError> {
+        self.pending.extend_from_slice(bytes);
+        loop {
+            let header = match self.header.take() {
+                Some(header) => header,
+                None => {
+                    if self.pending.len() < Packet::header_size() {
+                        return Ok(());
+                    }
+                    // we checked there is enough data for a header
+                    let header = match PacketHeader::read(&mut ByteReader::new(&self.pending)) {
+                        Ok(header) => header,
+                        Err(_) => return Ok(()),
+                    };
+                    if header.body_size() > self.max_packet_size as u64 {
+                        return Err(FrameError::PacketTooLarge { size: header.body_size(), max: self.max_packet_size });
+                    }
+                    self.pending.drain(..Packet::header_size());
+                    header
+                }
+            };
+            // can't overflow, it is smaller than the max packet size
+            let size = header.body_size() as usize;
+            if self.pending.len() < size {
+                // wait for the rest of the packet
+                self.header = Some(header);
+                return Ok(());
+            }
+            let body = self.pending.drain(..size).collect();
+            packets.push(Packet::from_raw(header, body));
         }
     }
 
-    pub fn get_u64(&mut self) -> Result<u64, ()> {
-        if size_of::<u64>() > self.remaining_data_size {
-            Err(())
-        }
-        else {
-            // we are assured it works because we checked the size 
-            let buf: [u8; size_of::<u64>()] = self.buffer[self.read_pointer..self.read_pointer+size_of::<u64>()].try_into().unwrap();
-            self.read_pointer += size_of::<u64>();
-            self.remaining_data_size -= size_of::<u64>();
-            Ok(u64::from_le_bytes(buf))
+}
+
+/// Bytes waiting to be written on a tcp stream.
+/// Packets are queued during a tick and written all at once on flush. What the os could not take is kept for the next flush.
+pub(crate) struct OutgoingBuffer {
+    bytes: Vec<u8>,
+}
+
+impl OutgoingBuffer {
+    pub(crate) fn new() -> OutgoingBuffer {
+        OutgoingBuffer {
+            bytes: Vec::new(),
         }
     }
 
-    /// returns true if the packet is complete, false otherwise. 
-    /// If false is returned, the buffer is empty
-    pub fn try_complete_packet(&mut self, packet: &mut Packet) -> bool {
-        let read_amount = std::cmp::min(packet.awaiting_size(), self.remaining_data_size);
-        match packet.push_data(&mut self.buffer[self.read_pointer..self.read_pointer + read_amount].to_vec()) {
-            Ok(_packet_finished) => { /* all good */},
-            Err(_e) => { /* todo : error handling */} // todo : better way
-        }
-        self.read_pointer += read_amount;
-        self.remaining_data_size -= read_amount;
-        packet.awaiting_size() == 0
-    }
-
-    /// try to create a new packet from the buffer
-    /// returns true if there is remaining data to read
-    /// returns a packet if we managed to create one (not necessarely complete)
-    pub fn try_read_packet(&mut self) -> Option<Packet> {
-        // check if we can read a header
-        if self.remaining_data_size >= Packet::header_size() {
-            // we can at least parse a header !
-            // we can unwrap safely as we know there is enough space (we measured)
-            let is_default = self.get_bool().unwrap();
-            let size = self.get_u64().unwrap();
-            let sender = self.get_u64().unwrap();
-            let mut packet = Packet::from_header(size, sender, is_default);
-            // check if we can read the packet !
-            self.try_complete_packet(&mut packet);
-            // if the packet is complete, there might be remaining data !
-            // otherwise, there is not because we read it all to try completing the packet
-            Some(packet)
-        }
-        else {
-            // nothing to do
-            None
-        }
+    /// Queue a packet, and returns its size in bytes.
+    pub(crate) fn push(&mut self, packet: &Packet) -> usize {
+        let before = self.bytes.len();
+        packet.write_into(&mut self.bytes);
+        self.bytes.len() - before
     }
 
+    /// Number of bytes waiting to be written.
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Write as much as the stream takes without blocking. The rest stays in the buffer.
+    pub(crate) fn flush<W: Write>(&mut self, stream: &mut W) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.bytes.len() {
+                break Ok(());
+            }
+            match stream.write(&self.bytes[written..]) {
+                Ok(0) => break Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed while writing")),
+                Ok(amount) => written += amount,
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => break Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => break Err(e),
+                }
+            }
+        };
+        self.bytes.drain(..written);
+        result
+    }
 }
 
-/// Wrapper arround a u8 buffer to ease reading on udp sockets
-pub struct UdpBuffer{
-    buffer: [u8; 256],
-    read_pointer: usize,
+/// Reads datagrams on udp sockets, and rebuilds the messages that were split in several fragments.
+pub struct UdpBuffer {
+    buffer: Vec<u8>,
+    reassembler: Reassembler,
 }
 
 impl UdpBuffer {
     pub fn new() -> UdpBuffer {
+        UdpBuffer::with_reassembly_limits(DEFAULT_REASSEMBLY_TIMEOUT, DEFAULT_REASSEMBLY_MEMORY)
+    }
+
+    /// Create a buffer that waits at most `timeout` seconds for the fragments of a message,
+    /// and keeps at most `max_memory` bytes of incomplete messages.
+    pub fn with_reassembly_limits(timeout: f64, max_memory: usize) -> UdpBuffer {
         UdpBuffer {
-            buffer: [0u8; 256],
-            read_pointer: 0
+            buffer: vec![0u8; MAX_DATAGRAM_SIZE],
+            reassembler: Reassembler::new(timeout, max_memory),
         }
     }
-    /// returns true if there was any new data to read
-    pub fn read_tcp(&mut self, socket: &mut UdpSocket) -> Result<Option<(Packet, SocketAddr)>, std::io::Error> {
-        let data_size = match socket.recv_from(&mut self.buffer) {
-            Ok(data_size) => data_size,
-            Err(e) => {
-                // ! non blocking sockets can throw errors if they found nothing. Prevent this error, throw the rest
-                match e.kind() {
-                    std::io::ErrorKind::WouldBlock => return Ok(None),
-                    _ => return Ok(None), // todo : silence errors for now, maybe treat them later ?
-                }
-            }
-        };
-        self.read_pointer = 0; // reset read pointers
-        match data_size.0 > 0 {
-            false => Ok(None),
-            true => {
-                let is_default = self.get_bool();
-                let size = self.get_u64()?;
-                let sender = self.get_u64()?;
-                let mut result_packet = Packet::from_header(size, sender, is_default);
-                match result_packet.push_data(&self.buffer[Packet::header_size()..data_size.0]) {
-                    Ok(complete) => if complete {
-                        Ok(Some((result_packet, data_size.1)))
-                    }
-                    else {
-                        println!("couldn't finish udp packet : throw it away");
-                        Ok(None) // it's udp, so packets come all at once. no chance for cathcing up !
-                    }
-                    Err(e) => {
-                        println!("Error while building udp packet : {e:?}");
-                        Ok(None)
-                    },
-                }
+
+    /// Statistics about the fragmented messages received so far.
+    pub fn fragment_stats(&self) -> FragmentStats {
+        self.reassembler.stats()
+    }
+
+    /// returns the next datagram received with its channel header, if any : the rest of the datagram is given as is, for the channels to read.
+    /// Datagrams without a valid header are thrown away.
+    /// `recv` reads the next datagram from the transport. The time is used to drop the messages we waited too long for.
+    /// `accept_fragment` checks the session token, sender and address of a fragment before it is kept to rebuild its message.
+    pub(crate) fn read_udp<F, A>(&mut self, mut recv: F, mut accept_fragment: A, time: f64) -> Result<Option<(ChannelHeader, Vec<u8>, SocketAddr)>, std::io::Error>
+        where F: FnMut(&mut [u8]) -> Result<Option<(usize, SocketAddr)>, std::io::Error>,
+              A: FnMut(u64, u64, SocketAddr) -> bool,
+    {
+        self.reassembler.expire(time);
+        loop {
+            let (data_size, from) = match recv(&mut self.buffer) {
+                Ok(Some(received)) => received,
+                Ok(None) => return Ok(None),
+                Err(_e) => return Ok(None), // todo : silence errors for now, maybe treat them later ?
+            };
+            let datagram = match self.reassembler.receive(from, &self.buffer[..data_size], time, |token, sender| accept_fragment(token, sender, from)) {
+                Some(datagram) => datagram,
+                None => continue, // fragment of a message that is not complete yet, or garbage
+            };
+            let mut reader = ByteReader::new(&datagram);
+            match ChannelHeader::deserialize_from(&mut reader) {
+                Ok((channel_header, _)) => {
+                    let payload = reader.read_bytes(reader.remaining()).unwrap_or_default().to_vec();
+                    return Ok(Some((channel_header, payload, from)));
+                },
+                Err(_) => println!("[NETWORK] -> Received an invalid udp packet from {from} : throw it away"),
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small deterministic generator, so a failing case can be run again.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
 
+        fn below(&mut self, max: usize) -> usize {
+            (self.next() % max as u64) as usize
+        }
     }
 
+    /// The bytes of a packet with an empty body : the size is zero whatever the endianness.
+    fn empty_packet_bytes() -> Vec<u8> {
+        let mut bytes = Packet::from(&0u8, 0).as_bytes();
+        bytes.truncate(Packet::header_size());
+        bytes[1..9].fill(0);
+        bytes
+    }
+
+    /// The buffer never keeps more than a header and a body of the maximum size.
+    fn assert_bounded(buffer: &TcpBuffer) {
+        assert!(buffer.pending.len() <= Packet::header_size() + buffer.max_packet_size + READ_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn random_chunks_give_back_the_packets() {
+        let mut random = XorShift(0x9e3779b97f4a7c15);
+        let bodies: Vec<Vec<u8>> = (0..200).map(|_| (0..random.below(300)).map(|_| random.next() as u8).collect()).collect();
+        let stream: Vec<u8> = bodies.iter().flat_map(|body| Packet::from(body, 3).as_bytes()).collect();
+        let mut buffer = TcpBuffer::new(DEFAULT_MAX_PACKET_SIZE);
+        let mut packets = Vec::new();
+        let mut position = 0;
+        while position < stream.len() {
+            let end = (position + 1 + random.below(64)).min(stream.len());
+            buffer.push_bytes(&stream[position..end], &mut packets).unwrap();
+            assert_bounded(&buffer);
+            position = end;
+        }
+        let received: Vec<Vec<u8>> = packets.into_iter().map(|packet| packet.into::<Vec<u8>>().unwrap()).collect();
+        assert_eq!(received, bodies);
+    }
+
+    #[test]
+    fn oversized_packets_are_refused_before_their_body() {
+        let mut buffer = TcpBuffer::new(16);
+        let bytes = Packet::from(&vec![0u8; 100], 0).as_bytes();
+        // the header alone is enough to refuse it
+        let result = buffer.push_bytes(&bytes[..Packet::header_size()], &mut Vec::new());
+        assert!(matches!(result, Err(FrameError::PacketTooLarge { max: 16, .. })));
+        assert!(buffer.pending.len() <= Packet::header_size());
+    }
+
+    #[test]
+    fn truncated_headers_wait_for_the_rest() {
+        let mut buffer = TcpBuffer::new(DEFAULT_MAX_PACKET_SIZE);
+        let bytes = Packet::from(&42u32, 0).as_bytes();
+        let mut packets = Vec::new();
+        buffer.push_bytes(&bytes[..Packet::header_size() - 1], &mut packets).unwrap();
+        assert!(packets.is_empty());
+        buffer.push_bytes(&bytes[Packet::header_size() - 1..], &mut packets).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets.remove(0).into::<u32>().unwrap(), 42);
+    }
 
-// TODO : rewrite both these func with serialization trait ?
-    fn get_bool(&mut self) -> bool {
-        match self.buffer.get(self.read_pointer) {
-            Some(val) => {
-                self.read_pointer += size_of::<bool>();
-                *val != 0
-            },
-            None => false,
+    #[test]
+    fn empty_bodies_are_packets() {
+        let mut buffer = TcpBuffer::new(DEFAULT_MAX_PACKET_SIZE);
+        let bytes: Vec<u8> = (0..3).flat_map(|_| empty_packet_bytes()).collect();
+        let mut packets = Vec::new();
+        buffer.push_bytes(&bytes, &mut packets).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|packet| packet.body.is_empty()));
+        assert!(buffer.pending.is_empty());
+    }
+
+    #[test]
+    fn headers_split_across_pushes() {
+        let mut buffer = TcpBuffer::new(DEFAULT_MAX_PACKET_SIZE);
+        let bytes: Vec<u8> = [Packet::from(&7u64, 1).as_bytes(), empty_packet_bytes(), Packet::from(&8u64, 1).as_bytes()].concat();
+        let mut packets = Vec::new();
+        for byte in bytes.iter() {
+            buffer.push_bytes(std::slice::from_ref(byte), &mut packets).unwrap();
+        }
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets.remove(2).into::<u64>().unwrap(), 8);
+        assert!(packets.remove(1).body.is_empty());
+        assert_eq!(packets.remove(0).into::<u64>().unwrap(), 7);
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic_nor_grow() {
+        let mut random = XorShift(0xdeadbeefcafe);
+        for _ in 0..500 {
+            let mut buffer = TcpBuffer::new(1024);
+            let mut packets = Vec::new();
+            for _ in 0..random.below(32) {
+                let chunk: Vec<u8> = (0..random.below(256)).map(|_| random.next() as u8).collect();
+                match buffer.push_bytes(&chunk, &mut packets) {
+                    Ok(()) => assert_bounded(&buffer),
+                    // the only way random bytes can fail is by announcing a huge packet
+                    Err(e) => {
+                        assert!(matches!(e, FrameError::PacketTooLarge { .. }));
+                        break;
+                    },
+                }
+            }
+            assert!(packets.iter().all(|packet| packet.body.len() <= 1024));
         }
     }
 
-    fn get_u64(&mut self) -> Result<u64, std::io::Error> {
-        let value = u64::from_le_bytes(match self.buffer[self.read_pointer..self.read_pointer+size_of::<u64>()].try_into() {
-            Ok(val) => val,
-            Err(e) => return Err( std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
-        });
-        self.read_pointer += size_of::<u64>();
-        Ok(value)
+    #[test]
+    fn read_packets_reports_closed_streams() {
+        let mut buffer = TcpBuffer::new(DEFAULT_MAX_PACKET_SIZE);
+        let bytes = Packet::from(&5u16, 0).as_bytes();
+        // a reader over a slice ends with a read of 0 : the stream is closed after the packet
+        let packets = buffer.read_packets(&mut bytes.as_slice()).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(buffer.read_packets(&mut [].as_slice()), Err(FrameError::ConnectionClosed)));
     }
 }
@@ -0,0 +1,572 @@
+use std::{collections::VecDeque, mem::size_of};
+
+use crate::{NetworkSerializable, NetworkUnserializeError, ByteWriter, ByteReader, guarded_schema_hash};
+
+use super::{packet::{Packet, PacketHeader}, fragments::{self, MAX_DATAGRAM_SIZE}, encryption::{UdpCipher, UDP_OVERHEAD}, priority::{Priority, Bandwidth, DEFAULT_UNRELIABLE_MAX_AGE}, stats::StatsRecorder};
+
+/// Delivery guarantees of a message sent over udp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Fire and forget : messages can be lost, duplicated or arrive out of order.
+    Unreliable,
+    /// Messages can be lost, but a message older than the last received one on the channel is dropped.
+    UnreliableSequenced,
+    /// Messages are resent until acknowledged, and received exactly once in any order.
+    ReliableUnordered,
+    /// Messages are resent until acknowledged, and received exactly once in the order they were sent.
+    ReliableOrdered,
+}
+
+/// Number of channels, to size the per channel states.
+pub(crate) const CHANNEL_COUNT: usize = 4;
+/// Number of streams : one per channel for the game, and the sequenced streams of the engine.
+const STREAM_COUNT: usize = CHANNEL_COUNT + 2;
+/// Size of the header put in front of every datagram.
+const HEADER_SIZE: usize = 2 * size_of::<u64>() + 2 * size_of::<u16>() + size_of::<u8>() + size_of::<u32>();
+/// Space left for the messages in a datagram that does not need to be fragmented.
+/// Only one byte is added to whole datagrams, see `fragments::split`.
+const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - 1 - HEADER_SIZE;
+/// Default time in seconds before resending a reliable message that was not acknowledged.
+pub const DEFAULT_RESEND_DELAY: f64 = 0.1;
+/// How many datagrams a reliable message remembers being sent in, to match the acks.
+const TRACKED_SEQUENCES: usize = 8;
+/// How far ahead of the next expected message we buffer reliable messages. Further ones are dropped, and will be resent.
+const RECEIVE_WINDOW: u16 = 1024;
+
+impl Channel {
+    pub fn is_reliable(self) -> bool {
+        matches!(self, Channel::ReliableUnordered | Channel::ReliableOrdered)
+    }
+
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Channel::Unreliable => 0,
+            Channel::UnreliableSequenced => 1,
+            Channel::ReliableUnordered => 2,
+            Channel::ReliableOrdered => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Channel> {
+        match id {
+            0 => Some(Channel::Unreliable),
+            1 => Some(Channel::UnreliableSequenced),
+            2 => Some(Channel::ReliableUnordered),
+            3 => Some(Channel::ReliableOrdered),
+            _ => None,
+        }
+    }
+}
+
+/// Messages of a channel, with their own message ids and receiving state.
+/// The engine has its own sequenced streams, so a newer snapshot does not make an older prediction state
+/// or game message look stale, and the other way around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stream {
+    /// messages of the game, and default messages that don't need their own stream
+    Game(Channel),
+    /// replication snapshots, sequenced
+    Snapshots,
+    /// authoritative states of the prediction, sequenced
+    PredictionStates,
+}
+
+impl Stream {
+    fn id(self) -> u8 {
+        match self {
+            Stream::Game(channel) => channel.id(),
+            Stream::Snapshots => CHANNEL_COUNT as u8,
+            Stream::PredictionStates => CHANNEL_COUNT as u8 + 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Stream> {
+        match id {
+            4 => Some(Stream::Snapshots),
+            5 => Some(Stream::PredictionStates),
+            _ => Channel::from_id(id).map(Stream::Game),
+        }
+    }
+
+    fn channel(self) -> Channel {
+        match self {
+            Stream::Game(channel) => channel,
+            Stream::Snapshots | Stream::PredictionStates => Channel::UnreliableSequenced,
+        }
+    }
+}
+
+impl From<Channel> for Stream {
+    fn from(channel: Channel) -> Stream {
+        Stream::Game(channel)
+    }
+}
+
+/// Returns true if the sequence a is more recent than b, accounting for wrapping.
+fn sequence_greater_than(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < u16::MAX / 2
+}
+
+/// Header put in front of every udp datagram.
+/// Each datagram has its own sequence number, and acknowledges the last 33 datagrams received from the peer.
+/// It also carries the session token of the connection, so nobody can send datagrams in the name of a client.
+/// The messages of the datagram follow it, each one with a `MessageHeader`. Datagrams without messages only carry acks.
+#[derive(NetworkSerializable)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChannelHeader {
+    /// secret given by the server over tcp when the client is welcomed.
+    token: u64,
+    /// id of the client sending the datagram, 0 for the server.
+    sender: u64,
+    /// sequence number of the datagram.
+    sequence: u16,
+    /// whether the sender received anything yet : the acks mean nothing until it did.
+    has_ack: bool,
+    /// most recent datagram sequence received from the peer.
+    ack: u16,
+    /// bit n is set if the datagram `ack - n - 1` was received.
+    ack_bits: u32,
+}
+
+impl ChannelHeader {
+    pub(crate) fn token(&self) -> u64 {
+        self.token
+    }
+
+    pub(crate) fn sender(&self) -> u64 {
+        self.sender
+    }
+}
+
+/// Put in front of each message of a datagram, before its packet.
+#[derive(NetworkSerializable)]
+struct MessageHeader {
+    stream: u8,
+    /// id of the message in its stream, for sequenced and reliable channels.
+    message_id: u16,
+}
+
+/// Message waiting to be sent : until the next flush, or longer if the bandwidth budget is exceeded.
+struct QueuedMessage {
+    stream: Stream,
+    message_id: u16,
+    packet: Packet,
+    priority: Priority,
+    queued_at: f64,
+}
+
+/// Bytes of a message in a datagram, headers included.
+fn message_bytes(stream: Stream, message_id: u16, packet: &Packet) -> Vec<u8> {
+    let header = MessageHeader { stream: stream.id(), message_id };
+    let mut result = Vec::with_capacity(header.size() + Packet::header_size() + packet.body.len());
+    header.serialize_into(&mut result);
+    packet.write_into(&mut result);
+    result
+}
+
+/// Read the messages of a datagram. None if any of them is invalid : the whole datagram is thrown away.
+fn read_messages(payload: &[u8]) -> Option<Vec<(Stream, u16, Packet)>> {
+    let mut reader = ByteReader::new(payload);
+    let mut result = Vec::new();
+    while reader.remaining() > 0 {
+        let (header, _) = MessageHeader::deserialize_from(&mut reader).ok()?;
+        let stream = Stream::from_id(header.stream)?;
+        let packet_header = PacketHeader::read(&mut reader).ok()?;
+        // it's udp, so messages come all at once : the body must be in the datagram
+        if packet_header.body_size() > reader.remaining() as u64 {
+            return None;
+        }
+        let body = reader.read_bytes(packet_header.body_size() as usize).ok()?.to_vec();
+        result.push((stream, header.message_id, Packet::from_raw(packet_header, body)));
+    }
+    Some(result)
+}
+
+/// Reliable message waiting for an ack.
+struct PendingMessage {
+    stream: Stream,
+    message_id: u16,
+    packet: Packet,
+    priority: Priority,
+    /// sequences of the datagrams the message was sent in
+    sequences: VecDeque<u16>,
+    last_sent: f64,
+}
+
+enum Slot {
+    Missing,
+    /// Received and given to the user (unordered channel)
+    Delivered,
+    /// Received and waiting for previous messages (ordered channel)
+    Waiting(Packet),
+}
+
+/// Receiving state of a stream.
+struct ReceiveChannel {
+    /// next message id we expect. All the messages before it have been received.
+    next_expected: u16,
+    /// state of the messages from `next_expected`.
+    slots: VecDeque<Slot>,
+    /// last message id received, for a sequenced stream.
+    last_sequenced: Option<u16>,
+}
+
+impl ReceiveChannel {
+    fn new() -> ReceiveChannel {
+        ReceiveChannel {
+            next_expected: 0,
+            slots: VecDeque::new(),
+            last_sequenced: None,
+        }
+    }
+
+    fn receive(&mut self, channel: Channel, message_id: u16, packet: Packet, delivered: &mut Vec<Packet>) {
+        match channel {
+            Channel::Unreliable => delivered.push(packet),
+            Channel::UnreliableSequenced => {
+                match self.last_sequenced {
+                    Some(last) if !sequence_greater_than(message_id, last) => {}, // stale, drop it
+                    _ => {
+                        self.last_sequenced = Some(message_id);
+                        delivered.push(packet);
+                    }
+                }
+            },
+            Channel::ReliableUnordered | Channel::ReliableOrdered => {
+                let offset = message_id.wrapping_sub(self.next_expected);
+                if offset >= RECEIVE_WINDOW {
+                    return; // already received (or way too far ahead, it will be resent)
+                }
+                let offset = offset as usize;
+                while self.slots.len() <= offset {
+                    self.slots.push_back(Slot::Missing);
+                }
+                if !matches!(self.slots[offset], Slot::Missing) {
+                    return; // duplicate
+                }
+                self.slots[offset] = match channel {
+                    Channel::ReliableUnordered => {
+                        delivered.push(packet);
+                        Slot::Delivered
+                    },
+                    _ => Slot::Waiting(packet),
+                };
+                // move forward over all the received messages
+                while let Some(slot) = self.slots.front() {
+                    if matches!(slot, Slot::Missing) {
+                        break;
+                    }
+                    if let Some(Slot::Waiting(packet)) = self.slots.pop_front() {
+                        delivered.push(packet);
+                    }
+                    self.next_expected = self.next_expected.wrapping_add(1);
+                }
+            },
+        }
+    }
+}
+
+/// One side of the udp channels between a server and a client.
+/// Queues the messages sent during a tick, and packs them in as few datagrams as possible when flushed.
+/// Keeps reliable messages until they are acknowledged, and sorts received messages.
+pub(crate) struct ChannelEndpoint {
+    local_sequence: u16,
+    /// most recent datagram sequence received, if any
+    remote_sequence: Option<u16>,
+    received_bits: u32,
+    /// we received datagrams that we did not acknowledge yet
+    ack_pending: bool,
+    next_message_ids: [u16; STREAM_COUNT],
+    receive_streams: [ReceiveChannel; STREAM_COUNT],
+    /// messages waiting to be sent, in the order they were queued
+    queue: Vec<QueuedMessage>,
+    pending: Vec<PendingMessage>,
+    resend_delay: f64,
+    /// how long a normal priority unreliable message can wait in the queue before being dropped
+    unreliable_max_age: f64,
+    /// unreliable messages dropped because they waited too long
+    dropped_messages: u64,
+    /// id of the next datagram that has to be fragmented
+    next_fragment_group: u16,
+    /// session token put in every datagram
+    token: u64,
+    /// encrypts the datagrams once the connection is secured
+    cipher: Option<UdpCipher>,
+}
+
+impl ChannelEndpoint {
+    pub(crate) fn new() -> ChannelEndpoint {
+        ChannelEndpoint {
+            local_sequence: 0,
+            remote_sequence: None,
+            received_bits: 0,
+            ack_pending: false,
+            next_message_ids: [0; STREAM_COUNT],
+            receive_streams: std::array::from_fn(|_| ReceiveChannel::new()),
+            queue: Vec::new(),
+            pending: Vec::new(),
+            resend_delay: DEFAULT_RESEND_DELAY,
+            unreliable_max_age: DEFAULT_UNRELIABLE_MAX_AGE,
+            dropped_messages: 0,
+            next_fragment_group: 0,
+            token: 0,
+            cipher: None,
+        }
+    }
+
+    pub(crate) fn token(&self) -> u64 {
+        self.token
+    }
+
+    pub(crate) fn set_token(&mut self, token: u64) {
+        self.token = token;
+    }
+
+    /// Encrypt everything sent from now on, and only accept encrypted datagrams.
+    pub(crate) fn set_cipher(&mut self, cipher: UdpCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    pub(crate) fn set_unreliable_max_age(&mut self, max_age: f64) {
+        self.unreliable_max_age = max_age;
+    }
+
+    /// Number of unreliable messages dropped because they waited too long for bandwidth.
+    pub(crate) fn dropped_messages(&self) -> u64 {
+        self.dropped_messages
+    }
+
+    /// Number of reliable messages that were not acknowledged yet.
+    pub(crate) fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Bytes of the messages waiting to be sent, and of the reliable messages waiting for an ack.
+    pub(crate) fn queued_bytes(&self) -> usize {
+        self.queue.iter().map(|message| message.packet.body.len()).sum::<usize>()
+            + self.pending.iter().map(|message| message.packet.body.len()).sum::<usize>()
+    }
+
+    /// Space left for the messages in a datagram, once the header and the encryption are accounted for.
+    fn payload_budget(&self) -> usize {
+        match self.cipher {
+            Some(_) => MAX_PAYLOAD_SIZE - UDP_OVERHEAD,
+            None => MAX_PAYLOAD_SIZE,
+        }
+    }
+
+    /// Build the datagrams carrying the payload : several of them if it has to be fragmented.
+    fn datagrams(&mut self, payload: &[u8], sender: u64, bandwidth: &mut Bandwidth) -> Vec<Vec<u8>> {
+        let header = ChannelHeader {
+            token: self.token,
+            sender,
+            sequence: self.local_sequence,
+            has_ack: self.remote_sequence.is_some(),
+            ack: self.remote_sequence.unwrap_or(0),
+            ack_bits: self.received_bits,
+        };
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+        self.ack_pending = false;
+        let mut datagram = Vec::with_capacity(HEADER_SIZE + payload.len() + UDP_OVERHEAD);
+        header.serialize_into(&mut datagram);
+        match &mut self.cipher {
+            // the header is all we wrote yet : it is authenticated with the payload
+            Some(cipher) => datagram.append(&mut cipher.seal(&datagram, payload)),
+            None => datagram.extend_from_slice(payload),
+        }
+        // the messages were counted when added to the payload
+        bandwidth.consume(datagram.len() - payload.len());
+        let result = fragments::split(&datagram, self.next_fragment_group, self.token, sender);
+        if result.len() > 1 {
+            self.next_fragment_group = self.next_fragment_group.wrapping_add(1);
+        }
+        result
+    }
+
+    /// Add a message to the datagram being built, sending the datagram first if the message does not fit in it.
+    /// Messages too big for any datagram go alone, and get fragmented. Returns the sequence of the datagram the message is in.
+    fn push_message(&mut self, payload: &mut Vec<u8>, message: &[u8], sender: u64, bandwidth: &mut Bandwidth, datagrams: &mut Vec<Vec<u8>>) -> u16 {
+        if !payload.is_empty() && payload.len() + message.len() > self.payload_budget() {
+            datagrams.append(&mut self.datagrams(payload, sender, bandwidth));
+            payload.clear();
+        }
+        payload.extend_from_slice(message);
+        self.local_sequence
+    }
+
+    /// Queue a packet on a channel, or an engine stream. It is sent on the next flush, or later if the bandwidth budget is exceeded.
+    pub(crate) fn send(&mut self, stream: impl Into<Stream>, packet: &Packet, priority: Priority, time: f64) {
+        let stream = stream.into();
+        let message_id = self.next_message_ids[stream.id() as usize];
+        self.next_message_ids[stream.id() as usize] = message_id.wrapping_add(1);
+        self.queue.push(QueuedMessage {
+            stream,
+            message_id,
+            packet: packet.clone(),
+            priority,
+            queued_at: time,
+        });
+    }
+
+    /// Build the datagrams to send this tick : reliable messages to resend and queued messages, coalesced in as few datagrams as possible.
+    /// Messages go by priority and age while the bandwidth allows it, the others wait for the next flush.
+    /// Unreliable messages that waited too long are dropped. Acks go with the messages, or alone if there is nothing else to send.
+    pub(crate) fn flush(&mut self, time: f64, sender: u64, bandwidth: &mut Bandwidth, stats: &mut StatsRecorder) -> Vec<Vec<u8>> {
+        let max_age = self.unreliable_max_age;
+        let queued = self.queue.len();
+        self.queue.retain(|message| message.stream.channel().is_reliable() || time - message.queued_at <= message.priority.max_age(max_age));
+        self.dropped_messages += (queued - self.queue.len()) as u64;
+
+        // everything that wants to be sent : (score, is a resend, index)
+        let mut candidates: Vec<(f64, bool, usize)> = self.pending.iter().enumerate()
+            .filter(|(_, message)| time - message.last_sent >= self.resend_delay)
+            .map(|(index, message)| (message.priority.score(message.last_sent, time), true, index))
+            .chain(self.queue.iter().enumerate().map(|(index, message)| (message.priority.score(message.queued_at, time), false, index)))
+            .collect();
+        // stable : same scores keep their order
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut result = Vec::new();
+        let mut payload = Vec::new();
+        // take the messages out to build the datagrams, as it needs self
+        let mut pending = std::mem::take(&mut self.pending);
+        let mut queue: Vec<Option<QueuedMessage>> = std::mem::take(&mut self.queue).into_iter().map(Some).collect();
+        for (_, resend, index) in candidates {
+            if !bandwidth.available() {
+                break; // the rest waits for the next flush
+            }
+            match resend {
+                true => {
+                    let message = &mut pending[index];
+                    let bytes = message_bytes(message.stream, message.message_id, &message.packet);
+                    bandwidth.consume(bytes.len());
+                    stats.message_sent(message.stream.channel(), &message.packet, bytes.len(), true);
+                    let sequence = self.push_message(&mut payload, &bytes, sender, bandwidth, &mut result);
+                    message.last_sent = time;
+                    message.sequences.push_back(sequence);
+                    if message.sequences.len() > TRACKED_SEQUENCES {
+                        message.sequences.pop_front();
+                    }
+                },
+                false => {
+                    let Some(message) = queue[index].take() else {
+                        continue;
+                    };
+                    let bytes = message_bytes(message.stream, message.message_id, &message.packet);
+                    bandwidth.consume(bytes.len());
+                    stats.message_sent(message.stream.channel(), &message.packet, bytes.len(), false);
+                    let sequence = self.push_message(&mut payload, &bytes, sender, bandwidth, &mut result);
+                    if message.stream.channel().is_reliable() {
+                        pending.push(PendingMessage {
+                            stream: message.stream,
+                            message_id: message.message_id,
+                            packet: message.packet,
+                            priority: message.priority,
+                            sequences: VecDeque::from([sequence]),
+                            last_sent: time,
+                        });
+                    }
+                },
+            }
+        }
+        self.pending = pending;
+        self.queue = queue.into_iter().flatten().collect();
+        if !payload.is_empty() || self.ack_pending {
+            result.append(&mut self.datagrams(&payload, sender, bandwidth));
+        }
+        result
+    }
+
+    /// Process a received datagram, and returns the packets that can be given to the user.
+    /// None if the datagram is not genuine (or a replay for encrypted connections) : it is ignored, acks included.
+    pub(crate) fn receive(&mut self, header: ChannelHeader, payload: &[u8], stats: &mut StatsRecorder) -> Option<Vec<Packet>> {
+        let messages = match &mut self.cipher {
+            Some(cipher) => read_messages(&cipher.open(&header.serialize(), payload)?)?,
+            None => read_messages(payload)?,
+        };
+        stats.datagram_received(HEADER_SIZE + payload.len());
+        // acks : remove the reliable messages the peer received, if it received anything
+        self.pending.retain(|message| !header.has_ack || !message.sequences.iter().any(|sequence| {
+            let back = header.ack.wrapping_sub(*sequence);
+            back == 0 || (back <= 32 && header.ack_bits & (1 << (back - 1)) != 0)
+        }));
+        // remember the sequence to acknowledge it
+        match self.remote_sequence {
+            None => self.remote_sequence = Some(header.sequence),
+            Some(remote) if sequence_greater_than(header.sequence, remote) => {
+                let shift = header.sequence.wrapping_sub(remote) as u32;
+                self.received_bits = match shift {
+                    1..=31 => (self.received_bits << shift) | (1 << (shift - 1)),
+                    32 => 1 << 31,
+                    _ => 0,
+                };
+                self.remote_sequence = Some(header.sequence);
+            },
+            Some(remote) => {
+                let back = remote.wrapping_sub(header.sequence) as u32;
+                if (1..=32).contains(&back) {
+                    self.received_bits |= 1 << (back - 1);
+                }
+            },
+        }
+        // datagrams with only acks don't need to be acknowledged, or we would ack each other forever
+        if !messages.is_empty() {
+            self.ack_pending = true;
+        }
+
+        let mut result = Vec::new();
+        for (stream, message_id, packet) in messages {
+            stats.message_received(stream.channel(), &packet, size_of::<u8>() + size_of::<u16>() + Packet::header_size() + packet.body.len());
+            self.receive_streams[stream.id() as usize].receive(stream.channel(), message_id, packet, &mut result);
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Give a datagram built by `flush` to the endpoint, as the udp buffer would.
+    fn deliver(to: &mut ChannelEndpoint, datagram: &[u8]) -> Option<Vec<Packet>> {
+        // whole datagrams start with a byte telling they are not fragmented
+        let mut reader = ByteReader::new(&datagram[1..]);
+        let (header, _) = ChannelHeader::deserialize_from(&mut reader).ok()?;
+        let payload = reader.read_bytes(reader.remaining()).ok()?.to_vec();
+        to.receive(header, &payload, &mut StatsRecorder::new())
+    }
+
+    fn flush(endpoint: &mut ChannelEndpoint, time: f64) -> Vec<Vec<u8>> {
+        endpoint.flush(time, 0, &mut Bandwidth::new(None), &mut StatsRecorder::new())
+    }
+
+    #[test]
+    fn lost_first_datagram_is_resent() {
+        let mut alice = ChannelEndpoint::new();
+        let mut bob = ChannelEndpoint::new();
+        let reliable = Packet::from(&42u32, 0);
+        alice.send(Channel::ReliableOrdered, &reliable, Priority::Normal, 0.);
+        bob.send(Channel::Unreliable, &Packet::from(&7u32, 0), Priority::Normal, 0.);
+
+        // both send before receiving anything, and the datagram 0 of alice is lost
+        let lost = flush(&mut alice, 0.);
+        assert_eq!(lost.len(), 1);
+        for datagram in flush(&mut bob, 0.) {
+            assert!(deliver(&mut alice, &datagram).is_some());
+        }
+        // bob did not receive anything : his datagram 0 does not acknowledge ours
+        assert_eq!(alice.pending_count(), 1);
+
+        let mut received = Vec::new();
+        for datagram in flush(&mut alice, DEFAULT_RESEND_DELAY) {
+            received.extend(deliver(&mut bob, &datagram).unwrap());
+        }
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].body, reliable.body);
+        // and now bob acks it for real
+        for datagram in flush(&mut bob, DEFAULT_RESEND_DELAY) {
+            deliver(&mut alice, &datagram);
+        }
+        assert_eq!(alice.pending_count(), 0);
+    }
+}
@@ -1,134 +1,441 @@
-use std::{net::{TcpStream, UdpSocket, SocketAddr, SocketAddrV4, Ipv4Addr}, thread::JoinHandle, io::Write};
-use std::thread;
+use std::{net::{SocketAddr, ToSocketAddrs}, io, sync::mpsc::{self, Receiver, TryRecvError}};
 
 use foundry::*;
-use crate::{NetworkSerializable, DefaultNetworkMessages};
+use crate::{NetworkSerializable, DefaultNetworkMessages, ProtocolVersion, RejectReason, NetworkTime, DEFAULT_TICK_RATE};
 
-use super::{packet::Packet, buffer::{TcpBuffer, UdpBuffer}};
+use super::{packet::Packet, buffer::{TcpBuffer, UdpBuffer, OutgoingBuffer, FrameError, DEFAULT_MAX_PACKET_SIZE}, channels::{Channel, ChannelEndpoint}, fragments::FragmentStats, transport::{ClientTransport, OsClientTransport}, heartbeat::{Heartbeat, ConnectionQuality, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_IDLE_TIMEOUT}, encryption::{KeyExchange, TcpCipher}, reconnect::{ReconnectPolicy, DEFAULT_CONNECT_TIMEOUT}, priority::{Priority, Bandwidth, DEFAULT_UNRELIABLE_MAX_AGE}, rpc::{Rpc, RpcCall, RpcEndpoint}, replication::{Networked, ReplicationClient, ReplicationEvent}, prediction::{Predicted, PredictionClient}, interpolation::{InterpolationStats, update_interpolation}, clock::ClockSync, stats::{NetworkStats, StatsRecorder}};
 
 /// client representation of the connection to the server
-pub struct Client<H: ClientHandler> {
+/// H is the client handler, T the transport used to talk to the server (os sockets by default).
+pub struct Client<H: ClientHandler, T: ClientTransport = OsClientTransport> {
     id: Option<u64>,
     client_handler: H,
-    // all this tcp stuff could be refactor into own struct ? no uses for now
-    tcp_connection: Option<TcpStream>,
+    transport: T,
+    tcp_connection: Option<T::Stream>,
     tcp_buffer: TcpBuffer,
-    udp_connection: Option<UdpSocket>,
+    /// tcp bytes waiting for the next flush
+    outgoing: OutgoingBuffer,
     udp_buffer: UdpBuffer,
-    tcp_incoming_packet: Option<Packet>,
-    tcp_connecting_thread: Option<JoinHandle<Result<TcpStream, String>>>,
+    channels: ChannelEndpoint,
+    protocol: ProtocolVersion,
+    /// time since the client started, in seconds. Used for the resend timers of the udp channels.
+    time: f64,
+    heartbeat: Heartbeat,
+    heartbeat_interval: f64,
+    idle_timeout: f64,
+    /// sent to the server when connecting, for it to accept us or not
+    login: Vec<u8>,
+    /// addresses of the server, kept to reconnect
+    server_addrs: Vec<SocketAddr>,
+    /// the address of the server being resolved on another thread, before connecting
+    resolving: Option<Receiver<io::Result<Vec<SocketAddr>>>>,
+    connect_timeout: f64,
+    reconnect: Option<ReconnectPolicy>,
+    state: ConnectionState,
+    /// state changes the handler was not told about yet
+    state_changes: Vec<ConnectionState>,
+    /// time the current connection attempt started, if any
+    connect_started: Option<f64>,
+    /// retries since we were last connected
+    reconnect_attempt: u32,
+    /// time of the next retry, if one is scheduled
+    next_attempt: Option<f64>,
+    /// udp datagrams thrown away because they did not have the session token
+    rejected_datagrams: u64,
+    /// encrypt the connection to the server
+    encryption: bool,
+    /// our half of the key agreement, while waiting for the server one
+    key_exchange: Option<KeyExchange>,
+    /// encrypts the tcp packets once the keys are agreed on
+    tcp_cipher: Option<TcpCipher>,
+    bandwidth: Bandwidth,
+    unreliable_max_age: f64,
+    /// calls made to the server, and responders to its calls
+    rpc: RpcEndpoint,
+    /// mirrors of the entities replicated by the server
+    replication: ReplicationClient,
+    /// runs our inputs on the predicted state, if any
+    prediction: Option<PredictionClient>,
+    interpolation_stats: InterpolationStats,
+    /// counts what goes through the connection to the server
+    stats: StatsRecorder,
+    /// estimation of the server clock, from the pongs of the server
+    clock: ClockSync,
+    /// number of ticks per second of the server
+    tick_rate: f64,
 }
 
 impl<H: ClientHandler> Client<H> {
     pub fn new(client_handler: H) -> Client<H> {
+        Client::with_transport(client_handler, OsClientTransport::new())
+    }
+}
+
+impl<H: ClientHandler, T: ClientTransport> Client<H, T> {
+    /// Create a client that talks to the server through the given transport.
+    pub fn with_transport(client_handler: H, transport: T) -> Client<H, T> {
         Client {
             id: None,
             client_handler: client_handler,
+            transport,
             tcp_connection: None,
-            tcp_buffer: TcpBuffer::new(),
-            udp_connection: None,
+            tcp_buffer: TcpBuffer::new(DEFAULT_MAX_PACKET_SIZE),
+            outgoing: OutgoingBuffer::new(),
             udp_buffer: UdpBuffer::new(),
-            tcp_incoming_packet: None,
-            tcp_connecting_thread: None,
+            channels: ChannelEndpoint::new(),
+            protocol: ProtocolVersion::new::<H::ServerMessages, H::ClientsMessages>(H::PROTOCOL_VERSION),
+            time: 0.,
+            heartbeat: Heartbeat::new(DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_IDLE_TIMEOUT, 0.),
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            login: Vec::new(),
+            server_addrs: Vec::new(),
+            resolving: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            reconnect: None,
+            state: ConnectionState::Disconnected,
+            state_changes: Vec::new(),
+            connect_started: None,
+            reconnect_attempt: 0,
+            next_attempt: None,
+            rejected_datagrams: 0,
+            encryption: false,
+            key_exchange: None,
+            tcp_cipher: None,
+            bandwidth: Bandwidth::new(None),
+            unreliable_max_age: DEFAULT_UNRELIABLE_MAX_AGE,
+            rpc: RpcEndpoint::new(),
+            replication: ReplicationClient::new(),
+            prediction: None,
+            interpolation_stats: InterpolationStats::default(),
+            stats: StatsRecorder::new(),
+            clock: ClockSync::new(),
+            tick_rate: DEFAULT_TICK_RATE,
         }
     }
 
-    pub fn try_connect(&mut self, ip: Ipv4Addr, port: u16) {
-        // clone the adress so we won't move ourself in the thread
-        let address = SocketAddr::V4(SocketAddrV4::new(ip, port));
-        self.tcp_connecting_thread = Some(thread::spawn(move || {
-            thread::sleep(std::time::Duration::from_secs(2));
-            match TcpStream::connect(&address) {
-                Ok(stream) => {
-                    match stream.set_nonblocking(true) {
-                        Err(e) => println!("[NETWORK CLIENT] -> Unable to set client as nonblocking ({e}). All client actions may freeze the engine."),
-                        _ => {},
-                    }
-                    return Ok(stream);
-                }
-                Err(e) => return Err(format!("Unable to connect to {address} : {e}")),
-            }
-        }));
+    /// Set the maximum size of the packets the server can send. If the server sends a bigger packet, we disconnect.
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Client<H, T> {
+        self.tcp_buffer = TcpBuffer::new(max_packet_size);
+        self
+    }
+
+    /// Set how long we wait for the fragments of a big udp message, and how much memory incomplete messages can use.
+    pub fn with_reassembly_limits(mut self, timeout: f64, max_memory: usize) -> Client<H, T> {
+        self.udp_buffer = UdpBuffer::with_reassembly_limits(timeout, max_memory);
+        self
+    }
+
+    /// Encrypt the connection to the server, which must have encryption enabled too (see `Server::with_encryption`).
+    /// The login and everything after it is encrypted and authenticated.
+    pub fn with_encryption(mut self) -> Client<H, T> {
+        self.encryption = true;
+        self
+    }
+
+    /// Whether the connection to the server is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.tcp_cipher.is_some()
+    }
+
+    /// Set the login sent to the server when connecting (a name, a token...).
+    /// The server handler gets it serialized in `on_connection_request`, and can read it back with `L::deserialize`.
+    pub fn with_login<L: NetworkSerializable>(mut self, login: &L) -> Client<H, T> {
+        self.login = login.serialize();
+        self
+    }
+
+    /// Set how long we wait for the connection to the server before giving up (in seconds). `f64::INFINITY` waits forever.
+    /// Negative timeouts give up right away, and NaN keeps the default.
+    pub fn with_connect_timeout(mut self, timeout: f64) -> Client<H, T> {
+        self.connect_timeout = match timeout.is_nan() {
+            true => DEFAULT_CONNECT_TIMEOUT,
+            false => timeout.max(0.),
+        };
+        self
+    }
+
+    /// Try to connect again when a connection attempt fails, or when the connection is lost.
+    /// Without it, the client stays disconnected.
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Client<H, T> {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Limit what is sent to the server to this many bytes per second. Udp messages that don't fit wait, by priority and age
+    /// (see `Priority`), and unreliable ones that waited too long are dropped. Tcp messages are counted, but never held back.
+    pub fn with_bandwidth_budget(mut self, bytes_per_second: u64) -> Client<H, T> {
+        self.bandwidth = Bandwidth::new(Some(bytes_per_second));
+        self
+    }
+
+    /// Set how long a normal priority unreliable message can wait for bandwidth before being dropped (in seconds).
+    /// Low priority messages wait half of it, high priority ones twice it.
+    pub fn with_unreliable_max_age(mut self, max_age: f64) -> Client<H, T> {
+        self.unreliable_max_age = max_age;
+        self.channels.set_unreliable_max_age(max_age);
+        self
+    }
+
+    /// Answer the calls to R made by the server (see `Server::call`).
+    /// Calls to a method without responder fail with `RpcError::UnknownMethod`.
+    pub fn with_rpc<R, F>(mut self, mut responder: F) -> Client<H, T>
+        where R: Rpc, F: FnMut(R, &mut ComponentTable) -> R::Response + 'static
+    {
+        self.rpc.register(move |_server, request, components| responder(request, components));
+        self
+    }
+
+    /// Call a method on the server. The callback is run with the response when it comes,
+    /// or with an error if the server can't answer, does not in time, or we get disconnected.
+    pub fn call(&mut self, call: RpcCall, components: &mut ComponentTable) {
+        // the server is the only peer
+        let request = self.rpc.start(call, 0, self.time);
+        match self.id {
+            Some(_) => self.send_default_message(&request),
+            None => {
+                println!("[NETWORK CLIENT] -> Unable to call the server : not connected.");
+                self.rpc.disconnected(0, components);
+            },
+        }
+    }
+
+    /// Mirror the component C of the entities replicated by the server. `Transform` is always mirrored.
+    /// The server must register the same components (see `Server::with_replicated`).
+    pub fn with_replicated<C: Networked>(mut self) -> Client<H, T> {
+        self.replication.types.register::<C>();
+        self
+    }
+
+    /// Predict the state P with our inputs, sent with `ClientMessage::predict` (see `Server::with_prediction`).
+    /// The state is in the `Prediction` singleton, starting from initial each time we connect. Each input is a tick of tick_delta seconds,
+    /// and the corrections of the server are smoothed over the given number of ticks (see `DEFAULT_SMOOTHING_TICKS`).
+    pub fn with_prediction<P: Predicted>(mut self, initial: P, tick_delta: f32, smoothing_ticks: u32) -> Client<H, T> {
+        self.prediction = Some(PredictionClient::new(initial, tick_delta, smoothing_ticks));
+        self
+    }
+
+    /// The entity mirroring the replicated entity with this network id (see `Replicated::network_id`), if it exists.
+    pub fn replicated_entity(&self, network_id: u64) -> Option<EntityRef> {
+        self.replication.entity(network_id)
+    }
+
+    /// Number of unreliable messages to the server dropped because they waited too long for bandwidth.
+    pub fn dropped_messages(&self) -> u64 {
+        self.channels.dropped_messages()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// The transport used to talk to the server.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Set how often the server is pinged, and how long it can stay silent before we disconnect (in seconds).
+    pub fn with_heartbeat(mut self, interval: f64, idle_timeout: f64) -> Client<H, T> {
+        self.heartbeat_interval = interval;
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Round trip time and other measures of the connection to the server, estimated with pings.
+    pub fn quality(&self) -> ConnectionQuality {
+        self.heartbeat.quality()
+    }
+
+    /// Smoothed round trip time to the server, in seconds.
+    pub fn rtt(&self) -> f64 {
+        self.heartbeat.quality().rtt
+    }
+
+    /// Variation of the round trip time to the server, in seconds.
+    pub fn jitter(&self) -> f64 {
+        self.heartbeat.quality().jitter
+    }
+
+    /// Ratio of the recent pings to the server that were lost, between 0 and 1.
+    pub fn packet_loss(&self) -> f64 {
+        self.heartbeat.quality().loss
+    }
+
+    /// Number of udp datagrams thrown away because they did not carry the session token.
+    pub fn rejected_datagrams(&self) -> u64 {
+        self.rejected_datagrams
+    }
+
+    /// Underruns and overruns of the snapshot buffers of all the `Interpolated` entities, which the client moves on each update.
+    pub fn interpolation_stats(&self) -> InterpolationStats {
+        self.interpolation_stats
+    }
+
+    /// Statistics about the fragmented udp messages received from the server.
+    pub fn fragment_stats(&self) -> FragmentStats {
+        self.udp_buffer.fragment_stats()
+    }
+
+    /// Start connecting to the server, like `"127.0.0.1:7777"`, `"[::1]:7777"` or `"example.com:7777"`.
+    /// The address is resolved once, on another thread so a slow dns does not block the game, and reconnections reuse it.
+    /// The handler is told when we are connected, or if it failed (the address could not be resolved, for instance).
+    /// Fails right away if the resolving thread can't be started.
+    pub fn try_connect<A: ToSocketAddrs + Send + 'static>(&mut self, addr: A) -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new().name("gear dns".to_string()).spawn(move || {
+            // the client may be gone by now, then nobody cares
+            let _ = sender.send(addr.to_socket_addrs().map(|addrs| addrs.collect()));
+        })?;
+        self.resolving = Some(receiver);
+        self.server_addrs = Vec::new();
+        self.reconnect_attempt = 0;
+        self.next_attempt = None;
+        self.connect_started = None;
+        self.set_state(ConnectionState::Connecting);
+        Ok(())
+    }
+
+    /// Start connecting once the address of the server is resolved. There is nothing to retry with if it can't be.
+    fn poll_resolving(&mut self, components: &mut ComponentTable) {
+        let Some(resolving) = &self.resolving else {
+            return;
+        };
+        let result = match resolving.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => Err(io::Error::new(io::ErrorKind::Other, "the address resolution stopped")),
+        };
+        self.resolving = None;
+        match result {
+            Ok(addrs) if !addrs.is_empty() => {
+                self.server_addrs = addrs;
+                self.start_connecting();
+            },
+            result => {
+                let error = result.err().unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"));
+                println!("[NETWORK CLIENT] -> Unable to resolve the server address : {error}.");
+                self.set_state(ConnectionState::Failed);
+                self.client_handler.on_connection_failed(components);
+            },
+        }
+    }
+
+    fn start_connecting(&mut self) {
+        println!("[NETWORK CLIENT] -> Connecting to {:?}.", self.server_addrs);
+        self.transport.connect(&self.server_addrs, self.connect_timeout);
+        self.connect_started = Some(self.time);
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            self.state = state;
+            self.state_changes.push(state);
+        }
+    }
+
+    /// Schedule the next connection attempt, if the policy allows it. Returns false if we give up.
+    fn schedule_reconnect(&mut self) -> bool {
+        let policy = match self.reconnect {
+            Some(policy) => policy,
+            None => return false,
+        };
+        if policy.max_attempts.is_some_and(|max| self.reconnect_attempt >= max) {
+            return false;
+        }
+        let delay = policy.delay(self.reconnect_attempt);
+        self.reconnect_attempt += 1;
+        println!("[NETWORK CLIENT] -> Reconnecting in {delay} seconds (attempt {}).", self.reconnect_attempt);
+        self.next_attempt = Some(self.time + delay);
+        self.set_state(ConnectionState::Reconnecting(self.reconnect_attempt));
+        true
+    }
+
+    /// A connection attempt did not work : try again later, or tell the handler we give up.
+    fn connection_attempt_failed(&mut self, error: io::Error, components: &mut ComponentTable) {
+        println!("[NETWORK CLIENT] -> Unable to connect to server : {error}.");
+        self.connect_started = None;
+        if !self.schedule_reconnect() {
+            self.set_state(ConnectionState::Failed);
+            self.client_handler.on_connection_failed(components);
+        }
     }
 
     pub fn disconnect(&mut self, reason: DisconnectReason, components: &mut ComponentTable) {
+        println!("[NETWORK CLIENT] -> Got disconnected from server for reason : {reason:?}");
+        let retry = matches!(reason, DisconnectReason::ServerShutDown | DisconnectReason::TimedOut | DisconnectReason::ConnectionLost);
         self.client_handler.on_disconected(reason, components);
+        self.rpc.disconnected(0, components);
+        let handler = &mut self.client_handler;
+        self.replication.reset(components, |event, components| report_replication_event(handler, event, components));
+        self.id = None;
         self.tcp_connection = None;
-        self.udp_connection = None;
-        println!("[NETWORK CLIENT] -> Got disconnected from server for reason : {reason:?}");
-    }
-
-    fn create_udp_from_tcp(&self, tcp: &TcpStream) -> Result<UdpSocket, std::io::Error> {
-        let udp = UdpSocket::bind(tcp.local_addr()?)?;
-        udp.connect(tcp.peer_addr()?)?;
-        udp.set_nonblocking(true)?;
-        Ok(udp)
+        self.outgoing = OutgoingBuffer::new();
+        self.transport.disconnect();
+        self.resolving = None;
+        self.connect_started = None;
+        self.next_attempt = None;
+        if self.state == ConnectionState::Connected {
+            self.reconnect_attempt = 0;
+        }
+        if !retry || !self.schedule_reconnect() {
+            self.set_state(ConnectionState::Disconnected);
+        }
     }
 
-    pub fn get_incoming_packets(&mut self) -> Result<Vec<Packet>, std::io::Error> {
+    pub fn get_incoming_packets(&mut self) -> Result<Vec<Packet>, FrameError> {
         let mut result = self.get_incoming_tcp()?;
-        result.append(&mut self.get_incoming_udp()?);
+        result.append(&mut self.get_incoming_udp().map_err(FrameError::Io)?);
         Ok(result)
     }
 
-    pub fn get_incoming_tcp(&mut self) -> Result<Vec<Packet>, std::io::Error> {
-        match &mut self.tcp_connection {
-            Some(connection) => {
-                if self.tcp_buffer.read_tcp(connection)? {
-                    // let's read !
-                    // check if there is an unfinished packet to read
-                    let mut result = Vec::new();
-                    if let Some(mut packet) = self.tcp_incoming_packet.take() {
-                        // try complete the packet
-                        if self.tcp_buffer.try_complete_packet(&mut packet) {
-                            result.push(packet);
-                        }
-                        else {
-                            // buffer is empty, we can return
-                            return Ok(result);
-                        }
-                    }
-                    loop {
-                        let new_packet = self.tcp_buffer.try_read_packet();
-                        match new_packet {
-                            Some(packet) => {
-                                if packet.awaiting_size() == 0 {
-                                    // packet complete, keep getting more !
-                                    result.push(packet);
-                                }
-                                else {
-                                    // put the packet as awaiting and break
-                                    self.tcp_incoming_packet = Some(packet);
-                                    break;
-                                }
-                            },
-                            None => break,
-                        }
-                    }
-                    Ok(result)
-                }
-                else {
-                    Ok(Vec::with_capacity(0))
-                }
-            }
-            None => Ok(Vec::with_capacity(0)),
+    pub fn get_incoming_tcp(&mut self) -> Result<Vec<Packet>, FrameError> {
+        let packets = match &mut self.tcp_connection {
+            Some(connection) => self.tcp_buffer.read_packets(connection)?,
+            None => return Ok(Vec::with_capacity(0)),
+        };
+        let packets: Vec<Packet> = match &mut self.tcp_cipher {
+            Some(cipher) => packets.iter().map(|packet| cipher.open(packet).ok_or(FrameError::Corrupted)).collect::<Result<_, _>>()?,
+            None => packets,
+        };
+        for packet in packets.iter() {
+            self.stats.tcp_received(packet);
         }
+        Ok(packets)
     }
 
     fn get_incoming_udp(&mut self) -> Result<Vec<Packet>, std::io::Error> {
-        match &mut self.udp_connection {
-            Some(connection) => {
-                let mut result = Vec::new();
-                while let Some(packet) = self.udp_buffer.read_tcp(connection)? {
-                    result.push(packet.0); // todo; check it is the server ?
+        let mut result = Vec::new();
+        // the transport only gives us datagrams from the server
+        while let Some((header, payload, _from)) = self.udp_buffer.read_udp(
+            |buffer| self.transport.recv(buffer),
+            // fragments are checked before being kept : the server only sends big messages once we are welcomed
+            |token, _sender, _from| {
+                let genuine = self.id.is_some() && token == self.channels.token();
+                if !genuine {
+                    self.rejected_datagrams += 1;
                 }
-                Ok(result)
+                genuine
+            },
+            self.time,
+        )? {
+            // once welcomed, we know the token the server puts in its datagrams
+            if self.id.is_some() && header.token() != self.channels.token() {
+                self.rejected_datagrams += 1;
+                continue;
+            }
+            // encrypted connections also check the datagram was not changed, or replayed
+            match self.channels.receive(header, &payload, &mut self.stats) {
+                Some(mut packets) => {
+                    self.heartbeat.udp_received(self.time);
+                    result.append(&mut packets);
+                },
+                None => self.rejected_datagrams += 1,
             }
-            None => Ok(Vec::with_capacity(0)),
         }
+        Ok(result)
     }
 
-    pub fn send_tcp(&mut self, message: H::ClientsMessages) {
+    pub fn send_tcp(&mut self, message: &H::ClientsMessages) {
         let packet = Packet::from(message, match self.id {
             Some(id) => id,
             None => {
@@ -136,18 +443,25 @@ impl<H: ClientHandler> Client<H> {
                 return;
             }
         });
-        match &mut self.tcp_connection {
-            Some(connection) => {
-                match connection.write(&packet.as_bytes()) {
-                    Ok(_amount_written) => {/* all good */},
-                    Err(e) => println!("[NETWORK CLIENT] -> Error while sending data : {e}")
-                };
-            }
-            None => println!("[NETWORK CLIENT] => Unable to send data to server : no active tcp connection !"),
+        self.write_tcp(&packet);
+    }
+
+    /// Queue a packet for the tcp stream, encrypted if the connection is. It is written on the next flush.
+    fn write_tcp(&mut self, packet: &Packet) {
+        if self.tcp_connection.is_none() {
+            println!("[NETWORK CLIENT] => Unable to send data to server : no active tcp connection !");
+            return;
         }
+        self.stats.tcp_sent(packet);
+        // sealed now, so the packets are encrypted in the order they are written
+        let bytes = match &mut self.tcp_cipher {
+            Some(cipher) => self.outgoing.push(&cipher.seal(packet)),
+            None => self.outgoing.push(packet),
+        };
+        self.bandwidth.consume(bytes);
     }
 
-    pub fn send_udp(&mut self, message: H::ClientsMessages) {
+    pub fn send_udp(&mut self, channel: Channel, priority: Priority, message: &H::ClientsMessages) {
         let packet = Packet::from(message, match self.id {
             Some(id) => id,
             None => {
@@ -155,60 +469,296 @@ impl<H: ClientHandler> Client<H> {
                 return;
             }
         });
-        match &mut self.udp_connection {
-            Some(connection) => {
-                let bytes = packet.as_bytes();
-                match connection.send(&bytes) {
-                    Ok(_amount_written) => { /* all good */},
-                    Err(e) => println!("[NETWORK CLIENT] -> Error while sending data : {e}")
-                };
+        self.channels.send(channel, &packet, priority, self.time);
+    }
+
+    /// Send a message returned by the handler. Udp messages go with the given priority, unless the message sets its own.
+    fn send_message(&mut self, message: ClientMessage<H::ClientsMessages>, priority: Priority, components: &mut ComponentTable) {
+        match message {
+            ClientMessage::Tcp(message) => self.send_tcp(&message),
+            ClientMessage::Udp(message) => self.send_udp(Channel::Unreliable, priority, &message),
+            ClientMessage::Channel(channel, message) => self.send_udp(channel, priority, &message),
+            ClientMessage::Prioritized(priority, message) => self.send_message(*message, priority, components),
+            ClientMessage::Call(call) => self.call(call, components),
+            ClientMessage::Predict(input) => self.predict(&input, components),
+        }
+    }
+
+    /// Predict the next tick with the input, and send it with the ones the server did not process yet.
+    fn predict(&mut self, input: &[u8], components: &mut ComponentTable) {
+        let Some(prediction) = &self.prediction else {
+            println!("[NETWORK CLIENT] -> Unable to predict an input : prediction is not enabled.");
+            return;
+        };
+        if let Some(message) = prediction.input(input, components) {
+            // inputs are sent again until processed, so losing them does not matter
+            self.send_default_udp(&message);
+        }
+    }
+
+    /// Update the `NetworkTime` singleton with the current estimate of the server clock, creating it if needed.
+    fn update_network_time(&mut self, components: &mut ComponentTable) {
+        if components.get_singleton::<NetworkTime>().is_none() {
+            components.add_singleton(NetworkTime::new());
+        }
+        if let Some(network_time) = components.get_singleton_mut::<NetworkTime>() {
+            network_time.update(&self.clock, self.time, self.tick_rate);
+        }
+    }
+
+    /// Write everything that was queued this tick : the tcp packets, then the udp messages coalesced in as few datagrams
+    /// as possible, with the reliable messages to resend and the acks. What the os could not take is written on the next flush.
+    fn flush(&mut self, components: &mut ComponentTable) {
+        self.bandwidth.refill(self.time);
+        if let Some(connection) = &mut self.tcp_connection {
+            if let Err(e) = self.outgoing.flush(connection) {
+                println!("[NETWORK CLIENT] -> Error while sending data : {e}");
+                self.disconnect(DisconnectReason::ConnectionLost, components);
+                return;
+            }
+        }
+        let Some(id) = self.id else {
+            return;
+        };
+        for datagram in self.channels.flush(self.time, id, &mut self.bandwidth, &mut self.stats) {
+            // the datagrams that did not make it are lost like any other, reliable messages will be resent
+            if let Err(e) = self.transport.send(&datagram) {
+                println!("[NETWORK CLIENT] -> Error while sending data : {e}");
+                break;
+            }
+            self.stats.datagram_sent(datagram.len());
+        }
+    }
+
+    /// Copy the statistics of the connection to the server in the `NetworkStats` singleton.
+    fn update_stats(&mut self, components: &mut ComponentTable) {
+        let server = self.tcp_connection.is_some().then(|| {
+            let mut stats = self.stats.snapshot(self.time);
+            stats.quality = self.heartbeat.quality();
+            stats.pending_reliable = self.channels.pending_count();
+            stats.queued_bytes = self.queued_bytes();
+            stats.dropped_messages = self.channels.dropped_messages();
+            stats.rejected_datagrams = self.rejected_datagrams;
+            stats.interpolation = self.interpolation_stats;
+            stats
+        });
+        if components.get_singleton::<NetworkStats>().is_none() {
+            components.add_singleton(NetworkStats::default());
+        }
+        if let Some(stats) = components.get_singleton_mut::<NetworkStats>() {
+            stats.server = server;
+        }
+    }
+
+    /// Number of bytes waiting to be sent to the server : tcp bytes the os did not take yet, and udp messages not acknowledged yet.
+    pub fn queued_bytes(&self) -> usize {
+        self.outgoing.len() + self.channels.queued_bytes()
+    }
+
+    fn report_state_changes(&mut self, components: &mut ComponentTable) {
+        for state in std::mem::take(&mut self.state_changes) {
+            self.client_handler.on_connection_state_changed(state, components);
+        }
+    }
+
+    /// Disconnect if the server went silent for too long, and ping it if it's time to.
+    fn update_heartbeat(&mut self, components: &mut ComponentTable) {
+        if self.tcp_connection.is_none() {
+            return;
+        }
+        if self.heartbeat.timed_out(self.time) {
+            println!("[NETWORK CLIENT] -> Server timed out.");
+            self.disconnect(DisconnectReason::TimedOut, components);
+            return;
+        }
+        // we can't send udp until we know our id
+        if self.id.is_some() {
+            if let Some(ping) = self.heartbeat.ping(self.time) {
+                self.send_heartbeat(&DefaultNetworkMessages::Ping(ping));
             }
-            None => println!("[NETWORK CLIENT] => Unable to send data to server : no active udp connection !"),
         }
     }
 
-    pub fn handle_default(&mut self, default_message: DefaultNetworkMessages) {
+    /// Send a ping or a pong over udp, or over tcp if udp does not get through from the server.
+    fn send_heartbeat(&mut self, message: &DefaultNetworkMessages) {
+        match self.heartbeat.udp_works(self.time) {
+            true => self.send_default_udp(message),
+            false => self.send_default_message(message),
+        }
+    }
+
+    /// Send a default message on the unreliable udp channel, for the ones that don't care about being lost (pings).
+    fn send_default_udp(&mut self, message: &DefaultNetworkMessages) {
+        let Some(id) = self.id else {
+            return;
+        };
+        // they go first, so waiting for bandwidth does not change the measured round trip time
+        self.channels.send(Channel::Unreliable, &Packet::from_default(message, id), Priority::High, self.time);
+    }
+
+    /// Number of reliable udp messages sent to the server that were not acknowledged yet.
+    pub fn pending_reliable_count(&self) -> usize {
+        self.channels.pending_count()
+    }
+
+    pub fn send_default_message(&mut self, message: &DefaultNetworkMessages) {
+        // default messages can be sent before knowing our id (hello)
+        let packet = Packet::from_default(message, self.id.unwrap_or(0));
+        self.write_tcp(&packet);
+    }
+
+    pub fn handle_default(&mut self, default_message: DefaultNetworkMessages, components: &mut ComponentTable) -> Vec<ClientMessage<H::ClientsMessages>> {
         match default_message {
-            DefaultNetworkMessages::Welcome(id) => {
+            DefaultNetworkMessages::Welcome(id, protocol, token) => {
+                if protocol != self.protocol {
+                    // the server should have rejected us, but let's not talk garbage with it
+                    self.disconnect(DisconnectReason::ProtocolMismatch(protocol), components);
+                    return Vec::new();
+                }
+                // a welcome before the keys are agreed on came in clear : someone on the way may be stripping the encryption
+                if self.encryption && self.tcp_cipher.is_none() {
+                    println!("[NETWORK CLIENT] -> The server welcomed us without encryption, disconnecting.");
+                    self.disconnect(DisconnectReason::EncryptionUnsupported, components);
+                    return Vec::new();
+                }
                 self.id = Some(id);
+                self.channels.set_token(token);
+                // the server may have restarted, its clock with it
+                self.clock = ClockSync::new();
+                if let Some(network_time) = components.get_singleton_mut::<NetworkTime>() {
+                    network_time.reset();
+                }
+                self.reconnect_attempt = 0;
+                self.set_state(ConnectionState::Connected);
+                if let Some(prediction) = &self.prediction {
+                    prediction.reset(components);
+                }
                 println!("[NETWORK CLIENT] -> Connected to server as client {id}.");
+                self.client_handler.on_connected(components)
+            },
+            DefaultNetworkMessages::Rejected(reason) => {
+                self.disconnect(match reason {
+                    RejectReason::ServerFull => DisconnectReason::ServerFull,
+                    RejectReason::ProtocolMismatch(protocol) => DisconnectReason::ProtocolMismatch(protocol),
+                    RejectReason::Banned => DisconnectReason::Banned,
+                    RejectReason::Refused(reason) => DisconnectReason::Refused(reason),
+                    RejectReason::EncryptionRequired => DisconnectReason::EncryptionRequired,
+                    RejectReason::EncryptionUnsupported => DisconnectReason::EncryptionUnsupported,
+                }, components);
+                Vec::new()
+            },
+            DefaultNetworkMessages::PublicKey(key) => {
+                let keys = match self.key_exchange.take().map(|exchange| exchange.finish(key, true)) {
+                    Some(Some(keys)) => keys,
+                    Some(None) => {
+                        println!("[NETWORK CLIENT] -> The server sent an invalid key.");
+                        self.disconnect(DisconnectReason::InvalidPacket, components);
+                        return Vec::new();
+                    },
+                    None => return Vec::new(), // we did not ask for it
+                };
+                self.tcp_cipher = Some(keys.tcp);
+                self.channels.set_cipher(keys.udp);
+                // the connection is secured, we can log in
+                self.send_default_message(&DefaultNetworkMessages::Login(self.login.clone()));
+                Vec::new()
+            },
+            DefaultNetworkMessages::Kicked(reason) => {
+                self.disconnect(DisconnectReason::ServerKick(reason), components);
+                Vec::new()
+            },
+            DefaultNetworkMessages::Ping(ping) => {
+                self.send_heartbeat(&DefaultNetworkMessages::Pong(ping, self.time));
+                Vec::new()
+            },
+            DefaultNetworkMessages::Pong(ping, server_time) => {
+                if let Some(sent) = self.heartbeat.pong(ping, self.time) {
+                    self.clock.sample(sent, server_time, self.time);
+                }
+                Vec::new()
+            },
+            DefaultNetworkMessages::TickRate(tick_rate) => {
+                self.tick_rate = tick_rate;
+                Vec::new()
+            },
+            DefaultNetworkMessages::Snapshot(snapshot, baseline, entities, despawned) => {
+                let handler = &mut self.client_handler;
+                let acked = self.replication.receive(snapshot, baseline, entities, despawned, components, |event, components| report_replication_event(handler, event, components));
+                if let Some(snapshot) = acked {
+                    self.send_default_udp(&DefaultNetworkMessages::SnapshotAck(snapshot));
+                }
+                Vec::new()
+            },
+            DefaultNetworkMessages::PredictionState(tick, state) => {
+                if let Some(Err(e)) = self.prediction.as_ref().map(|prediction| prediction.reconcile(tick, &state, components)) {
+                    println!("[NETWORK CLIENT] -> Unable to read the predicted state : {e:?}.");
+                }
+                Vec::new()
+            },
+            DefaultNetworkMessages::RpcRequest(id, method, request) => {
+                let answer = self.rpc.respond(0, id, method, &request, components);
+                self.send_default_message(&answer);
+                Vec::new()
+            },
+            DefaultNetworkMessages::RpcResponse(id, response) => {
+                self.rpc.complete(0, id, Ok(response), components);
+                Vec::new()
             },
-            _ => {},
+            DefaultNetworkMessages::RpcFailed(id, error) => {
+                self.rpc.complete(0, id, Err(error), components);
+                Vec::new()
+            },
+            _ => Vec::new(),
         } 
     }
 
 }
 
-impl<H: ClientHandler + 'static> Updatable for Client<H> {
+impl<H: ClientHandler + 'static, T: ClientTransport> Updatable for Client<H, T> {
     fn update(&mut self, components: &mut ComponentTable, delta: f32, _user_data: &mut dyn std::any::Any) {
+        self.time += delta as f64;
+        self.transport.update(self.time);
+        // before the messages, so the handler has it when they are handled
+        self.update_network_time(components);
+        self.poll_resolving(components);
+        // retry connecting when it's time
+        if self.next_attempt.is_some_and(|next| self.time >= next) {
+            self.next_attempt = None;
+            self.start_connecting();
+        }
+        // give up on connections that take too long, whatever the transport does
+        if self.connect_started.is_some_and(|started| self.time - started > self.connect_timeout) {
+            self.transport.disconnect();
+            self.connection_attempt_failed(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"), components);
+        }
         // check if we are trying to connect to a server
-        if let Some(handle) = self.tcp_connecting_thread.take() {
-            if handle.is_finished() {
-                match handle.join() {
-                    Ok(thread_result) => {
-                        match thread_result {
-                            Ok(tcp_stream) => {
-                                // try to create a udp 'connection'
-                                let udp = self.create_udp_from_tcp(&tcp_stream);
-
-                                self.tcp_connection = Some(tcp_stream);
-                                self.client_handler.on_connected(components);
-                                // create the udp !
-                                match udp {
-                                    Ok(udp) => self.udp_connection = Some(udp),
-                                    Err(e) => println!("[NETWORK CLIENT] -> Fail to use udp with the server : {e}."),
-                                }
-                            }
-                            Err(_e) => {self.client_handler.on_connection_failed(components);},
-                        }
+        match self.transport.poll_connect() {
+            Some(Ok(tcp_stream)) => {
+                self.connect_started = None;
+                self.tcp_connection = Some(tcp_stream);
+                // start from a clean buffer, previous connections may have left data in it
+                self.tcp_buffer = TcpBuffer::new(self.tcp_buffer.max_packet_size());
+                self.outgoing = OutgoingBuffer::new();
+                self.channels = ChannelEndpoint::new();
+                self.channels.set_unreliable_max_age(self.unreliable_max_age);
+                self.stats = StatsRecorder::new();
+                self.heartbeat = Heartbeat::new(self.heartbeat_interval, self.idle_timeout, self.time);
+                self.tcp_cipher = None;
+                // introduce ourself : the handler is told we are connected once the server welcomes us
+                let protocol = self.protocol;
+                self.send_default_message(&DefaultNetworkMessages::Hello(protocol));
+                match self.encryption {
+                    // the login waits for the server key
+                    true => {
+                        let exchange = KeyExchange::new();
+                        self.send_default_message(&DefaultNetworkMessages::PublicKey(exchange.public_key()));
+                        self.key_exchange = Some(exchange);
                     },
-                    Err(_e) => { /* unable to finish join thread properly */},
+                    false => self.send_default_message(&DefaultNetworkMessages::Login(self.login.clone())),
                 }
-            }
-            else {
-                // move the thread back, as we moved it to get ownership of it
-                self.tcp_connecting_thread = Some(handle);
-            }
+            },
+            Some(Err(e)) => self.connection_attempt_failed(e, components),
+            None => {},
         }
 
         let mut to_send_messages = Vec::new();
@@ -217,34 +767,62 @@ impl<H: ClientHandler + 'static> Updatable for Client<H> {
         match self.get_incoming_packets() {
             Ok(packets) => {
                 for packet in packets {
+                    self.heartbeat.received(self.time);
                     match packet.is_default() {
                         true => match packet.into::<DefaultNetworkMessages>() {
-                            Ok(message) => self.handle_default(message),
-                            Err(_e) => println!("[NETWORK CLIENT] -> Unable to deserialize packet !"),
+                            Ok(message) => to_send_messages.append(&mut self.handle_default(message, components)),
+                            Err(_e) => {
+                                println!("[NETWORK CLIENT] -> Unable to deserialize packet !");
+                                self.stats.deserialization_failed();
+                            },
                         }
-                        false => match packet.into() {
-                            Ok(data) => to_send_messages.append(&mut self.client_handler.handle_message(data, components)),
-                            Err(_) => println!("[NETWORK CLIENT] -> Unable to deserialize packet !"),
+                        false => {
+                            // the handler can see when the server sent the message
+                            let tick = packet.tick();
+                            if let Some(network_time) = components.get_singleton_mut::<NetworkTime>() {
+                                network_time.set_message_tick(Some(tick));
+                            }
+                            match packet.into() {
+                                Ok(data) => to_send_messages.append(&mut self.client_handler.handle_message(data, components)),
+                                Err(_) => {
+                                    println!("[NETWORK CLIENT] -> Unable to deserialize packet !");
+                                    self.stats.deserialization_failed();
+                                },
+                            }
+                            if let Some(network_time) = components.get_singleton_mut::<NetworkTime>() {
+                                network_time.set_message_tick(None);
+                            }
                         }
                     }
                 }
             },
             Err(e) => {
                 println!("[NETWORK CLIENT] -> Error while receiving tcp packets : {e}.");
-                self.disconnect(DisconnectReason::ServerShutDown, components);
+                self.disconnect(match e {
+                    FrameError::PacketTooLarge { .. } | FrameError::Corrupted => DisconnectReason::InvalidPacket,
+                    FrameError::ConnectionClosed => DisconnectReason::ServerShutDown,
+                    FrameError::Io(_) => DisconnectReason::ConnectionLost,
+                }, components);
             }
         }
 
+        self.update_heartbeat(components);
+        self.rpc.expire(self.time, components);
+
         // user stuff
         to_send_messages.append(&mut self.client_handler.update(components, delta));
 
+        // after the handler, so the transforms it pushed this update are used
+        self.interpolation_stats.add(update_interpolation(components));
+
         // send all messages !
         for message in to_send_messages.into_iter() {
-            match message {
-                ClientMessage::Tcp(message) => self.send_tcp(message),
-                ClientMessage::Udp(message) => self.send_udp(message), 
-            }
+            self.send_message(message, Priority::Normal, components);
         }
+
+        self.flush(components);
+        self.update_stats(components);
+        self.report_state_changes(components);
     }
 
     fn as_any(&self) -> &dyn std::any::Any {
@@ -256,24 +834,89 @@ impl<H: ClientHandler + 'static> Updatable for Client<H> {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+fn report_replication_event<H: ClientHandler>(handler: &mut H, event: ReplicationEvent, components: &mut ComponentTable) {
+    match event {
+        ReplicationEvent::Spawned(network_id, entity) => handler.on_entity_spawned(network_id, entity, components),
+        ReplicationEvent::Despawned(network_id, entity) => handler.on_entity_despawned(network_id, entity, components),
+    }
+}
+
+/// Why a connection ended. Used on both sides : the client gets it in `on_disconected`, the server in `on_client_disconnected`.
+#[derive(Debug, Clone)]
 pub enum DisconnectReason {
     ClientShutDown,
     ServerShutDown,
-    ServerKick,
+    /// The server kicked the client. Carries the reason it gave.
+    ServerKick(String),
+    ServerFull,
+    /// The client address or id is banned from the server.
+    Banned,
+    /// The server handler refused the login. Carries the reason it gave.
+    Refused(String),
+    /// The server only accepts encrypted connections, and the client does not have encryption enabled.
+    EncryptionRequired,
+    /// The client has encryption enabled, and the server does not.
+    EncryptionUnsupported,
+    /// The server speaks another protocol. Carries the server protocol.
+    ProtocolMismatch(ProtocolVersion),
+    /// The peer sent a packet we can't accept (too large).
+    InvalidPacket,
+    /// We did not hear from the peer for longer than the idle timeout.
+    TimedOut,
+    /// The connection broke without the peer saying goodbye.
+    ConnectionLost,
+}
+
+/// Where the client is in its connection to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    /// Connecting to the server, or waiting for it to accept us.
+    Connecting,
+    /// The server welcomed us.
+    Connected,
+    /// A connection attempt failed or the connection was lost, and we will try again. Carries the attempt number, from 1.
+    Reconnecting(u32),
+    /// We could not connect, and gave up.
+    Failed,
 }
 
 pub enum ClientMessage<E: NetworkSerializable> {
     Tcp(E),
+    /// Udp message on the unreliable channel.
     Udp(E),
+    /// Udp message with the given delivery guarantees.
+    Channel(Channel, E),
+    /// Send the udp message with the given priority, instead of the normal one (see `Priority`).
+    Prioritized(Priority, Box<ClientMessage<E>>),
+    /// Call a method on the server (see `Client::call`).
+    Call(RpcCall),
+    /// Predict a tick with the serialized input, and send it to the server (see `ClientMessage::predict`).
+    Predict(Vec<u8>),
+}
+
+impl<E: NetworkSerializable> ClientMessage<E> {
+    /// Predict a tick with the input of the predicted state P (see `Client::with_prediction`).
+    pub fn predict<P: Predicted>(input: &P::Input) -> ClientMessage<E> {
+        ClientMessage::Predict(input.serialize())
+    }
 }
 
 pub trait ClientHandler {
     type ServerMessages: NetworkSerializable;
     type ClientsMessages: NetworkSerializable;
+    /// Version of the game protocol. It must match the server one to be accepted.
+    const PROTOCOL_VERSION: u32 = 0;
     fn on_connected(&mut self, components: &mut ComponentTable) -> Vec<ClientMessage<Self::ClientsMessages>>;
+    /// Called when we could not connect to the server, and will not try again.
     fn on_connection_failed(&mut self, components: &mut ComponentTable);
+    /// Called when the client goes from a connection state to another. Does nothing by default.
+    fn on_connection_state_changed(&mut self, _state: ConnectionState, _components: &mut ComponentTable) {}
     fn on_disconected(&mut self, reason: DisconnectReason, components: &mut ComponentTable);
+    /// Called when a replicated entity becomes relevant to us, once it is mirrored with its components. Does nothing by default.
+    fn on_entity_spawned(&mut self, _network_id: u64, _entity: EntityRef, _components: &mut ComponentTable) {}
+    /// Called when a mirrored entity stops being relevant to us (or is removed by the server), before it is destroyed. Does nothing by default.
+    fn on_entity_despawned(&mut self, _network_id: u64, _entity: EntityRef, _components: &mut ComponentTable) {}
     fn update(&mut self, components: &mut ComponentTable, delta: f32) -> Vec<ClientMessage<Self::ClientsMessages>>;
     fn handle_message(&mut self, message: Self::ServerMessages, components: &mut ComponentTable) -> Vec<ClientMessage<Self::ClientsMessages>>;
 }
\ No newline at end of file
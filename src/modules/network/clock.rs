@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+
+/// Default number of server ticks per second.
+pub const DEFAULT_TICK_RATE: f64 = 60.;
+/// Number of pings used to estimate the clock of the server.
+const CLOCK_WINDOW: usize = 16;
+/// Samples with a round trip longer than this many times the shortest one are too delayed to trust.
+const MAX_RTT_RATIO: f64 = 2.;
+/// Round trips under this are all as good, so a loopback connection does not throw every sample away.
+const MIN_RTT: f64 = 0.001;
+/// The clocks can't drift by more than this (seconds per second) : bigger estimates come from bad samples.
+const MAX_DRIFT: f64 = 0.01;
+
+/// A ping answered by the server, with what it tells about its clock.
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    /// our time halfway through the round trip, when the server most likely answered
+    local: f64,
+    /// server time minus our time at that moment
+    offset: f64,
+    rtt: f64,
+}
+
+/// Estimates the clock of the server from the pings, like ntp : the server puts its time in its pongs,
+/// and we assume it answered halfway through the round trip. The drift is the slope of the offsets over time.
+pub(crate) struct ClockSync {
+    samples: VecDeque<ClockSample>,
+    offset: f64,
+    drift: f64,
+    /// our time the offset was measured at
+    reference: f64,
+    synced: bool,
+}
+
+impl ClockSync {
+    pub(crate) fn new() -> ClockSync {
+        ClockSync {
+            samples: VecDeque::new(),
+            offset: 0.,
+            drift: 0.,
+            reference: 0.,
+            synced: false,
+        }
+    }
+
+    /// The server answered a ping we sent at the given time with its time, and we got it at the received time.
+    pub(crate) fn sample(&mut self, sent: f64, server_time: f64, received: f64) {
+        let local = (sent + received) / 2.;
+        self.samples.push_back(ClockSample { local, offset: server_time - local, rtt: received - sent });
+        if self.samples.len() > CLOCK_WINDOW {
+            self.samples.pop_front();
+        }
+        // the samples with the shortest round trips are the ones that waited the least on the way
+        let min_rtt = self.samples.iter().map(|sample| sample.rtt).fold(f64::INFINITY, f64::min).max(MIN_RTT);
+        let good: Vec<&ClockSample> = self.samples.iter().filter(|sample| sample.rtt <= min_rtt * MAX_RTT_RATIO).collect();
+        let count = good.len() as f64;
+        let mean_local = good.iter().map(|sample| sample.local).sum::<f64>() / count;
+        let mean_offset = good.iter().map(|sample| sample.offset).sum::<f64>() / count;
+        let variance: f64 = good.iter().map(|sample| (sample.local - mean_local).powi(2)).sum();
+        let covariance: f64 = good.iter().map(|sample| (sample.local - mean_local) * (sample.offset - mean_offset)).sum();
+        self.drift = match good.len() >= 3 && variance > f64::EPSILON {
+            true => (covariance / variance).clamp(-MAX_DRIFT, MAX_DRIFT),
+            false => 0.,
+        };
+        self.offset = mean_offset;
+        self.reference = mean_local;
+        self.synced = true;
+    }
+
+    /// The server time at the given time of ours, if we heard from it.
+    pub(crate) fn server_time(&self, local: f64) -> Option<f64> {
+        self.synced.then(|| local + self.offset + self.drift * (local - self.reference))
+    }
+
+    pub(crate) fn offset(&self) -> f64 {
+        self.offset
+    }
+
+    pub(crate) fn drift(&self) -> f64 {
+        self.drift
+    }
+}
+
+/// Client singleton with the time and tick of the server, as estimated from the pings. Updated by the client on each update.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkTime {
+    server_time: Option<f64>,
+    tick_rate: f64,
+    offset: f64,
+    drift: f64,
+    message_tick: Option<u32>,
+}
+
+impl NetworkTime {
+    pub(crate) fn new() -> NetworkTime {
+        NetworkTime {
+            server_time: None,
+            tick_rate: DEFAULT_TICK_RATE,
+            offset: 0.,
+            drift: 0.,
+            message_tick: None,
+        }
+    }
+
+    /// Move to the new estimate. The server time does not go backward, unless the connection changed.
+    pub(crate) fn update(&mut self, clock: &ClockSync, local: f64, tick_rate: f64) {
+        self.server_time = match (self.server_time, clock.server_time(local)) {
+            (Some(previous), Some(estimate)) => Some(estimate.max(previous)),
+            (_, estimate) => estimate,
+        };
+        self.tick_rate = tick_rate;
+        self.offset = clock.offset();
+        self.drift = clock.drift();
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = NetworkTime::new();
+    }
+
+    pub(crate) fn set_message_tick(&mut self, tick: Option<u32>) {
+        self.message_tick = tick;
+    }
+
+    /// Estimated time of the server now, in seconds since it started. None until the first pong of the server.
+    pub fn server_time(&self) -> Option<f64> {
+        self.server_time
+    }
+
+    /// Estimated tick of the server now.
+    pub fn server_tick(&self) -> Option<u32> {
+        self.server_time.map(|time| (time * self.tick_rate) as u32)
+    }
+
+    /// Number of server ticks per second.
+    pub fn tick_rate(&self) -> f64 {
+        self.tick_rate
+    }
+
+    /// Server time minus our time, in seconds.
+    pub fn offset(&self) -> f64 {
+        self.offset
+    }
+
+    /// How much faster the server clock goes than ours, in seconds per second.
+    pub fn drift(&self) -> f64 {
+        self.drift
+    }
+
+    /// Tick of the server when it sent the message being handled, while in `ClientHandler::handle_message`.
+    pub fn message_tick(&self) -> Option<u32> {
+        self.message_tick
+    }
+
+    /// Time of the server when it sent the message being handled, in seconds, from `message_tick`.
+    pub fn message_time(&self) -> Option<f64> {
+        self.message_tick.map(|tick| tick as f64 / self.tick_rate)
+    }
+}
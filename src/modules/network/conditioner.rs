@@ -0,0 +1,644 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use super::transport::{TransportStream, ServerTransport, ClientTransport};
+
+/// Extra delay given to a datagram picked for reordering, so the next ones overtake it.
+const REORDER_DELAY: f64 = 0.05;
+/// When the bandwidth cap makes data wait more than this (in seconds), datagrams are dropped like a full router queue would.
+const MAX_QUEUE_DELAY: f64 = 1.;
+
+/// How traffic is degraded in one direction.
+/// Times are in seconds, probabilities between 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NetworkConditions {
+    /// one way delay added to everything
+    pub latency: f64,
+    /// the delay varies randomly by up to this amount, in both ways
+    pub jitter: f64,
+    /// chance for a datagram to be lost
+    pub loss: f64,
+    /// chance for a datagram to arrive twice
+    pub duplication: f64,
+    /// chance for a datagram to be held back so that the following ones arrive before it
+    pub reordering: f64,
+    /// maximum bytes per second. None for unlimited.
+    pub bandwidth: Option<u64>,
+}
+
+impl NetworkConditions {
+    /// Perfect conditions : nothing is changed.
+    pub fn new() -> NetworkConditions {
+        NetworkConditions::default()
+    }
+
+    pub fn with_latency(mut self, latency: f64) -> NetworkConditions {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> NetworkConditions {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_loss(mut self, loss: f64) -> NetworkConditions {
+        self.loss = loss;
+        self
+    }
+
+    pub fn with_duplication(mut self, duplication: f64) -> NetworkConditions {
+        self.duplication = duplication;
+        self
+    }
+
+    pub fn with_reordering(mut self, reordering: f64) -> NetworkConditions {
+        self.reordering = reordering;
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bytes_per_second: u64) -> NetworkConditions {
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+}
+
+/// What the conditioner did to the traffic so far.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConditionerStats {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// Small random generator (splitmix64). We need the same numbers for the same seed on every platform.
+struct Random(u64);
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in [0, 1[
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0. && self.next_f64() < probability
+    }
+}
+
+/// State shared by a conditioned transport and all its streams.
+struct Shared {
+    time: f64,
+    random: Random,
+    stats: ConditionerStats,
+}
+
+/// Schedules the traffic in one direction.
+struct Direction {
+    conditions: NetworkConditions,
+    /// time at which the link is done sending what it was given, for the bandwidth cap
+    link_free: f64,
+    /// last release time of the stream, as it must stay ordered
+    last_release: f64,
+}
+
+impl Direction {
+    fn new(conditions: NetworkConditions) -> Direction {
+        Direction {
+            conditions,
+            link_free: 0.,
+            last_release: 0.,
+        }
+    }
+
+    /// Time at which the data leaves the link, with the bandwidth cap. None if it waits too long and is dropped.
+    fn departure(&mut self, size: usize, now: f64) -> Option<f64> {
+        match self.conditions.bandwidth {
+            Some(bandwidth) => {
+                let departure = self.link_free.max(now);
+                if departure - now > MAX_QUEUE_DELAY {
+                    return None;
+                }
+                self.link_free = departure + size as f64 / bandwidth.max(1) as f64;
+                Some(departure)
+            },
+            None => Some(now),
+        }
+    }
+
+    fn delay(&self, shared: &mut Shared) -> f64 {
+        let jitter = self.conditions.jitter * (shared.random.next_f64() * 2. - 1.);
+        (self.conditions.latency + jitter).max(0.)
+    }
+
+    /// Release times of a datagram : none if it is lost, two if it is duplicated.
+    fn schedule_datagram(&mut self, size: usize, shared: &mut Shared) -> Vec<f64> {
+        if shared.random.chance(self.conditions.loss) {
+            shared.stats.dropped += 1;
+            return Vec::new();
+        }
+        let departure = match self.departure(size, shared.time) {
+            Some(departure) => departure,
+            None => {
+                shared.stats.dropped += 1;
+                return Vec::new();
+            },
+        };
+        let mut release = departure + self.delay(shared);
+        if shared.random.chance(self.conditions.reordering) {
+            shared.stats.reordered += 1;
+            release += REORDER_DELAY * (1. + shared.random.next_f64());
+        }
+        let mut result = vec![release];
+        if shared.random.chance(self.conditions.duplication) {
+            shared.stats.duplicated += 1;
+            result.push(departure + self.delay(shared));
+        }
+        result
+    }
+
+    /// Release time of bytes written on a stream. Streams are never lost nor reordered.
+    fn schedule_stream(&mut self, size: usize, shared: &mut Shared) -> f64 {
+        // streams are never dropped : ignore the queue limit
+        let departure = self.link_free.max(shared.time);
+        if let Some(bandwidth) = self.conditions.bandwidth {
+            self.link_free = departure + size as f64 / bandwidth.max(1) as f64;
+        }
+        let release = (departure + self.delay(shared)).max(self.last_release);
+        self.last_release = release;
+        release
+    }
+}
+
+/// Datagrams waiting for their release time.
+struct DelayedDatagrams {
+    /// (release time, order of arrival, datagram, address)
+    datagrams: Vec<(f64, u64, Vec<u8>, SocketAddr)>,
+    next_order: u64,
+}
+
+impl DelayedDatagrams {
+    fn new() -> DelayedDatagrams {
+        DelayedDatagrams {
+            datagrams: Vec::new(),
+            next_order: 0,
+        }
+    }
+
+    fn push(&mut self, release: f64, datagram: Vec<u8>, addr: SocketAddr) {
+        self.datagrams.push((release, self.next_order, datagram, addr));
+        self.next_order += 1;
+    }
+
+    /// Pop the datagram with the earliest release time, if it is due.
+    fn pop_ready(&mut self, now: f64) -> Option<(Vec<u8>, SocketAddr)> {
+        let (index, _) = self.datagrams.iter().enumerate()
+            .filter(|(_, (release, ..))| *release <= now)
+            .min_by(|(_, a), (_, b)| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))?;
+        let (_, _, datagram, addr) = self.datagrams.swap_remove(index);
+        Some((datagram, addr))
+    }
+
+    fn clear(&mut self) {
+        self.datagrams.clear();
+    }
+}
+
+/// Copy a released datagram in the read buffer, truncating it like udp does.
+fn copy_datagram(datagram: &[u8], buffer: &mut [u8]) -> usize {
+    let amount = buffer.len().min(datagram.len());
+    buffer[..amount].copy_from_slice(&datagram[..amount]);
+    amount
+}
+
+/// The conditioning part shared by the server and client transports.
+struct Conditioner {
+    shared: Arc<Mutex<Shared>>,
+    outgoing: Direction,
+    incoming: Direction,
+    outgoing_datagrams: DelayedDatagrams,
+    incoming_datagrams: DelayedDatagrams,
+}
+
+impl Conditioner {
+    fn new(seed: u64) -> Conditioner {
+        Conditioner {
+            shared: Arc::new(Mutex::new(Shared {
+                time: 0.,
+                random: Random(seed),
+                stats: ConditionerStats::default(),
+            })),
+            outgoing: Direction::new(NetworkConditions::default()),
+            incoming: Direction::new(NetworkConditions::default()),
+            outgoing_datagrams: DelayedDatagrams::new(),
+            incoming_datagrams: DelayedDatagrams::new(),
+        }
+    }
+
+    fn shared(&self) -> MutexGuard<'_, Shared> {
+        lock(&self.shared)
+    }
+
+    fn stats(&self) -> ConditionerStats {
+        self.shared().stats
+    }
+
+    fn stream<S: TransportStream>(&self, inner: S) -> ConditionedStream<S> {
+        ConditionedStream {
+            inner,
+            shared: self.shared.clone(),
+            outgoing: Direction::new(self.outgoing.conditions),
+            incoming: Direction::new(self.incoming.conditions),
+            outgoing_bytes: VecDeque::new(),
+            incoming_bytes: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    /// Advance the clock, and returns the outgoing datagrams that are due.
+    fn update(&mut self, time: f64) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.shared().time = time;
+        let mut result = Vec::new();
+        while let Some(datagram) = self.outgoing_datagrams.pop_ready(time) {
+            result.push(datagram);
+        }
+        result
+    }
+
+    /// Schedule an outgoing datagram, and returns the datagrams that are due now.
+    fn send(&mut self, datagram: &[u8], addr: SocketAddr) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut shared = lock(&self.shared);
+        for release in self.outgoing.schedule_datagram(datagram.len(), &mut shared) {
+            self.outgoing_datagrams.push(release, datagram.to_vec(), addr);
+        }
+        let mut result = Vec::new();
+        while let Some(datagram) = self.outgoing_datagrams.pop_ready(shared.time) {
+            result.push(datagram);
+        }
+        result
+    }
+
+    /// Schedule a datagram received from the inner transport.
+    fn receive(&mut self, datagram: &[u8], from: SocketAddr) {
+        let mut shared = lock(&self.shared);
+        for release in self.incoming.schedule_datagram(datagram.len(), &mut shared) {
+            self.incoming_datagrams.push(release, datagram.to_vec(), from);
+        }
+    }
+
+    fn pop_incoming(&mut self, buffer: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let time = self.shared().time;
+        self.incoming_datagrams.pop_ready(time).map(|(datagram, from)| (copy_datagram(&datagram, buffer), from))
+    }
+}
+
+fn lock(shared: &Arc<Mutex<Shared>>) -> MutexGuard<'_, Shared> {
+    // the state is always left consistent, so a panic somewhere else does not matter
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Size of the buffer used to pull datagrams out of the inner transport.
+const PULL_BUFFER_SIZE: usize = 1 << 16;
+
+/// Stream that delays the bytes written and read on the inner stream.
+pub struct ConditionedStream<S: TransportStream> {
+    inner: S,
+    shared: Arc<Mutex<Shared>>,
+    outgoing: Direction,
+    incoming: Direction,
+    outgoing_bytes: VecDeque<(f64, Vec<u8>)>,
+    incoming_bytes: VecDeque<(f64, Vec<u8>)>,
+    /// the inner stream was closed by the peer
+    closed: bool,
+}
+
+impl<S: TransportStream> ConditionedStream<S> {
+    /// Write the bytes that are due on the inner stream.
+    fn flush_due(&mut self) -> io::Result<()> {
+        let time = lock(&self.shared).time;
+        while let Some((release, bytes)) = self.outgoing_bytes.front_mut() {
+            if *release > time {
+                break;
+            }
+            match self.inner.write(bytes) {
+                // the inner stream can't take anything anymore, retrying would spin forever
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(amount) if amount == bytes.len() => { self.outgoing_bytes.pop_front(); },
+                Ok(amount) => { bytes.drain(..amount); },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: TransportStream> Read for ConditionedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // reads happen every tick, use them to push the delayed writes
+        self.flush_due()?;
+        // pull everything the inner stream has, and delay it
+        let mut chunk = [0u8; 4096];
+        while !self.closed {
+            match self.inner.read(&mut chunk) {
+                Ok(0) => self.closed = true,
+                Ok(amount) => {
+                    let release = self.incoming.schedule_stream(amount, &mut lock(&self.shared));
+                    self.incoming_bytes.push_back((release, chunk[..amount].to_vec()));
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let time = lock(&self.shared).time;
+        match self.incoming_bytes.front_mut() {
+            Some((release, bytes)) if *release <= time => {
+                let amount = buf.len().min(bytes.len());
+                buf[..amount].copy_from_slice(&bytes[..amount]);
+                bytes.drain(..amount);
+                if bytes.is_empty() {
+                    self.incoming_bytes.pop_front();
+                }
+                Ok(amount)
+            },
+            None if self.closed => Ok(0),
+            _ => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S: TransportStream> Write for ConditionedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let release = self.outgoing.schedule_stream(buf.len(), &mut lock(&self.shared));
+        self.outgoing_bytes.push_back((release, buf.to_vec()));
+        self.flush_due()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_due()?;
+        self.inner.flush()
+    }
+}
+
+impl<S: TransportStream> TransportStream for ConditionedStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+/// Server transport that degrades the traffic of another one : latency, jitter, loss, duplication, reordering and bandwidth.
+/// The random choices only depend on the seed and on what is sent, so runs can be reproduced.
+pub struct ConditionedServerTransport<T: ServerTransport> {
+    inner: T,
+    conditioner: Conditioner,
+    pull_buffer: Vec<u8>,
+}
+
+impl<T: ServerTransport> ConditionedServerTransport<T> {
+    pub fn new(inner: T, seed: u64) -> ConditionedServerTransport<T> {
+        ConditionedServerTransport {
+            inner,
+            conditioner: Conditioner::new(seed),
+            pull_buffer: vec![0u8; PULL_BUFFER_SIZE],
+        }
+    }
+
+    /// Conditions of the traffic sent to the clients.
+    pub fn with_outgoing(mut self, conditions: NetworkConditions) -> ConditionedServerTransport<T> {
+        self.conditioner.outgoing = Direction::new(conditions);
+        self
+    }
+
+    /// Conditions of the traffic received from the clients.
+    pub fn with_incoming(mut self, conditions: NetworkConditions) -> ConditionedServerTransport<T> {
+        self.conditioner.incoming = Direction::new(conditions);
+        self
+    }
+
+    /// Same conditions in both directions.
+    pub fn with_conditions(self, conditions: NetworkConditions) -> ConditionedServerTransport<T> {
+        self.with_outgoing(conditions).with_incoming(conditions)
+    }
+
+    pub fn stats(&self) -> ConditionerStats {
+        self.conditioner.stats()
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ServerTransport> ServerTransport for ConditionedServerTransport<T> {
+    type Stream = ConditionedStream<T::Stream>;
+
+    fn update(&mut self, time: f64) {
+        self.inner.update(time);
+        for (datagram, addr) in self.conditioner.update(time) {
+            if let Err(e) = self.inner.send_to(&datagram, addr) {
+                println!("[NETWORK CONDITIONER] -> Error while sending delayed datagram to {addr} : {e}.");
+            }
+        }
+    }
+
+    fn accept(&mut self) -> io::Result<Option<(Self::Stream, SocketAddr)>> {
+        Ok(self.inner.accept()?.map(|(stream, addr)| (self.conditioner.stream(stream), addr)))
+    }
+
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        for (datagram, addr) in self.conditioner.send(datagram, addr) {
+            self.inner.send_to(&datagram, addr)?;
+        }
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        while let Some((size, from)) = self.inner.recv_from(&mut self.pull_buffer)? {
+            self.conditioner.receive(&self.pull_buffer[..size], from);
+        }
+        Ok(self.conditioner.pop_incoming(buffer))
+    }
+}
+
+/// Client transport that degrades the traffic of another one : latency, jitter, loss, duplication, reordering and bandwidth.
+/// The random choices only depend on the seed and on what is sent, so runs can be reproduced.
+pub struct ConditionedClientTransport<T: ClientTransport> {
+    inner: T,
+    conditioner: Conditioner,
+    server: Option<SocketAddr>,
+    pull_buffer: Vec<u8>,
+}
+
+impl<T: ClientTransport> ConditionedClientTransport<T> {
+    pub fn new(inner: T, seed: u64) -> ConditionedClientTransport<T> {
+        ConditionedClientTransport {
+            inner,
+            conditioner: Conditioner::new(seed),
+            server: None,
+            pull_buffer: vec![0u8; PULL_BUFFER_SIZE],
+        }
+    }
+
+    /// Conditions of the traffic sent to the server.
+    pub fn with_outgoing(mut self, conditions: NetworkConditions) -> ConditionedClientTransport<T> {
+        self.conditioner.outgoing = Direction::new(conditions);
+        self
+    }
+
+    /// Conditions of the traffic received from the server.
+    pub fn with_incoming(mut self, conditions: NetworkConditions) -> ConditionedClientTransport<T> {
+        self.conditioner.incoming = Direction::new(conditions);
+        self
+    }
+
+    /// Same conditions in both directions.
+    pub fn with_conditions(self, conditions: NetworkConditions) -> ConditionedClientTransport<T> {
+        self.with_outgoing(conditions).with_incoming(conditions)
+    }
+
+    pub fn stats(&self) -> ConditionerStats {
+        self.conditioner.stats()
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ClientTransport> ClientTransport for ConditionedClientTransport<T> {
+    type Stream = ConditionedStream<T::Stream>;
+
+    fn update(&mut self, time: f64) {
+        self.inner.update(time);
+        for (datagram, _) in self.conditioner.update(time) {
+            if let Err(e) = self.inner.send(&datagram) {
+                println!("[NETWORK CONDITIONER] -> Error while sending delayed datagram : {e}.");
+            }
+        }
+    }
+
+    fn connect(&mut self, addrs: &[SocketAddr], timeout: f64) {
+        self.inner.connect(addrs, timeout);
+    }
+
+    fn poll_connect(&mut self) -> Option<io::Result<Self::Stream>> {
+        let result = self.inner.poll_connect()?;
+        Some(result.and_then(|stream| {
+            // the address that worked, to queue the datagrams
+            self.server = Some(stream.peer_addr()?);
+            Ok(self.conditioner.stream(stream))
+        }))
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        // the address is only used to queue the datagram, the inner transport knows where to send it
+        let server = self.server.ok_or(io::ErrorKind::NotConnected)?;
+        for (datagram, _) in self.conditioner.send(datagram, server) {
+            self.inner.send(&datagram)?;
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        while let Some((size, from)) = self.inner.recv(&mut self.pull_buffer)? {
+            self.conditioner.receive(&self.pull_buffer[..size], from);
+        }
+        Ok(self.conditioner.pop_incoming(buffer))
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+        self.server = None;
+        self.conditioner.outgoing_datagrams.clear();
+        self.conditioner.incoming_datagrams.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 4000))
+    }
+
+    /// Send numbered datagrams through a conditioner over bad conditions, and returns what came out with the time it came out.
+    fn run(seed: u64) -> (Vec<(u32, f64)>, u64, u64, u64) {
+        let mut conditioner = Conditioner::new(seed);
+        conditioner.outgoing = Direction::new(NetworkConditions::new()
+            .with_latency(0.05)
+            .with_jitter(0.02)
+            .with_loss(0.2)
+            .with_duplication(0.1)
+            .with_reordering(0.1)
+            .with_bandwidth(20_000));
+        let mut result = Vec::new();
+        for index in 0..500u32 {
+            let time = index as f64 * 0.01;
+            let mut released = conditioner.update(time);
+            released.extend(conditioner.send(&index.to_le_bytes(), addr()));
+            result.extend(released.into_iter().map(|(datagram, _)| (u32::from_le_bytes(datagram[..4].try_into().unwrap()), time)));
+        }
+        // let everything out
+        result.extend(conditioner.update(100.).into_iter().map(|(datagram, _)| (u32::from_le_bytes(datagram[..4].try_into().unwrap()), 100.)));
+        let stats = conditioner.stats();
+        (result, stats.dropped, stats.duplicated, stats.reordered)
+    }
+
+    #[test]
+    fn same_seed_same_traffic() {
+        let (received, dropped, duplicated, reordered) = run(42);
+        assert_eq!(run(42), (received.clone(), dropped, duplicated, reordered));
+        // the conditions did something
+        assert!(dropped > 0 && duplicated > 0 && reordered > 0);
+        assert!(received.windows(2).any(|pair| pair[1].0 < pair[0].0));
+        // and another seed does something else
+        assert_ne!(run(43).0, received);
+    }
+
+    /// Stream that never takes any byte.
+    struct StuckStream;
+
+    impl Read for StuckStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for StuckStream {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TransportStream for StuckStream {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(addr())
+        }
+    }
+
+    #[test]
+    fn write_zero_is_an_error() {
+        let mut stream = Conditioner::new(0).stream(StuckStream);
+        let error = stream.write(&[1, 2, 3]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+    }
+}
@@ -1,8 +1,43 @@
-use crate::{NetworkSerializable, NetworkUnserializeError};
+use crate::{NetworkSerializable, NetworkUnserializeError, ByteWriter, ByteReader, guarded_schema_hash};
 
 
+use super::{protocol::{ProtocolVersion, RejectReason}, rpc::RpcError, replication::EntityState};
+
+// ids are explicit : these messages are exchanged before checking the protocol, so they must never change.
 #[derive(NetworkSerializable)]
 pub enum DefaultNetworkMessages {
-    Welcome(u64), // Server -> client / client id
+    #[network(id = 0)]
+    Welcome(u64, ProtocolVersion, u64), // Server -> client / client id, server protocol, session token to put in udp datagrams
+    #[network(id = 1)]
     Disconnecting, // client -> server
+    #[network(id = 2)]
+    Hello(ProtocolVersion), // client -> server / first message of the client
+    #[network(id = 3)]
+    Rejected(RejectReason), // server -> client / the connection is refused and will be closed
+    #[network(id = 4)]
+    Ping(u32), // both ways / keeps the connection alive, must be answered with a pong with the same id
+    #[network(id = 5)]
+    Pong(u32, f64), // both ways / answer to a ping, with the time of the peer when answering
+    #[network(id = 6)]
+    Login(Vec<u8>), // client -> server / sent right after the hello, given to the server handler to accept the client or not
+    #[network(id = 7)]
+    Kicked(String), // server -> client / the client is removed by the server for the given reason, the connection will be closed
+    #[network(id = 8)]
+    PublicKey([u8; 32]), // both ways / key agreement of encrypted connections, sent instead of the login by the client, then everything is encrypted
+    #[network(id = 9)]
+    RpcRequest(u32, u32, Vec<u8>), // both ways / call id, method, request. Answered with a response or a failure with the same call id
+    #[network(id = 10)]
+    RpcResponse(u32, Vec<u8>), // both ways / call id, response
+    #[network(id = 11)]
+    RpcFailed(u32, RpcError), // both ways / call id, why the call could not be answered
+    #[network(id = 12)]
+    Snapshot(u32, Option<u32>, Vec<EntityState>, Vec<u64>), // server -> client / snapshot id, baseline it is relative to, changed entities, despawned entities
+    #[network(id = 13)]
+    SnapshotAck(u32), // client -> server / the snapshot was received, and can be used as baseline
+    #[network(id = 14)]
+    PredictionInputs(u32, Vec<Vec<u8>>), // client -> server / tick of the first input, inputs of the following ticks
+    #[network(id = 15)]
+    PredictionState(u32, Vec<u8>), // server -> client / tick of the last input applied, authoritative state after it
+    #[network(id = 16)]
+    TickRate(f64), // server -> client / sent after the welcome, number of server ticks per second
 }
@@ -0,0 +1,287 @@
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::{Aead, Payload}};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::packet::Packet;
+
+/// Size of the authentication tag added to every encrypted body.
+const TAG_SIZE: usize = 16;
+/// Size of the explicit nonce counter put in front of encrypted udp payloads.
+const UDP_NONCE_SIZE: usize = 8;
+/// How far behind the most recent udp nonce we still accept datagrams. Older ones are dropped as replays.
+const REPLAY_WINDOW: u64 = 128;
+/// Bytes added to the payload of an encrypted datagram : the nonce and the tag.
+pub(crate) const UDP_OVERHEAD: usize = UDP_NONCE_SIZE + TAG_SIZE;
+
+/// Half of the x25519 key agreement done during the handshake.
+/// Each side sends its public key in the clear, and both derive the same session keys from them.
+/// This protects against eavesdropping and tampering, but nothing proves who the server is : a man in the middle
+/// that can change the traffic while it is connecting can still pretend to be it.
+pub(crate) struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    pub(crate) fn new() -> KeyExchange {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        KeyExchange {
+            secret,
+            public,
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// Derive the session keys from the peer public key. None if the peer key is a weak one.
+    pub(crate) fn finish(self, peer: [u8; 32], is_client: bool) -> Option<SessionKeys> {
+        let (client, server) = match is_client {
+            true => (self.public.to_bytes(), peer),
+            false => (peer, self.public.to_bytes()),
+        };
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return None;
+        }
+        let mut salt = [0; 64];
+        salt[..32].copy_from_slice(&client);
+        salt[32..].copy_from_slice(&server);
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let key = |info: &[u8]| {
+            let mut key = [0; 32];
+            // 32 bytes is always a valid length for sha256
+            hkdf.expand(info, &mut key).expect("invalid hkdf length");
+            ChaCha20Poly1305::new(&key.into())
+        };
+        let (tcp_send, tcp_receive, udp_send, udp_receive) = match is_client {
+            true => (b"gear tcp client", b"gear tcp server", b"gear udp client", b"gear udp server"),
+            false => (b"gear tcp server", b"gear tcp client", b"gear udp server", b"gear udp client"),
+        };
+        Some(SessionKeys {
+            tcp: TcpCipher {
+                send: key(tcp_send),
+                receive: key(tcp_receive),
+                sent: 0,
+                received: 0,
+            },
+            udp: UdpCipher {
+                send: key(udp_send),
+                receive: key(udp_receive),
+                next_nonce: 0,
+                replay: ReplayWindow::new(),
+            },
+        })
+    }
+}
+
+/// Keys of an encrypted connection.
+pub(crate) struct SessionKeys {
+    pub(crate) tcp: TcpCipher,
+    pub(crate) udp: UdpCipher,
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Encrypts the packet bodies sent over tcp.
+/// The stream is ordered and reliable, so both sides count the packets to get the nonces, nothing more is sent.
+pub(crate) struct TcpCipher {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    sent: u64,
+    received: u64,
+}
+
+impl TcpCipher {
+    pub(crate) fn seal(&mut self, packet: &Packet) -> Packet {
+        let aad = packet.authenticated_data();
+        // only fails if the body is bigger than what chacha can encrypt, way over any packet size limit
+        let body = self.send.encrypt(&nonce(self.sent).into(), Payload { msg: &packet.body, aad: &aad }).expect("packet too large to encrypt");
+        self.sent += 1;
+        packet.with_body(body)
+    }
+
+    /// Decrypt a received packet. None if it was not encrypted with our key, or was changed on the way.
+    pub(crate) fn open(&mut self, packet: &Packet) -> Option<Packet> {
+        let aad = packet.authenticated_data();
+        let body = self.receive.decrypt(&nonce(self.received).into(), Payload { msg: &packet.body, aad: &aad }).ok()?;
+        self.received += 1;
+        Some(packet.with_body(body))
+    }
+}
+
+/// Encrypts the payload of the udp datagrams.
+/// Datagrams can be lost or reordered, so each one carries its nonce, and a window remembers the received ones to drop replays.
+/// The channel header is authenticated with the payload.
+pub(crate) struct UdpCipher {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    next_nonce: u64,
+    replay: ReplayWindow,
+}
+
+impl UdpCipher {
+    pub(crate) fn seal(&mut self, header: &[u8], payload: &[u8]) -> Vec<u8> {
+        let counter = self.next_nonce;
+        self.next_nonce += 1;
+        let mut result = Vec::with_capacity(UDP_OVERHEAD + payload.len());
+        result.extend_from_slice(&counter.to_le_bytes());
+        // only fails if the payload is bigger than what chacha can encrypt, way over any datagram size
+        result.append(&mut self.send.encrypt(&nonce(counter).into(), Payload { msg: payload, aad: header }).expect("payload too large to encrypt"));
+        result
+    }
+
+    /// Decrypt a received payload. None if it was not encrypted with our key, was changed on the way, or was already received.
+    pub(crate) fn open(&mut self, header: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < UDP_OVERHEAD {
+            return None;
+        }
+        let (counter, encrypted) = sealed.split_at(UDP_NONCE_SIZE);
+        let counter = u64::from_le_bytes(counter.try_into().ok()?);
+        // check the window before decrypting, but only move it once we know the datagram is genuine
+        if !self.replay.is_new(counter) {
+            return None;
+        }
+        let payload = self.receive.decrypt(&nonce(counter).into(), Payload { msg: encrypted, aad: header }).ok()?;
+        self.replay.insert(counter);
+        Some(payload)
+    }
+}
+
+/// Remembers the recently received udp nonces.
+struct ReplayWindow {
+    /// most recent nonce received, if any
+    latest: Option<u64>,
+    /// bit n is set if the nonce `latest - n` was received
+    received: u128,
+}
+
+impl ReplayWindow {
+    fn new() -> ReplayWindow {
+        ReplayWindow {
+            latest: None,
+            received: 0,
+        }
+    }
+
+    fn is_new(&self, counter: u64) -> bool {
+        match self.latest {
+            None => true,
+            Some(latest) if counter > latest => true,
+            Some(latest) if latest - counter >= REPLAY_WINDOW => false, // too old to know
+            Some(latest) => self.received & (1 << (latest - counter)) == 0,
+        }
+    }
+
+    fn insert(&mut self, counter: u64) {
+        match self.latest {
+            Some(latest) if counter <= latest => self.received |= 1 << (latest - counter),
+            Some(latest) => {
+                let shift = counter - latest;
+                self.received = match shift < REPLAY_WINDOW {
+                    true => (self.received << shift) | 1,
+                    false => 1,
+                };
+                self.latest = Some(counter);
+            },
+            None => {
+                self.received = 1;
+                self.latest = Some(counter);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Udp ciphers of both ends of a connection : (client, server).
+    fn udp_pair() -> (UdpCipher, UdpCipher) {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_key = client.public_key();
+        let client = client.finish(server.public_key(), true).unwrap();
+        let server = server.finish(client_key, false).unwrap();
+        (client.udp, server.udp)
+    }
+
+    /// Seal `count` datagrams, the payload of each one being its index.
+    fn seal_all(cipher: &mut UdpCipher, count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|index| cipher.seal(b"header", &[index])).collect()
+    }
+
+    #[test]
+    fn datagrams_open_on_the_other_side() {
+        let (mut client, mut server) = udp_pair();
+        let sealed = client.seal(b"header", b"payload");
+        assert_eq!(sealed.len(), b"payload".len() + UDP_OVERHEAD);
+        assert_eq!(server.open(b"header", &sealed), Some(b"payload".to_vec()));
+        // each direction has its own key
+        let back = server.seal(b"header", b"back");
+        assert_eq!(client.open(b"header", &back), Some(b"back".to_vec()));
+        let own = server.seal(b"header", b"own");
+        assert_eq!(server.open(b"header", &own), None);
+    }
+
+    #[test]
+    fn out_of_order_datagrams_in_the_window_are_accepted() {
+        let (mut client, mut server) = udp_pair();
+        let sealed = seal_all(&mut client, 100);
+        for index in [5, 99, 0, 50, 98, 6] {
+            assert_eq!(server.open(b"header", &sealed[index]), Some(vec![index as u8]));
+        }
+    }
+
+    #[test]
+    fn replayed_datagrams_are_rejected() {
+        let (mut client, mut server) = udp_pair();
+        let sealed = seal_all(&mut client, 3);
+        assert!(server.open(b"header", &sealed[1]).is_some());
+        assert!(server.open(b"header", &sealed[1]).is_none());
+        assert!(server.open(b"header", &sealed[2]).is_some());
+        assert!(server.open(b"header", &sealed[1]).is_none());
+        assert!(server.open(b"header", &sealed[2]).is_none());
+        // the missing one is still welcome, once
+        assert!(server.open(b"header", &sealed[0]).is_some());
+        assert!(server.open(b"header", &sealed[0]).is_none());
+    }
+
+    #[test]
+    fn datagrams_older_than_the_window_are_rejected() {
+        let (mut client, mut server) = udp_pair();
+        let sealed = seal_all(&mut client, REPLAY_WINDOW as u8 + 2);
+        assert!(server.open(b"header", sealed.last().unwrap()).is_some());
+        // REPLAY_WINDOW + 1 behind the latest, then REPLAY_WINDOW behind
+        assert!(server.open(b"header", &sealed[0]).is_none());
+        assert!(server.open(b"header", &sealed[1]).is_none());
+        // the most behind we still know about
+        assert!(server.open(b"header", &sealed[2]).is_some());
+    }
+
+    #[test]
+    fn tampered_datagrams_are_rejected() {
+        let (mut client, mut server) = udp_pair();
+        let sealed = client.seal(b"header", b"payload");
+        let mut body = sealed.clone();
+        *body.last_mut().unwrap() ^= 1;
+        assert!(server.open(b"header", &body).is_none());
+        // the nonce is authenticated as well
+        let mut counter = sealed.clone();
+        counter[0] ^= 1;
+        assert!(server.open(b"header", &counter).is_none());
+        // and the header sent in the clear
+        assert!(server.open(b"heaDer", &sealed).is_none());
+        assert!(server.open(b"header", &sealed[..UDP_OVERHEAD - 1]).is_none());
+        // none of them moved the window : the genuine datagram still goes through
+        assert_eq!(server.open(b"header", &sealed), Some(b"payload".to_vec()));
+    }
+}
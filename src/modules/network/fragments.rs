@@ -0,0 +1,302 @@
+use std::{borrow::Cow, collections::HashMap, net::SocketAddr};
+
+use crate::{NetworkSerializable, NetworkUnserializeError, ByteWriter, ByteReader, guarded_schema_hash};
+
+/// Maximum size of the datagrams we send. Small enough to not be split by the ip layer on most networks.
+/// Bigger messages are sent in several fragments, and rebuilt on the other side.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+/// Default time in seconds we wait for all the fragments of a message before dropping it.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: f64 = 1.;
+/// Default maximum amount of bytes kept in incomplete messages. Oldest messages are dropped to stay under it.
+pub const DEFAULT_REASSEMBLY_MEMORY: usize = 4 << 20;
+
+/// First byte of a datagram carrying a whole message.
+const WHOLE_DATAGRAM: u8 = 0;
+/// First byte of a datagram carrying a fragment of a message.
+const FRAGMENT: u8 = 1;
+const FRAGMENT_HEADER_SIZE: usize = 21;
+const FRAGMENT_SIZE: usize = MAX_DATAGRAM_SIZE - FRAGMENT_HEADER_SIZE;
+const MAX_FRAGMENT_COUNT: usize = u8::MAX as usize;
+
+/// Header of a fragment, after the fragment byte.
+#[derive(NetworkSerializable)]
+#[derive(Debug, Clone, Copy)]
+struct FragmentHeader {
+    /// session token and sender of the datagram, to check the fragment before keeping it
+    token: u64,
+    sender: u64,
+    /// id of the message the fragment belongs to
+    group: u16,
+    index: u8,
+    count: u8,
+}
+
+/// Statistics about the fragmented messages we received.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FragmentStats {
+    pub fragments_received: u64,
+    /// messages that were completely rebuilt
+    pub groups_completed: u64,
+    /// messages dropped because some fragments did not arrive in time
+    pub groups_timed_out: u64,
+    /// messages dropped to stay under the memory cap
+    pub groups_evicted: u64,
+    /// fragments that did not make sense (bad header, inconsistent with the rest of the group)
+    pub invalid_fragments: u64,
+}
+
+/// Split a datagram into pieces that fit in the max datagram size.
+/// Returns nothing if the datagram is too big to be sent over udp, even fragmented.
+/// If a single fragment is lost the whole message is lost (reliable channels resend all of it), so very big messages are better sent over tcp.
+/// Fragments carry the session token and the sender of the datagram, so the peer can check them before keeping the fragments.
+pub(crate) fn split(datagram: &[u8], group: u16, token: u64, sender: u64) -> Vec<Vec<u8>> {
+    if datagram.len() < MAX_DATAGRAM_SIZE {
+        let mut result = Vec::with_capacity(datagram.len() + 1);
+        result.push(WHOLE_DATAGRAM);
+        result.write_bytes(datagram);
+        return vec![result];
+    }
+    let count = datagram.len().div_ceil(FRAGMENT_SIZE);
+    if count > MAX_FRAGMENT_COUNT {
+        println!("[NETWORK] -> udp message of {} bytes is too big to be sent (max {} bytes), use tcp instead.", datagram.len(), MAX_FRAGMENT_COUNT * FRAGMENT_SIZE);
+        return Vec::new();
+    }
+    datagram.chunks(FRAGMENT_SIZE).enumerate().map(|(index, chunk)| {
+        let mut result = Vec::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+        result.push(FRAGMENT);
+        // both fit in a u8, we checked the count
+        FragmentHeader { token, sender, group, index: index as u8, count: count as u8 }.serialize_into(&mut result);
+        result.write_bytes(chunk);
+        result
+    }).collect()
+}
+
+struct FragmentGroup {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    /// bytes held by the group
+    size: usize,
+    started: f64,
+}
+
+/// Rebuilds messages out of their fragments, for all the peers of a socket.
+pub(crate) struct Reassembler {
+    groups: HashMap<(SocketAddr, u16), FragmentGroup>,
+    /// bytes held by all the groups
+    buffered: usize,
+    timeout: f64,
+    max_memory: usize,
+    stats: FragmentStats,
+}
+
+impl Reassembler {
+    pub(crate) fn new(timeout: f64, max_memory: usize) -> Reassembler {
+        Reassembler {
+            groups: HashMap::new(),
+            buffered: 0,
+            timeout,
+            max_memory,
+            stats: FragmentStats::default(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> FragmentStats {
+        self.stats
+    }
+
+    /// Drop the groups that waited too long for their fragments.
+    pub(crate) fn expire(&mut self, time: f64) {
+        let timeout = self.timeout;
+        let mut dropped = 0;
+        let mut freed = 0;
+        self.groups.retain(|_, group| match time - group.started > timeout {
+            true => {
+                dropped += 1;
+                freed += group.size;
+                false
+            },
+            false => true,
+        });
+        self.buffered -= freed;
+        self.stats.groups_timed_out += dropped;
+    }
+
+    /// Process a received datagram. Returns the message it carries, if it is whole or completes a message.
+    /// Fragments are only kept if `accept` agrees with their session token and sender : the others can't fill the memory.
+    pub(crate) fn receive<'a, A>(&mut self, from: SocketAddr, datagram: &'a [u8], time: f64, accept: A) -> Option<Cow<'a, [u8]>>
+        where A: FnOnce(u64, u64) -> bool
+    {
+        let mut reader = ByteReader::new(datagram);
+        match reader.read_array::<1>() {
+            Ok([WHOLE_DATAGRAM]) => return Some(Cow::Borrowed(&datagram[1..])),
+            Ok([FRAGMENT]) => {},
+            _ => {
+                self.stats.invalid_fragments += 1;
+                return None;
+            },
+        }
+        self.stats.fragments_received += 1;
+        let header = match FragmentHeader::deserialize_from(&mut reader) {
+            Ok((header, _)) if header.index < header.count && reader.remaining() <= FRAGMENT_SIZE => header,
+            _ => {
+                self.stats.invalid_fragments += 1;
+                return None;
+            },
+        };
+        if !accept(header.token, header.sender) {
+            return None;
+        }
+        let data = &datagram[datagram.len() - reader.remaining()..];
+
+        // make room for the fragment, dropping the oldest groups
+        while self.buffered + data.len() > self.max_memory {
+            let oldest = match self.groups.iter().min_by(|(_, a), (_, b)| a.started.total_cmp(&b.started)) {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            if let Some(group) = self.groups.remove(&oldest) {
+                self.buffered -= group.size;
+                self.stats.groups_evicted += 1;
+            }
+        }
+
+        let key = (from, header.group);
+        let group = self.groups.entry(key).or_insert_with(|| FragmentGroup {
+            fragments: vec![None; header.count as usize],
+            received: 0,
+            size: 0,
+            started: time,
+        });
+        if group.fragments.len() != header.count as usize {
+            // another message is using this id, or someone is messing with us
+            self.stats.invalid_fragments += 1;
+            return None;
+        }
+        if group.fragments[header.index as usize].is_some() {
+            return None; // duplicate
+        }
+        group.fragments[header.index as usize] = Some(data.to_vec());
+        group.received += 1;
+        group.size += data.len();
+        self.buffered += data.len();
+        if group.received < group.fragments.len() {
+            return None;
+        }
+
+        // all fragments are here
+        let group = self.groups.remove(&key)?;
+        self.buffered -= group.size;
+        self.stats.groups_completed += 1;
+        let mut result = Vec::with_capacity(group.size);
+        for fragment in group.fragments.into_iter().flatten() {
+            result.write_bytes(&fragment);
+        }
+        Some(Cow::Owned(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    const TOKEN: u64 = 0x5eed;
+    const SENDER: u64 = 3;
+
+    fn peer() -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 7777)
+    }
+
+    /// Message of the given size, with bytes that tell where they were.
+    fn message(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn genuine(token: u64, sender: u64) -> bool {
+        token == TOKEN && sender == SENDER
+    }
+
+    #[test]
+    fn whole_datagrams_go_through() {
+        let data = message(100);
+        let datagrams = split(&data, 0, TOKEN, SENDER);
+        assert_eq!(datagrams.len(), 1);
+        let mut reassembler = Reassembler::new(1., DEFAULT_REASSEMBLY_MEMORY);
+        assert_eq!(reassembler.receive(peer(), &datagrams[0], 0., genuine).as_deref(), Some(&data[..]));
+        assert!(reassembler.receive(peer(), &[7, 0, 0], 0., genuine).is_none());
+        assert_eq!(reassembler.stats().invalid_fragments, 1);
+    }
+
+    #[test]
+    fn out_of_order_fragments_are_rebuilt() {
+        let data = message(3000);
+        let fragments = split(&data, 4, TOKEN, SENDER);
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= MAX_DATAGRAM_SIZE));
+        let mut reassembler = Reassembler::new(1., DEFAULT_REASSEMBLY_MEMORY);
+        assert!(reassembler.receive(peer(), &fragments[2], 0., genuine).is_none());
+        assert!(reassembler.receive(peer(), &fragments[0], 0.1, genuine).is_none());
+        // a duplicate changes nothing
+        assert!(reassembler.receive(peer(), &fragments[0], 0.1, genuine).is_none());
+        assert_eq!(reassembler.receive(peer(), &fragments[1], 0.2, genuine).as_deref(), Some(&data[..]));
+        let stats = reassembler.stats();
+        assert_eq!((stats.fragments_received, stats.groups_completed), (4, 1));
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn missing_fragment_times_out() {
+        let data = message(3000);
+        let fragments = split(&data, 4, TOKEN, SENDER);
+        let mut reassembler = Reassembler::new(1., DEFAULT_REASSEMBLY_MEMORY);
+        reassembler.receive(peer(), &fragments[0], 0., genuine);
+        reassembler.receive(peer(), &fragments[2], 0., genuine);
+        reassembler.expire(0.5);
+        assert_eq!(reassembler.stats().groups_timed_out, 0);
+        reassembler.expire(1.5);
+        assert_eq!(reassembler.stats().groups_timed_out, 1);
+        assert_eq!(reassembler.buffered, 0);
+        // the late fragment starts over, and can't complete the message alone
+        assert!(reassembler.receive(peer(), &fragments[1], 1.6, genuine).is_none());
+        assert_eq!(reassembler.stats().groups_completed, 0);
+    }
+
+    #[test]
+    fn memory_cap_drops_the_oldest_messages() {
+        // three fragments : two full ones and a small one
+        let data = message(2 * FRAGMENT_SIZE + 100);
+        let first = split(&data, 1, TOKEN, SENDER);
+        let second = split(&data, 2, TOKEN, SENDER);
+        let mut reassembler = Reassembler::new(10., 3 * FRAGMENT_SIZE);
+        reassembler.receive(peer(), &first[0], 0., genuine);
+        reassembler.receive(peer(), &first[1], 0., genuine);
+        reassembler.receive(peer(), &second[0], 1., genuine);
+        assert_eq!(reassembler.stats().groups_evicted, 0);
+        // no room for this one : the first message goes
+        reassembler.receive(peer(), &second[1], 1., genuine);
+        assert_eq!(reassembler.stats().groups_evicted, 1);
+        assert!(reassembler.buffered <= 3 * FRAGMENT_SIZE);
+        assert_eq!(reassembler.receive(peer(), &second[2], 1., genuine).as_deref(), Some(&data[..]));
+        assert!(reassembler.receive(peer(), &first[2], 1., genuine).is_none());
+        assert_eq!(reassembler.stats().groups_completed, 1);
+    }
+
+    #[test]
+    fn fragments_with_a_wrong_token_are_rejected() {
+        let data = message(3000);
+        let forged = split(&message(5000), 4, TOKEN + 1, SENDER);
+        let mut reassembler = Reassembler::new(1., DEFAULT_REASSEMBLY_MEMORY);
+        for fragment in &forged {
+            assert!(reassembler.receive(peer(), fragment, 0., genuine).is_none());
+        }
+        assert!(reassembler.receive(peer(), &split(&data, 4, TOKEN, SENDER + 1)[0], 0., genuine).is_none());
+        assert_eq!(reassembler.buffered, 0);
+        // they did not take the place of the genuine message with the same group
+        let mut result = None;
+        for fragment in &split(&data, 4, TOKEN, SENDER) {
+            result = reassembler.receive(peer(), fragment, 0., genuine).map(|message| message.into_owned());
+        }
+        assert_eq!(result, Some(data));
+        assert_eq!(reassembler.stats().invalid_fragments, 0);
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+/// Default time in seconds between two pings.
+pub const DEFAULT_HEARTBEAT_INTERVAL: f64 = 0.5;
+/// Default time in seconds without receiving anything before dropping the connection.
+pub const DEFAULT_IDLE_TIMEOUT: f64 = 10.;
+/// Number of pings used to estimate the loss.
+const PING_WINDOW: usize = 32;
+/// A ping that was not answered after this many seconds is counted as lost.
+const PING_LOST_AFTER: f64 = 2.;
+/// Weight of a new sample in the smoothed rtt and jitter.
+const SMOOTHING: f64 = 0.125;
+
+/// Estimations of the quality of a connection, measured with pings.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectionQuality {
+    /// smoothed round trip time, in seconds
+    pub rtt: f64,
+    /// mean deviation of the round trip time, in seconds
+    pub jitter: f64,
+    /// ratio of the recent pings that got no answer, between 0 and 1
+    pub loss: f64,
+}
+
+/// Sends pings to keep the connection alive and measure it, and tells when the peer went silent for too long.
+pub(crate) struct Heartbeat {
+    interval: f64,
+    timeout: f64,
+    last_ping_sent: f64,
+    last_received: f64,
+    /// last time a udp datagram came from the peer, if any
+    last_udp_received: Option<f64>,
+    next_ping: u32,
+    /// pings waiting for their pong, with the time they were sent
+    in_flight: VecDeque<(u32, f64)>,
+    /// outcome of the last pings : true if answered
+    results: VecDeque<bool>,
+    quality: ConnectionQuality,
+    has_rtt: bool,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(interval: f64, timeout: f64, time: f64) -> Heartbeat {
+        Heartbeat {
+            interval,
+            timeout,
+            last_ping_sent: time,
+            last_received: time,
+            last_udp_received: None,
+            next_ping: 0,
+            in_flight: VecDeque::new(),
+            results: VecDeque::new(),
+            quality: ConnectionQuality::default(),
+            has_rtt: false,
+        }
+    }
+
+    pub(crate) fn quality(&self) -> ConnectionQuality {
+        self.quality
+    }
+
+    /// We received something from the peer : it is alive.
+    pub(crate) fn received(&mut self, time: f64) {
+        self.last_received = self.last_received.max(time);
+    }
+
+    /// We received a udp datagram from the peer : udp goes through.
+    pub(crate) fn udp_received(&mut self, time: f64) {
+        self.received(time);
+        self.last_udp_received = Some(time);
+    }
+
+    /// Whether udp from the peer got through lately. When it does not (blocked, or a tcp only peer),
+    /// pings go over tcp so the connection is not dropped while tcp is fine.
+    pub(crate) fn udp_works(&self, time: f64) -> bool {
+        self.last_udp_received.is_some_and(|last| time - last <= 2. * self.interval + PING_LOST_AFTER)
+    }
+
+    pub(crate) fn timed_out(&self, time: f64) -> bool {
+        time - self.last_received > self.timeout
+    }
+
+    /// Returns the id of the ping to send, if it is time to send one.
+    pub(crate) fn ping(&mut self, time: f64) -> Option<u32> {
+        // forget the pings that are too old to be answered
+        while let Some((_, sent)) = self.in_flight.front() {
+            if time - sent <= PING_LOST_AFTER {
+                break;
+            }
+            self.in_flight.pop_front();
+            self.record(false);
+        }
+        if time - self.last_ping_sent < self.interval {
+            return None;
+        }
+        let id = self.next_ping;
+        self.next_ping = self.next_ping.wrapping_add(1);
+        self.last_ping_sent = time;
+        self.in_flight.push_back((id, time));
+        Some(id)
+    }
+
+    /// The peer answered one of our pings. Returns the time the ping was sent, unless it is too old or duplicated.
+    pub(crate) fn pong(&mut self, id: u32, time: f64) -> Option<f64> {
+        let index = self.in_flight.iter().position(|(ping, _)| *ping == id)?;
+        let (_, sent) = self.in_flight.remove(index).unwrap_or((id, time));
+        let rtt = time - sent;
+        match self.has_rtt {
+            true => {
+                self.quality.jitter += SMOOTHING * ((rtt - self.quality.rtt).abs() - self.quality.jitter);
+                self.quality.rtt += SMOOTHING * (rtt - self.quality.rtt);
+            },
+            false => {
+                self.quality.rtt = rtt;
+                self.quality.jitter = rtt / 2.;
+                self.has_rtt = true;
+            },
+        }
+        self.record(true);
+        Some(sent)
+    }
+
+    fn record(&mut self, answered: bool) {
+        self.results.push_back(answered);
+        if self.results.len() > PING_WINDOW {
+            self.results.pop_front();
+        }
+        let lost = self.results.iter().filter(|answered| !**answered).count();
+        self.quality.loss = lost as f64 / self.results.len() as f64;
+    }
+}
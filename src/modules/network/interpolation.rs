@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+
+use cgmath::{Vector3, Quaternion, VectorSpace};
+use foundry::*;
+
+use crate::{Transform, NetworkTime};
+
+/// Default time in seconds remote entities are shown in the past, to always have a snapshot to go to.
+pub const DEFAULT_INTERPOLATION_DELAY: f64 = 0.1;
+/// Default time in seconds we keep moving an entity past its last snapshot, when the next one is late.
+pub const DEFAULT_MAX_EXTRAPOLATION: f64 = 0.25;
+/// Default number of snapshots an entity keeps. Older ones are dropped when more arrive.
+pub const DEFAULT_MAX_SNAPSHOTS: usize = 32;
+
+/// Statistics about the snapshot buffers of the interpolated entities.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterpolationStats {
+    /// times an entity ran out of snapshots to go to, and had to extrapolate or stop
+    pub underruns: u64,
+    /// snapshots dropped because a buffer was full
+    pub overruns: u64,
+}
+
+impl InterpolationStats {
+    pub(crate) fn add(&mut self, other: InterpolationStats) {
+        self.underruns += other.underruns;
+        self.overruns += other.overruns;
+    }
+}
+
+/// A transform received from the server, with the time of the server it is from.
+#[derive(Debug, Clone, Copy)]
+struct TransformSnapshot {
+    time: f64,
+    position: Vector3<f32>,
+    rotation: Quaternion<f32>,
+}
+
+/// Smooths the transform of a remote entity. Instead of applying the transforms received from the server right away,
+/// push them here : the entity is shown a little in the past, between the two snapshots around that time.
+/// Snapshots are placed on the timeline of the server, so jitter on the way does not show.
+/// The transform of the entity is updated by the client (see `Client::interpolation_stats`).
+#[derive(Debug, Clone)]
+pub struct Interpolated {
+    delay: f64,
+    max_extrapolation: f64,
+    max_snapshots: usize,
+    snapshots: VecDeque<TransformSnapshot>,
+    /// the last update had no snapshot to go to
+    starving: bool,
+    stats: InterpolationStats,
+    /// stats already added to the ones of the client
+    reported: InterpolationStats,
+}
+
+impl Interpolated {
+    pub fn new() -> Interpolated {
+        Interpolated {
+            delay: DEFAULT_INTERPOLATION_DELAY,
+            max_extrapolation: DEFAULT_MAX_EXTRAPOLATION,
+            max_snapshots: DEFAULT_MAX_SNAPSHOTS,
+            snapshots: VecDeque::new(),
+            starving: false,
+            stats: InterpolationStats::default(),
+            reported: InterpolationStats::default(),
+        }
+    }
+
+    /// Set how far in the past the entity is shown (in seconds). Longer hides more late packets, but shows older positions.
+    pub fn with_delay(mut self, delay: f64) -> Interpolated {
+        self.delay = delay;
+        self
+    }
+
+    /// Set how long the entity keeps moving past its last snapshot when the next one is late (in seconds).
+    pub fn with_max_extrapolation(mut self, max_extrapolation: f64) -> Interpolated {
+        self.max_extrapolation = max_extrapolation;
+        self
+    }
+
+    /// Set how many snapshots are kept.
+    pub fn with_max_snapshots(mut self, max_snapshots: usize) -> Interpolated {
+        self.max_snapshots = max_snapshots.max(2);
+        self
+    }
+
+    /// A transform of the entity was received from the server. It is from the tick the server sent the message at,
+    /// when pushed from `ClientHandler::handle_message`, or from the estimated server time otherwise.
+    /// Ignored until we know the clock of the server.
+    pub fn push(&mut self, transform: &Transform, network_time: &NetworkTime) {
+        let Some(time) = network_time.message_time().or(network_time.server_time()) else {
+            return;
+        };
+        let snapshot = TransformSnapshot {
+            time,
+            position: transform.position(),
+            rotation: transform.rotation(),
+        };
+        // udp can reorder the snapshots : keep them sorted, and the newest one of a tick
+        let index = self.snapshots.partition_point(|other| other.time <= time);
+        if index > 0 && self.snapshots[index - 1].time == time {
+            self.snapshots[index - 1] = snapshot;
+            return;
+        }
+        self.snapshots.insert(index, snapshot);
+        if self.snapshots.len() > self.max_snapshots {
+            self.snapshots.pop_front();
+            self.stats.overruns += 1;
+        }
+    }
+
+    /// Number of snapshots waiting to be shown.
+    pub fn buffered(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Underruns and overruns of this entity since it was created.
+    pub fn stats(&self) -> InterpolationStats {
+        self.stats
+    }
+
+    /// Returns the position and rotation to show at the given server time, if any snapshot was received.
+    fn sample(&mut self, server_time: f64) -> Option<(Vector3<f32>, Quaternion<f32>)> {
+        let render_time = server_time - self.delay;
+        // the two snapshots around the render time are kept, or the last two to extrapolate from
+        while self.snapshots.len() > 2 && self.snapshots[1].time <= render_time {
+            self.snapshots.pop_front();
+        }
+        let (from, to) = match (self.snapshots.front(), self.snapshots.get(1)) {
+            (Some(from), Some(to)) => (*from, *to),
+            (Some(only), None) => {
+                self.starve(render_time > only.time);
+                return Some((only.position, only.rotation));
+            },
+            _ => return None,
+        };
+        self.starve(render_time > to.time);
+        let span = to.time - from.time;
+        if span <= 0. {
+            return Some((to.position, to.rotation));
+        }
+        match render_time <= to.time {
+            true => {
+                let t = ((render_time - from.time) / span).max(0.) as f32;
+                Some((from.position.lerp(to.position, t), from.rotation.slerp(to.rotation, t)))
+            },
+            // late : keep going the same way for a while, then wait
+            false => {
+                let t = ((render_time.min(to.time + self.max_extrapolation) - from.time) / span) as f32;
+                Some((from.position.lerp(to.position, t), to.rotation))
+            },
+        }
+    }
+
+    /// Count an underrun when we start running out of snapshots.
+    fn starve(&mut self, starving: bool) {
+        if starving && !self.starving {
+            self.stats.underruns += 1;
+        }
+        self.starving = starving;
+    }
+}
+
+impl Default for Interpolated {
+    fn default() -> Interpolated {
+        Interpolated::new()
+    }
+}
+
+/// Move the interpolated entities, and returns the underruns and overruns that happened since the last call.
+pub(crate) fn update_interpolation(components: &mut ComponentTable) -> InterpolationStats {
+    let mut result = InterpolationStats::default();
+    // nothing moves before we know the clock of the server
+    let Some(server_time) = components.get_singleton::<NetworkTime>().and_then(NetworkTime::server_time) else {
+        return result;
+    };
+    for (interpolated, transform) in iterate_over_component_mut!(components; Interpolated, Transform) {
+        if let Some((position, rotation)) = interpolated.sample(server_time) {
+            transform.set_position(position);
+            transform.set_rotation(rotation);
+        }
+        result.add(InterpolationStats {
+            underruns: interpolated.stats.underruns - interpolated.reported.underruns,
+            overruns: interpolated.stats.overruns - interpolated.reported.overruns,
+        });
+        interpolated.reported = interpolated.stats;
+    }
+    result
+}
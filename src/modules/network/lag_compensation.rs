@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use cgmath::{Vector3, Quaternion, Rotation, ElementWise};
+use foundry::*;
+
+use crate::Transform;
+
+/// Default time in seconds the server remembers the past transforms of the lag compensated entities.
+pub const DEFAULT_LAG_HISTORY: f64 = 1.;
+
+/// Opt-in component for the entities the server must be able to rewind, like the players that can be shot.
+/// The server records their transform on each update (see `Server::with_lag_compensation`).
+#[derive(Debug, Clone, Copy)]
+pub struct LagCompensated {
+    /// bounding box in the space of the entity, min then max corner
+    bounds: [Vector3<f32>; 2],
+}
+
+impl LagCompensated {
+    /// The bounding box is given in the space of the entity, like the one of a mesh : min corner, then max corner.
+    pub fn new(bounds: [Vector3<f32>; 2]) -> LagCompensated {
+        LagCompensated { bounds }
+    }
+
+    pub fn bounds(&self) -> [Vector3<f32>; 2] {
+        self.bounds
+    }
+}
+
+/// Where a lag compensated entity was at some point in the past.
+#[derive(Clone, Copy)]
+pub struct PastEntity {
+    pub entity: EntityRef,
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+    pub bounds: [Vector3<f32>; 2],
+}
+
+impl PastEntity {
+    /// Distance along the ray where it enters the box of the entity, if it does before max_distance.
+    /// The direction does not need to be normalized : the distance is in units of its length.
+    /// Boxes the ray starts in are not hit, so a shot does not hit its shooter.
+    pub fn ray_hit(&self, origin: Vector3<f32>, direction: Vector3<f32>, max_distance: f32) -> Option<f32> {
+        // in the space of the entity, the box is aligned with the axes
+        let inverse = self.rotation.invert();
+        let local_origin = inverse.rotate_vector(origin - self.position).div_element_wise(self.scale);
+        let local_direction = inverse.rotate_vector(direction).div_element_wise(self.scale);
+        let mut enter = f32::NEG_INFINITY;
+        let mut exit = f32::INFINITY;
+        for axis in 0..3 {
+            let (min, max) = (self.bounds[0][axis], self.bounds[1][axis]);
+            match local_direction[axis] == 0. {
+                true if local_origin[axis] < min || local_origin[axis] > max => return None,
+                true => {},
+                false => {
+                    let first = (min - local_origin[axis]) / local_direction[axis];
+                    let second = (max - local_origin[axis]) / local_direction[axis];
+                    enter = enter.max(first.min(second));
+                    exit = exit.min(first.max(second));
+                },
+            }
+        }
+        (enter <= exit && enter >= 0. && enter <= max_distance).then_some(enter)
+    }
+}
+
+/// A ray that hit a lag compensated entity.
+#[derive(Clone, Copy)]
+pub struct RayHit {
+    pub entity: EntityRef,
+    /// distance along the ray, in units of the length of its direction
+    pub distance: f32,
+    pub point: Vector3<f32>,
+}
+
+/// The lag compensated entities at one update of the server.
+struct HistoryFrame {
+    time: f64,
+    entities: Vec<PastEntity>,
+}
+
+/// Server singleton remembering where the lag compensated entities were, to check the actions of the clients against
+/// what they saw when they did them : a client sees the world a round trip time plus its interpolation delay in the past.
+pub struct LagCompensation {
+    /// oldest first
+    frames: VecDeque<HistoryFrame>,
+    history: f64,
+    time: f64,
+    /// how far in the past each client sees the world, in seconds
+    view_delays: BTreeMap<u64, f64>,
+}
+
+impl LagCompensation {
+    pub(crate) fn new(history: f64) -> LagCompensation {
+        LagCompensation {
+            frames: VecDeque::new(),
+            history,
+            time: 0.,
+            view_delays: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn set_view_delays(&mut self, time: f64, view_delays: BTreeMap<u64, f64>) {
+        self.time = time;
+        self.view_delays = view_delays;
+    }
+
+    /// Remember where the entities are now, and forget what is older than the history.
+    pub(crate) fn record(&mut self, entities: Vec<PastEntity>) {
+        self.frames.push_back(HistoryFrame { time: self.time, entities });
+        while self.frames.front().is_some_and(|frame| self.time - frame.time > self.history) {
+            self.frames.pop_front();
+        }
+    }
+
+    /// Time of the server when the client saw what it is doing now. Never older than the history.
+    pub fn view_time(&self, client: u64) -> Option<f64> {
+        let delay = self.view_delays.get(&client)?.min(self.history);
+        Some(self.time - delay)
+    }
+
+    /// The lag compensated entities at the recorded update closest to the given server time.
+    pub fn entities_at(&self, time: f64) -> &[PastEntity] {
+        self.frames.iter()
+            .min_by(|a, b| (a.time - time).abs().total_cmp(&(b.time - time).abs()))
+            .map(|frame| frame.entities.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// The lag compensated entities as the client sees them now.
+    pub fn entities_seen_by(&self, client: u64) -> &[PastEntity] {
+        match self.view_time(client) {
+            Some(time) => self.entities_at(time),
+            None => &[],
+        }
+    }
+
+    /// The closest entity the ray hits at the given server time.
+    pub fn raycast_at(&self, time: f64, origin: Vector3<f32>, direction: Vector3<f32>, max_distance: f32) -> Option<RayHit> {
+        self.entities_at(time).iter()
+            .filter_map(|entity| entity.ray_hit(origin, direction, max_distance).map(|distance| (entity.entity, distance)))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(entity, distance)| RayHit { entity, distance, point: origin + direction * distance })
+    }
+
+    /// The closest entity the ray hits, where the client saw the entities. For shots sent by the client.
+    pub fn raycast(&self, client: u64, origin: Vector3<f32>, direction: Vector3<f32>, max_distance: f32) -> Option<RayHit> {
+        self.raycast_at(self.view_time(client)?, origin, direction, max_distance)
+    }
+}
+
+/// Read the lag compensated entities, to record them.
+pub(crate) fn capture_lag_compensated(components: &mut ComponentTable) -> Vec<PastEntity> {
+    iterate_over_component!(components; EntityRef; LagCompensated, Transform)
+        .map(|(entity, compensated, transform)| PastEntity {
+            entity,
+            position: transform.position(),
+            rotation: transform.rotation(),
+            scale: transform.get_scale(),
+            bounds: compensated.bounds,
+        })
+        .collect()
+}
@@ -1,23 +1,44 @@
 use std::mem::size_of;
 
-use crate::{NetworkSerializable, DefaultNetworkMessages};
+use crate::{NetworkSerializable, NetworkUnserializeError, DefaultNetworkMessages, ByteWriter, ByteReader};
 
 /// Packet errors
 #[derive(Debug)]
 pub enum PacketError {
     /// Packet could not be converted into the desired data type.
     InvalidForConversion,
-    /// Attempt to put too much data in the packet for it's remaining size.
-    DataOverflow,
 }
 
 /// Packet header is of known size and allow to tell us how many bytes we are expecting on connection.
 #[derive(Clone)]
 #[repr(C)]
-struct PacketHeader {
+pub(crate) struct PacketHeader {
     is_default: bool,
     size: u64,
     sender_id: u64,
+    /// tick of the server when it sent the packet, 0 for the packets of the clients
+    tick: u32,
+}
+
+impl PacketHeader {
+    /// Read a header from raw bytes. The size is not checked here, as it depends on the transport.
+    pub(crate) fn read(reader: &mut ByteReader) -> Result<PacketHeader, NetworkUnserializeError> {
+        let [is_default] = reader.read_array::<1>()?;
+        let (size, _) = u64::deserialize_from(reader)?;
+        let (sender_id, _) = u64::deserialize_from(reader)?;
+        let (tick, _) = u32::deserialize_from(reader)?;
+        Ok(PacketHeader {
+            is_default: is_default != 0,
+            size,
+            sender_id,
+            tick,
+        })
+    }
+
+    /// Size of the body announced by the header.
+    pub(crate) fn body_size(&self) -> u64 {
+        self.size
+    }
 }
 
 /// a packet is the data that is send through the network.
@@ -33,12 +54,13 @@ pub struct Packet {
 impl Packet {
     /// Creates a packet from any data implementing the `NetworkSerializable` trait. 
     /// If this is sent from the server, the sender does not matter.
-    pub fn from<S: NetworkSerializable>(from: S, sender: u64) -> Packet {
+    pub fn from<S: NetworkSerializable>(from: &S, sender: u64) -> Packet {
         Packet {
             header: PacketHeader {
                 is_default: false,
                 size: from.size() as u64,
                 sender_id: sender,
+                tick: 0,
             },
             body: from.serialize(),
         }
@@ -46,65 +68,70 @@ impl Packet {
 
     /// Creates a packet from the default network message enum. 
     /// If this is sent from the server, the sender does not matter.
-    pub fn from_default(from: DefaultNetworkMessages, sender: u64) -> Packet {
+    pub fn from_default(from: &DefaultNetworkMessages, sender: u64) -> Packet {
         Packet {
             header: PacketHeader {
                 is_default: true,
                 size: from.size() as u64,
                 sender_id: sender,
+                tick: 0,
             },
             body: from.serialize(),
         }
     }
 
-    /// Creates a packet with only a header as valid data. The packet must be completed before use. 
-    pub fn from_header(size: u64, sender_id: u64, is_default: bool) -> Packet {
+    /// Creates a packet from a header and a body read from the network.
+    pub(crate) fn from_raw(header: PacketHeader, body: Vec<u8>) -> Packet {
         Packet {
-            header: PacketHeader {
-                is_default,
-                size,
-                sender_id,
-            },
-            body: Vec::with_capacity(size as usize),
+            header,
+            body,
         }
     }
 
-    /// Add data to a packet.
-    /// returns an error if the packet overflowed (too many data was pushed into it) and won't add the data.
-    /// Otherwise, returns true if the packet is full (and ready to be unserialized)
-    pub fn push_data(&mut self, data: &[u8]) -> Result<bool, PacketError> {
-        match self.body.capacity().cmp(&(self.body.len() + data.len())) {
-            std::cmp::Ordering::Less => return Err(PacketError::DataOverflow),
-            std::cmp::Ordering::Equal => {
-                for val in data {
-                    self.body.push(*val);
-                }
-                return Ok(true);
+    /// Same packet with another body, for encryption.
+    pub(crate) fn with_body(&self, body: Vec<u8>) -> Packet {
+        Packet {
+            header: PacketHeader {
+                is_default: self.header.is_default,
+                size: body.len() as u64,
+                sender_id: self.header.sender_id,
+                tick: self.header.tick,
             },
-            std::cmp::Ordering::Greater => {
-                for val in data {
-                    self.body.push(*val);
-                }
-                return Ok(false);
-            }
+            body,
         }
     }
 
-    /// Get how many bytes is missing to complete the packet
-    pub fn awaiting_size(&self) -> usize {
-        self.body.capacity() - self.body.len()
+    /// Same packet, stamped with the tick of the server.
+    pub(crate) fn stamped(mut self, tick: u32) -> Packet {
+        self.header.tick = tick;
+        self
+    }
+
+    /// The header fields that do not depend on the body, to authenticate them along with an encrypted body.
+    pub(crate) fn authenticated_data(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(1 + size_of::<u64>() + size_of::<u32>());
+        result.write_bytes(&[self.header.is_default as u8]);
+        self.header.sender_id.serialize_into(&mut result);
+        self.header.tick.serialize_into(&mut result);
+        result
     }
 
     /// Convert the packet to a byte array.
-    pub fn as_bytes(mut self) -> Vec<u8> {
-        let mut result = Vec::with_capacity(self.header.size as usize + size_of::<PacketHeader>());
-        result.append(&mut vec![self.header.is_default as u8]);
-        result.append(&mut self.header.size.to_le_bytes().to_vec());
-        result.append(&mut self.header.sender_id.to_le_bytes().to_vec());
-        result.append(&mut self.body);
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.body.len() + Packet::header_size());
+        self.write_into(&mut result);
         result
     }
 
+    /// Write the packet, header included, at the end of the buffer.
+    pub fn write_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        buffer.write_bytes(&[self.header.is_default as u8]);
+        self.header.size.serialize_into(buffer);
+        self.header.sender_id.serialize_into(buffer);
+        self.header.tick.serialize_into(buffer);
+        buffer.write_bytes(&self.body);
+    }
+
     pub fn is_default(&self) -> bool {
         self.header.is_default
     }
@@ -112,10 +139,15 @@ impl Packet {
     pub fn get_sender(&self) -> u64 {
         self.header.sender_id
     }
+
+    /// Tick of the server when it sent the packet (see `Server::tick`). Always 0 for the packets of the clients.
+    pub fn tick(&self) -> u32 {
+        self.header.tick
+    }
     
     /// Try to convert the packet into the given data type.
     pub fn into<S: NetworkSerializable>(self) -> Result<S, PacketError> {
-        match S::deserialize(self.body) {
+        match S::deserialize(&self.body) {
             Ok(result) => Ok(result),
             Err(_e) => Err(PacketError::InvalidForConversion),
         }
@@ -123,7 +155,7 @@ impl Packet {
 
     /// Get the size of a packet header. 
     pub fn header_size() -> usize {
-        2 * size_of::<u64>() + size_of::<bool>()
+        2 * size_of::<u64>() + size_of::<u32>() + size_of::<bool>()
     }
 }
 
@@ -0,0 +1,457 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use foundry::*;
+
+use crate::{NetworkSerializable, NetworkUnserializeError, DefaultNetworkMessages};
+
+/// Default number of ticks a correction of the predicted state is smoothed over.
+pub const DEFAULT_SMOOTHING_TICKS: u32 = 6;
+/// Inputs sent again in each input message, in case the previous ones were lost.
+const REDUNDANT_INPUTS: usize = 8;
+/// Inputs the client keeps while waiting for the server to process them. The oldest are forgotten past it.
+const MAX_PENDING_INPUTS: usize = 256;
+/// Ticks of inputs the server can apply at once for a client, to catch up after late messages. More would let it run faster than time.
+const MAX_INPUT_BURST: f64 = 2. * REDUNDANT_INPUTS as f64;
+
+/// A state the client predicts from its inputs, before the server says what it really is (like the movement of its player).
+/// The same step runs on the client, ahead of the server, and on the server, which has the last word.
+/// The step must be deterministic, so the client only has to correct its prediction when the server state changed in another way.
+pub trait Predicted: NetworkSerializable + Clone + PartialEq + 'static {
+    type Input: NetworkSerializable + Clone + 'static;
+    /// Move the state forward of one tick, with the input of the tick. Delta is the length of a tick in seconds.
+    fn step(&mut self, input: &Self::Input, delta: f32);
+    /// A state between this one (t = 0) and the target (t = 1), to smooth the corrections of the prediction.
+    /// Jumps right to the target by default.
+    fn blend(&self, target: &Self, _t: f32) -> Self {
+        target.clone()
+    }
+}
+
+/// An input of a tick, with what the state was predicted to be after it.
+struct PendingInput<P: Predicted> {
+    tick: u32,
+    input: P::Input,
+    state: P,
+}
+
+/// Client singleton holding the state predicted from our inputs (see `Client::with_prediction`).
+/// Inputs are sent with `ClientMessage::predict`, each one is a tick. When the server state disagrees with what we predicted,
+/// we go back to it and replay the inputs it did not process yet. The displayed state then slides to the corrected one over a few ticks.
+pub struct Prediction<P: Predicted> {
+    tick_delta: f32,
+    /// tick of the next input
+    next_tick: u32,
+    state: P,
+    /// inputs the server did not process yet, oldest first
+    pending: VecDeque<PendingInput<P>>,
+    /// the state we predicted before the last correction, moving on with the inputs, and the ticks since the correction
+    correction: Option<(P, u32)>,
+    smoothing_ticks: u32,
+    corrections: u64,
+}
+
+impl<P: Predicted> Prediction<P> {
+    pub(crate) fn new(state: P, tick_delta: f32, smoothing_ticks: u32) -> Prediction<P> {
+        Prediction {
+            tick_delta,
+            next_tick: 0,
+            state,
+            pending: VecDeque::new(),
+            correction: None,
+            smoothing_ticks,
+            corrections: 0,
+        }
+    }
+
+    /// The predicted state, with all our inputs.
+    pub fn state(&self) -> &P {
+        &self.state
+    }
+
+    /// The state to show : the predicted one, or on its way to it after a correction.
+    pub fn displayed(&self) -> P {
+        match &self.correction {
+            Some((previous, ticks)) => previous.blend(&self.state, *ticks as f32 / self.smoothing_ticks as f32),
+            None => self.state.clone(),
+        }
+    }
+
+    /// Tick of the next input.
+    pub fn tick(&self) -> u32 {
+        self.next_tick
+    }
+
+    /// Number of inputs the server did not process yet.
+    pub fn pending_inputs(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of times the server disagreed with our prediction.
+    pub fn corrections(&self) -> u64 {
+        self.corrections
+    }
+
+    /// Predict the next tick with the input, and returns the message carrying the inputs not processed yet.
+    fn input(&mut self, input: P::Input) -> DefaultNetworkMessages {
+        self.state.step(&input, self.tick_delta);
+        if let Some((previous, ticks)) = &mut self.correction {
+            previous.step(&input, self.tick_delta);
+            *ticks += 1;
+            if *ticks >= self.smoothing_ticks {
+                self.correction = None;
+            }
+        }
+        self.pending.push_back(PendingInput { tick: self.next_tick, input, state: self.state.clone() });
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.next_tick = self.next_tick.wrapping_add(1);
+        let resent = self.pending.len().min(REDUNDANT_INPUTS);
+        let first = self.pending.len() - resent;
+        let inputs = self.pending.range(first..).map(|pending| pending.input.serialize()).collect();
+        DefaultNetworkMessages::PredictionInputs(self.pending[first].tick, inputs)
+    }
+
+    /// The server sent its state after the input of the given tick : check our prediction, and correct it if it was wrong.
+    fn reconcile(&mut self, tick: u32, state: P) {
+        // late, we already reconciled a newer one
+        let Some(index) = self.pending.iter().position(|pending| pending.tick == tick) else {
+            return;
+        };
+        let predicted = self.pending.drain(..=index).last().map(|pending| pending.state);
+        if predicted.as_ref() == Some(&state) {
+            return;
+        }
+        self.corrections += 1;
+        // smooth from what is displayed now, not from the wrong prediction
+        self.correction = Some((self.displayed(), 0));
+        self.state = state;
+        for pending in self.pending.iter_mut() {
+            self.state.step(&pending.input, self.tick_delta);
+            pending.state = self.state.clone();
+        }
+    }
+}
+
+/// Server singleton holding the authoritative state of each client (see `Server::with_prediction`).
+/// The inputs of the clients are applied to it as they come. Changing a state here is sent to its client, which corrects its prediction.
+pub struct Authority<P: Predicted> {
+    tick_delta: f32,
+    clients: BTreeMap<u64, AuthoritativeState<P>>,
+}
+
+struct AuthoritativeState<P: Predicted> {
+    state: P,
+    /// tick of the last input applied, if any
+    last_tick: Option<u32>,
+    /// ticks of inputs that can still be applied : the time that went by, in ticks, minus the inputs applied
+    budget: f64,
+    /// what the client was last sent, to only send the states that changed
+    sent: Option<(u32, Vec<u8>)>,
+}
+
+impl<P: Predicted> Authority<P> {
+    fn new(tick_delta: f32) -> Authority<P> {
+        Authority {
+            tick_delta,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    pub fn state(&self, client: u64) -> Option<&P> {
+        self.clients.get(&client).map(|client| &client.state)
+    }
+
+    pub fn state_mut(&mut self, client: u64) -> Option<&mut P> {
+        self.clients.get_mut(&client).map(|client| &mut client.state)
+    }
+
+    /// Tick of the last input of the client that was applied.
+    pub fn last_tick(&self, client: u64) -> Option<u32> {
+        self.clients.get(&client).and_then(|client| client.last_tick)
+    }
+
+    /// The clients and their states.
+    pub fn states(&self) -> impl Iterator<Item = (u64, &P)> {
+        self.clients.iter().map(|(id, client)| (*id, &client.state))
+    }
+
+    /// Time went by on the server : the clients can apply that many more ticks of inputs.
+    fn elapse(&mut self, delta: f64) {
+        let ticks = delta / self.tick_delta as f64;
+        for client in self.clients.values_mut() {
+            client.budget = (client.budget + ticks).min(MAX_INPUT_BURST);
+        }
+    }
+
+    /// Apply the inputs of a client, the first one being of the given tick. Inputs already applied are skipped.
+    /// Missing ticks (all their messages were lost) are skipped too : the client will be corrected.
+    /// A client can't apply inputs faster than the ticks go by : the ones over the budget wait for the next message.
+    fn apply(&mut self, client: u64, first_tick: u32, inputs: &[Vec<u8>]) -> Result<(), NetworkUnserializeError> {
+        let Some(client) = self.clients.get_mut(&client) else {
+            return Ok(());
+        };
+        for (index, input) in inputs.iter().enumerate() {
+            let tick = first_tick.wrapping_add(index as u32);
+            // ticks come from the client : they may wrap, or be anything
+            if client.last_tick.is_some_and(|last| tick.wrapping_sub(last) as i32 <= 0) {
+                continue;
+            }
+            if client.budget < 1. {
+                break;
+            }
+            client.state.step(&P::Input::deserialize(input)?, self.tick_delta);
+            client.last_tick = Some(tick);
+            client.budget -= 1.;
+        }
+        Ok(())
+    }
+
+    /// The states that changed since they were last sent, with the tick of the last input applied to them.
+    fn changed(&mut self) -> Vec<(u64, u32, Vec<u8>)> {
+        let mut result = Vec::new();
+        for (id, client) in self.clients.iter_mut() {
+            // the client can only reconcile with a tick of its inputs
+            let Some(tick) = client.last_tick else {
+                continue;
+            };
+            let bytes = client.state.serialize();
+            if client.sent.as_ref().is_some_and(|(sent_tick, sent)| *sent_tick == tick && *sent == bytes) {
+                continue;
+            }
+            client.sent = Some((tick, bytes.clone()));
+            result.push((*id, tick, bytes));
+        }
+        result
+    }
+}
+
+/// Client side of the prediction : runs the inputs on the `Prediction` singleton, without knowing the predicted type.
+pub(crate) struct PredictionClient {
+    /// (re)create the singleton, when connecting
+    reset: Box<dyn Fn(&mut ComponentTable)>,
+    /// predict a tick with a serialized input, and returns the message to send
+    input: Box<dyn Fn(&[u8], &mut ComponentTable) -> Option<DefaultNetworkMessages>>,
+    /// correct the prediction with the serialized server state after the given tick
+    reconcile: Box<dyn Fn(u32, &[u8], &mut ComponentTable) -> Result<(), NetworkUnserializeError>>,
+}
+
+impl PredictionClient {
+    pub(crate) fn new<P: Predicted>(initial: P, tick_delta: f32, smoothing_ticks: u32) -> PredictionClient {
+        PredictionClient {
+            reset: Box::new(move |components| {
+                let prediction = Prediction::new(initial.clone(), tick_delta, smoothing_ticks.max(1));
+                match components.get_singleton_mut::<Prediction<P>>() {
+                    Some(current) => *current = prediction,
+                    None => components.add_singleton(prediction),
+                }
+            }),
+            input: Box::new(|input, components| {
+                let input = match P::Input::deserialize(input) {
+                    Ok(input) => input,
+                    Err(e) => {
+                        println!("[NETWORK CLIENT] -> Unable to read the predicted input : {e:?}.");
+                        return None;
+                    },
+                };
+                components.get_singleton_mut::<Prediction<P>>().map(|prediction| prediction.input(input))
+            }),
+            reconcile: Box::new(|tick, state, components| {
+                let state = P::deserialize(state)?;
+                if let Some(prediction) = components.get_singleton_mut::<Prediction<P>>() {
+                    prediction.reconcile(tick, state);
+                }
+                Ok(())
+            }),
+        }
+    }
+
+    pub(crate) fn reset(&self, components: &mut ComponentTable) {
+        (self.reset)(components)
+    }
+
+    pub(crate) fn input(&self, input: &[u8], components: &mut ComponentTable) -> Option<DefaultNetworkMessages> {
+        (self.input)(input, components)
+    }
+
+    pub(crate) fn reconcile(&self, tick: u32, state: &[u8], components: &mut ComponentTable) -> Result<(), NetworkUnserializeError> {
+        (self.reconcile)(tick, state, components)
+    }
+}
+
+/// Server side of the prediction : runs the inputs of the clients on the `Authority` singleton, without knowing the predicted type.
+pub(crate) struct PredictionServer {
+    /// create the state of a client that joined
+    connect: Box<dyn FnMut(u64, &mut ComponentTable)>,
+    disconnect: Box<dyn Fn(u64, &mut ComponentTable)>,
+    apply: Box<dyn Fn(u64, u32, &[Vec<u8>], &mut ComponentTable) -> Result<(), NetworkUnserializeError>>,
+    /// let the time go by, for the inputs the clients can apply
+    elapse: Box<dyn Fn(f64, &mut ComponentTable)>,
+    /// the states to send, with the tick of the last input applied
+    changed: Box<dyn Fn(&mut ComponentTable) -> Vec<(u64, u32, Vec<u8>)>>,
+}
+
+impl PredictionServer {
+    pub(crate) fn new<P, F>(tick_delta: f32, mut spawn: F) -> PredictionServer
+        where P: Predicted, F: FnMut(u64, &mut ComponentTable) -> P + 'static
+    {
+        PredictionServer {
+            connect: Box::new(move |client, components| {
+                let state = AuthoritativeState { state: spawn(client, components), last_tick: None, budget: REDUNDANT_INPUTS as f64, sent: None };
+                if components.get_singleton::<Authority<P>>().is_none() {
+                    components.add_singleton(Authority::<P>::new(tick_delta));
+                }
+                if let Some(authority) = components.get_singleton_mut::<Authority<P>>() {
+                    authority.clients.insert(client, state);
+                }
+            }),
+            disconnect: Box::new(|client, components| {
+                if let Some(authority) = components.get_singleton_mut::<Authority<P>>() {
+                    authority.clients.remove(&client);
+                }
+            }),
+            apply: Box::new(|client, first_tick, inputs, components| {
+                match components.get_singleton_mut::<Authority<P>>() {
+                    Some(authority) => authority.apply(client, first_tick, inputs),
+                    None => Ok(()),
+                }
+            }),
+            elapse: Box::new(|delta, components| {
+                if let Some(authority) = components.get_singleton_mut::<Authority<P>>() {
+                    authority.elapse(delta);
+                }
+            }),
+            changed: Box::new(|components| {
+                match components.get_singleton_mut::<Authority<P>>() {
+                    Some(authority) => authority.changed(),
+                    None => Vec::new(),
+                }
+            }),
+        }
+    }
+
+    pub(crate) fn connect(&mut self, client: u64, components: &mut ComponentTable) {
+        (self.connect)(client, components)
+    }
+
+    pub(crate) fn disconnect(&self, client: u64, components: &mut ComponentTable) {
+        (self.disconnect)(client, components)
+    }
+
+    pub(crate) fn apply(&self, client: u64, first_tick: u32, inputs: &[Vec<u8>], components: &mut ComponentTable) -> Result<(), NetworkUnserializeError> {
+        (self.apply)(client, first_tick, inputs, components)
+    }
+
+    pub(crate) fn elapse(&self, delta: f64, components: &mut ComponentTable) {
+        (self.elapse)(delta, components)
+    }
+
+    pub(crate) fn changed(&self, components: &mut ComponentTable) -> Vec<(u64, u32, Vec<u8>)> {
+        (self.changed)(components)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ByteWriter, ByteReader, guarded_schema_hash};
+
+    /// Moves of the input each tick.
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Walker {
+        x: i32,
+    }
+
+    impl Predicted for Walker {
+        type Input = i32;
+        fn step(&mut self, input: &i32, _delta: f32) {
+            self.x += input;
+        }
+        fn blend(&self, target: &Walker, t: f32) -> Walker {
+            Walker { x: self.x + ((target.x - self.x) as f32 * t) as i32 }
+        }
+    }
+
+    fn inputs(count: usize) -> Vec<Vec<u8>> {
+        vec![1i32.serialize(); count]
+    }
+
+    /// Authority with one client, that can apply as many inputs as when it connects.
+    fn authority(client: u64) -> Authority<Walker> {
+        let mut authority = Authority::new(0.1);
+        authority.clients.insert(client, AuthoritativeState { state: Walker { x: 0 }, last_tick: None, budget: REDUNDANT_INPUTS as f64, sent: None });
+        authority
+    }
+
+    #[test]
+    fn inputs_are_sent_with_the_previous_ones() {
+        let mut prediction = Prediction::new(Walker { x: 0 }, 0.1, 4);
+        assert!(matches!(prediction.input(1), DefaultNetworkMessages::PredictionInputs(0, inputs) if inputs.len() == 1));
+        for _ in 0..9 {
+            prediction.input(1);
+        }
+        assert!(matches!(prediction.input(1), DefaultNetworkMessages::PredictionInputs(3, inputs) if inputs.len() == REDUNDANT_INPUTS));
+        assert_eq!(prediction.state(), &Walker { x: 11 });
+        assert_eq!(prediction.pending_inputs(), 11);
+    }
+
+    #[test]
+    fn correction_replays_the_pending_inputs() {
+        let mut prediction = Prediction::new(Walker { x: 0 }, 0.1, 4);
+        for input in [1, 2, 3] {
+            prediction.input(input);
+        }
+        assert_eq!(prediction.state(), &Walker { x: 6 });
+        // the server agrees with the first tick
+        prediction.reconcile(0, Walker { x: 1 });
+        assert_eq!((prediction.corrections(), prediction.pending_inputs()), (0, 2));
+        // then something pushed us after the second one : the third input is replayed from there
+        prediction.reconcile(1, Walker { x: 10 });
+        assert_eq!((prediction.corrections(), prediction.pending_inputs()), (1, 1));
+        assert_eq!(prediction.state(), &Walker { x: 13 });
+        // the displayed state starts from where it was, and slides to the corrected one
+        assert_eq!(prediction.displayed(), Walker { x: 6 });
+        prediction.input(0);
+        assert!(prediction.displayed().x > 6 && prediction.displayed().x < 13);
+        for _ in 0..3 {
+            prediction.input(0);
+        }
+        assert_eq!(prediction.displayed(), Walker { x: 13 });
+        // a late state is ignored, and the replayed prediction was right
+        prediction.reconcile(0, Walker { x: 100 });
+        prediction.reconcile(2, Walker { x: 13 });
+        assert_eq!(prediction.corrections(), 1);
+        assert_eq!(prediction.state(), &Walker { x: 13 });
+    }
+
+    #[test]
+    fn input_budget_caps_bursts() {
+        let mut authority = authority(1);
+        // only the inputs of the starting budget go through
+        authority.apply(1, 0, &inputs(20)).unwrap();
+        assert_eq!(authority.last_tick(1), Some(REDUNDANT_INPUTS as u32 - 1));
+        authority.apply(1, 0, &inputs(20)).unwrap();
+        assert_eq!(authority.state(1), Some(&Walker { x: REDUNDANT_INPUTS as i32 }));
+        // three ticks went by : three more inputs, the applied ones being skipped
+        authority.elapse(0.3 + 1e-6);
+        authority.apply(1, 0, &inputs(20)).unwrap();
+        assert_eq!(authority.last_tick(1), Some(REDUNDANT_INPUTS as u32 + 2));
+        // a long pause does not allow more than the max burst
+        authority.elapse(100.);
+        authority.apply(1, 0, &inputs(100)).unwrap();
+        assert_eq!(authority.last_tick(1), Some(REDUNDANT_INPUTS as u32 + 2 + MAX_INPUT_BURST as u32));
+        assert_eq!(authority.state(1), Some(&Walker { x: REDUNDANT_INPUTS as i32 + 3 + MAX_INPUT_BURST as i32 }));
+    }
+
+    #[test]
+    fn only_changed_states_are_sent() {
+        let mut authority = authority(1);
+        assert!(authority.changed().is_empty());
+        authority.apply(1, 0, &inputs(2)).unwrap();
+        assert_eq!(authority.changed(), vec![(1, 1, Walker { x: 2 }.serialize())]);
+        assert!(authority.changed().is_empty());
+        authority.state_mut(1).unwrap().x = 5;
+        assert_eq!(authority.changed(), vec![(1, 1, Walker { x: 5 }.serialize())]);
+    }
+}
@@ -0,0 +1,82 @@
+/// Default time in seconds a normal priority unreliable message can wait for bandwidth before being dropped.
+pub const DEFAULT_UNRELIABLE_MAX_AGE: f64 = 0.2;
+/// The allowance of a bandwidth budget can't grow over this many seconds of budget, to avoid bursts after a long tick.
+const MAX_BURST: f64 = 0.25;
+
+/// How important a message is when there is not enough bandwidth to send everything.
+/// Messages are sent by priority, and a message gains its weight again for every second it waits, so low priority ones are not stuck forever.
+/// Unreliable messages that waited too long are dropped : low priority ones sooner, high priority ones later.
+/// Tcp messages are never held back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Things that can wait, like cosmetic effects.
+    Low,
+    #[default]
+    Normal,
+    /// Things that should not wait behind the rest, like chat or inventory.
+    High,
+}
+
+impl Priority {
+    fn weight(self) -> f64 {
+        match self {
+            Priority::Low => 1.,
+            Priority::Normal => 2.,
+            Priority::High => 4.,
+        }
+    }
+
+    /// How urgent a message queued at the given time is. The highest is sent first.
+    pub(crate) fn score(self, queued_at: f64, time: f64) -> f64 {
+        self.weight() * (1. + (time - queued_at).max(0.))
+    }
+
+    /// How long an unreliable message can wait before being dropped, from the max age of normal priority messages.
+    pub(crate) fn max_age(self, normal_max_age: f64) -> f64 {
+        normal_max_age * self.weight() / Priority::Normal.weight()
+    }
+}
+
+/// Bytes per second budget of a connection, as a token bucket : the allowance grows with time, and shrinks with what is sent.
+/// A message is sent as long as the allowance is positive, even if it is bigger than it : big messages are not stuck forever,
+/// the connection is just in debt for the next ticks.
+pub(crate) struct Bandwidth {
+    /// bytes per second, None for no limit
+    budget: Option<f64>,
+    allowance: f64,
+    last_refill: Option<f64>,
+}
+
+impl Bandwidth {
+    pub(crate) fn new(budget: Option<u64>) -> Bandwidth {
+        let budget = budget.map(|budget| budget as f64);
+        Bandwidth {
+            budget,
+            allowance: budget.unwrap_or(0.) * MAX_BURST,
+            last_refill: None,
+        }
+    }
+
+    pub(crate) fn budget(&self) -> Option<u64> {
+        self.budget.map(|budget| budget as u64)
+    }
+
+    /// Grow the allowance with the time since the last refill.
+    pub(crate) fn refill(&mut self, time: f64) {
+        if let (Some(budget), Some(last)) = (self.budget, self.last_refill) {
+            self.allowance = (self.allowance + budget * (time - last).max(0.)).min(budget * MAX_BURST);
+        }
+        self.last_refill = Some(time);
+    }
+
+    /// Whether something can be sent now.
+    pub(crate) fn available(&self) -> bool {
+        self.budget.is_none() || self.allowance > 0.
+    }
+
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        if self.budget.is_some() {
+            self.allowance -= bytes as f64;
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use crate::{NetworkSerializable, NetworkUnserializeError, ByteWriter, ByteReader, guarded_schema_hash, DefaultNetworkMessages};
+
+/// Protocol spoken by a server or a client.
+/// Both sides must have the same to talk to each other, otherwise they would deserialize garbage.
+#[derive(NetworkSerializable)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    /// Version chosen by the game, from the `PROTOCOL_VERSION` of the handlers.
+    pub version: u32,
+    /// Hash of the layout of all the messages that can be exchanged.
+    pub schema_hash: u64,
+}
+
+impl ProtocolVersion {
+    /// Create the protocol version from the game version and the messages of the handler.
+    /// The server messages must be given first, then the client ones, on both sides.
+    pub fn new<S: NetworkSerializable, C: NetworkSerializable>(version: u32) -> ProtocolVersion {
+        let mut schema_hash = DefaultNetworkMessages::schema_hash();
+        for hash in [S::schema_hash(), C::schema_hash()] {
+            schema_hash = (schema_hash ^ hash).wrapping_mul(0x100000001b3);
+        }
+        ProtocolVersion {
+            version,
+            schema_hash,
+        }
+    }
+}
+
+/// Reasons for the server to refuse a connection.
+#[derive(NetworkSerializable)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    #[network(id = 0)]
+    ServerFull,
+    /// The client protocol is not the server one. Carries the server protocol.
+    #[network(id = 1)]
+    ProtocolMismatch(ProtocolVersion),
+    /// The address or id of the client is in the server ban list.
+    #[network(id = 2)]
+    Banned,
+    /// The server handler refused the connection. Carries the reason it gave.
+    #[network(id = 3)]
+    Refused(String),
+    /// The server only accepts encrypted connections.
+    #[network(id = 4)]
+    EncryptionRequired,
+    /// The client asked for encryption, but the server does not have it enabled.
+    #[network(id = 5)]
+    EncryptionUnsupported,
+}
@@ -0,0 +1,60 @@
+/// Default time in seconds the client waits for a connection to the server before giving up.
+pub const DEFAULT_CONNECT_TIMEOUT: f64 = 5.;
+
+/// How the client tries to connect again when a connection could not be made, or was lost.
+/// The delay between two attempts starts at the initial delay, and is multiplied after each failure, up to the max delay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    /// delay before the first retry, in seconds
+    pub initial_delay: f64,
+    /// the delay never grows over this, in seconds
+    pub max_delay: f64,
+    /// the delay is multiplied by this after each failed attempt
+    pub multiplier: f64,
+    /// give up after this many retries. None to never give up.
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    /// Retry after half a second, then double the delay up to 30 seconds, forever.
+    pub fn new() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: 0.5,
+            max_delay: 30.,
+            multiplier: 2.,
+            max_attempts: None,
+        }
+    }
+
+    pub fn with_initial_delay(mut self, initial_delay: f64) -> ReconnectPolicy {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: f64) -> ReconnectPolicy {
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn with_multiplier(mut self, multiplier: f64) -> ReconnectPolicy {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> ReconnectPolicy {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Delay before the given retry, the first one being 0.
+    pub(crate) fn delay(&self, attempt: u32) -> f64 {
+        // clamp the exponent, the delay is capped long before anyway
+        (self.initial_delay * self.multiplier.powi(attempt.min(64) as i32)).min(self.max_delay)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy::new()
+    }
+}
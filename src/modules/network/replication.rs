@@ -0,0 +1,513 @@
+use std::{collections::{BTreeMap, BTreeSet, VecDeque}, rc::Rc};
+
+use cgmath::{Vector3, InnerSpace};
+use foundry::*;
+
+use crate::{NetworkSerializable, NetworkUnserializeError, ByteWriter, ByteReader, guarded_schema_hash, DefaultNetworkMessages, Transform};
+
+/// Snapshots sent to a client that can still be acknowledged, and snapshots a client keeps to read the next ones.
+const MAX_SNAPSHOT_HISTORY: usize = 64;
+/// Serialized components are compared by words of this many bytes : only the words that changed are sent.
+const WORD_SIZE: usize = 4;
+
+/// A component type whose value is copied from the server to the clients, on the entities with a `Replicated` component.
+/// It must be registered on both sides (see `Server::with_replicated` and `Client::with_replicated`) with the same id.
+/// Ids are explicit, so they don't depend on the build. 0 is taken by `Transform`, which is always replicated.
+pub trait Networked: NetworkSerializable + 'static {
+    const COMPONENT_ID: u16;
+}
+
+impl Networked for Transform {
+    const COMPONENT_ID: u16 = 0;
+}
+
+/// Marks an entity whose networked components are mirrored on the clients.
+/// Add it to the entities to replicate on the server : the clients get an entity with the same components,
+/// and a `Replicated` component with the same network id. Removing it (or the entity) removes the mirrors.
+/// Clients only get the entities relevant to them : see `Server::with_relevancy_distance` and `ServerHandler::is_relevant`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Replicated {
+    network_id: Option<u64>,
+    always_relevant: bool,
+    owner: Option<u64>,
+    owner_only: bool,
+}
+
+impl Replicated {
+    pub fn new() -> Replicated {
+        Replicated {
+            network_id: None,
+            always_relevant: false,
+            owner: None,
+            owner_only: false,
+        }
+    }
+
+    /// Send the entity to every client, however far it is from their viewers.
+    pub fn always_relevant(mut self) -> Replicated {
+        self.always_relevant = true;
+        self
+    }
+
+    /// The entity belongs to a client : it is always relevant to it.
+    pub fn owned_by(mut self, client: u64) -> Replicated {
+        self.owner = Some(client);
+        self
+    }
+
+    /// Only send the entity to its owner (see `owned_by`). Entities without owner are then sent to no one.
+    pub fn owner_only(mut self) -> Replicated {
+        self.owner_only = true;
+        self
+    }
+
+    /// Id of the entity shared by the server and the clients, given by the server the first time it replicates the entity.
+    pub fn network_id(&self) -> Option<u64> {
+        self.network_id
+    }
+
+    /// The client the entity belongs to, if any. Only known on the server.
+    pub fn owner(&self) -> Option<u64> {
+        self.owner
+    }
+
+    /// What the flags say about the relevancy of the entity to a client, if they decide.
+    fn forced_relevancy(&self, client: u64) -> Option<bool> {
+        match (self.owner_only, self.owner == Some(client)) {
+            (true, is_owner) => Some(is_owner),
+            (false, true) => Some(true),
+            (false, false) => self.always_relevant.then_some(true),
+        }
+    }
+}
+
+/// Marks the entity a client sees the world from, like its player or camera. When the server has a relevancy distance,
+/// the replicated entities further than that from all the viewers of a client are not sent to it.
+#[derive(Debug, Clone, Copy)]
+pub struct Viewer {
+    client: u64,
+}
+
+impl Viewer {
+    pub fn new(client: u64) -> Viewer {
+        Viewer { client }
+    }
+
+    pub fn client(&self) -> u64 {
+        self.client
+    }
+}
+
+/// Whether a replicated entity is sent to a client.
+pub(crate) enum Relevancy {
+    /// the flags of the entity decide
+    Forced(bool),
+    /// up to the server handler, knowing if the entity is close enough to a viewer of the client
+    InRange(bool),
+}
+
+/// A networked component in a snapshot.
+#[derive(NetworkSerializable)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComponentState {
+    /// the whole serialized component
+    #[network(id = 0)]
+    Full(Vec<u8>),
+    /// the words that changed since the baseline : bit i of the mask is set when word i changed, and the changed words follow in order
+    #[network(id = 1)]
+    Changed(Vec<u8>, Vec<u8>),
+}
+
+/// An entity that was spawned or changed since the baseline.
+#[derive(NetworkSerializable)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityState {
+    network_id: u64,
+    /// components added or changed, by component id
+    components: Vec<(u16, ComponentState)>,
+    /// ids of the components removed from the entity
+    removed: Vec<u16>,
+}
+
+/// Serialized networked components of the replicated entities, by network id then component id.
+type WorldState = BTreeMap<u64, BTreeMap<u16, Vec<u8>>>;
+
+/// The smallest way to send the new value of a component, knowing the client has the old one.
+fn encode(old: Option<&Vec<u8>>, new: &[u8]) -> ComponentState {
+    let full = ComponentState::Full(new.to_vec());
+    let old = match old {
+        Some(old) if old.len() == new.len() => old,
+        _ => return full,
+    };
+    let mut mask = vec![0u8; new.len().div_ceil(WORD_SIZE).div_ceil(8)];
+    let mut words = Vec::new();
+    for (index, (old, new)) in old.chunks(WORD_SIZE).zip(new.chunks(WORD_SIZE)).enumerate() {
+        if old != new {
+            mask[index / 8] |= 1 << (index % 8);
+            words.extend_from_slice(new);
+        }
+    }
+    let changed = ComponentState::Changed(mask, words);
+    match changed.size() < full.size() {
+        true => changed,
+        false => full,
+    }
+}
+
+/// Rebuild the new value of a component from the old one. None if the state does not match the old value.
+fn decode(old: Option<&Vec<u8>>, state: &ComponentState) -> Option<Vec<u8>> {
+    match state {
+        ComponentState::Full(bytes) => Some(bytes.clone()),
+        ComponentState::Changed(mask, words) => {
+            let mut result = old?.clone();
+            if mask.len() != result.len().div_ceil(WORD_SIZE).div_ceil(8) {
+                return None;
+            }
+            let mut words = words.as_slice();
+            for (index, word) in result.chunks_mut(WORD_SIZE).enumerate() {
+                if mask[index / 8] & (1 << (index % 8)) != 0 {
+                    let (new, rest) = words.split_at_checked(word.len())?;
+                    word.copy_from_slice(new);
+                    words = rest;
+                }
+            }
+            words.is_empty().then_some(result)
+        },
+    }
+}
+
+/// How to read a networked component type on the server, and write it on the clients.
+struct NetworkedType {
+    /// serialized value of the component on every replicated entity, by network id
+    read: Box<dyn Fn(&mut ComponentTable) -> Vec<(u64, Vec<u8>)>>,
+    write: Box<dyn Fn(&mut ComponentTable, EntityRef, &[u8]) -> Result<(), NetworkUnserializeError>>,
+    remove: Box<dyn Fn(&mut ComponentTable, EntityRef)>,
+}
+
+/// The networked component types, by component id.
+pub(crate) struct NetworkedTypes {
+    types: BTreeMap<u16, NetworkedType>,
+}
+
+impl NetworkedTypes {
+    /// Transform is always networked.
+    pub(crate) fn new() -> NetworkedTypes {
+        let mut result = NetworkedTypes { types: BTreeMap::new() };
+        result.register::<Transform>();
+        result
+    }
+
+    pub(crate) fn register<C: Networked>(&mut self) {
+        if self.types.contains_key(&C::COMPONENT_ID) {
+            println!("[NETWORK] -> Networked component id {} is registered twice, the last one is kept.", C::COMPONENT_ID);
+        }
+        self.types.insert(C::COMPONENT_ID, NetworkedType {
+            read: Box::new(|components| {
+                iterate_over_component!(components; EntityRef; Replicated, C)
+                    .filter_map(|(_, replicated, component)| replicated.network_id.map(|id| (id, component.serialize())))
+                    .collect()
+            }),
+            write: Box::new(|components, entity, bytes| {
+                components.add_component(entity, C::deserialize(bytes)?);
+                Ok(())
+            }),
+            remove: Box::new(|components, entity| components.remove_component::<C>(entity)),
+        });
+    }
+}
+
+/// A replicated entity, as read by the server this tick.
+pub(crate) struct CapturedEntity {
+    pub(crate) network_id: u64,
+    pub(crate) entity: EntityRef,
+    replicated: Replicated,
+    /// entities without a transform are always in range
+    position: Option<Vector3<f32>>,
+}
+
+/// The replicated entities read by the server this tick, that each client gets a part of.
+pub(crate) struct Capture {
+    pub(crate) snapshot: u32,
+    state: Rc<WorldState>,
+    pub(crate) entities: Vec<CapturedEntity>,
+    /// positions of the viewers of each client
+    viewers: BTreeMap<u64, Vec<Vector3<f32>>>,
+}
+
+impl Capture {
+    /// The state of the given entities only.
+    pub(crate) fn state_for(&self, relevant: &BTreeSet<u64>) -> Rc<WorldState> {
+        match relevant.len() == self.state.len() {
+            true => self.state.clone(),
+            false => Rc::new(self.state.iter()
+                .filter(|(id, _)| relevant.contains(id))
+                .map(|(id, entity)| (*id, entity.clone()))
+                .collect()),
+        }
+    }
+}
+
+/// What was sent to a client, and what it acknowledged.
+struct ClientSnapshots {
+    /// snapshots sent and not acknowledged yet, oldest first
+    sent: VecDeque<(u32, Rc<WorldState>)>,
+    /// the last snapshot the client acknowledged : the next ones are sent relative to it
+    acked: Option<(u32, Rc<WorldState>)>,
+}
+
+/// Server side of the replication : gives network ids to the replicated entities, and builds the snapshots of each client.
+pub(crate) struct ReplicationServer {
+    pub(crate) types: NetworkedTypes,
+    next_network_id: u64,
+    next_snapshot: u32,
+    clients: BTreeMap<u64, ClientSnapshots>,
+    /// entities further than this from all the viewers of a client are not relevant to it
+    pub(crate) relevancy_distance: Option<f32>,
+}
+
+impl ReplicationServer {
+    pub(crate) fn new() -> ReplicationServer {
+        ReplicationServer {
+            types: NetworkedTypes::new(),
+            next_network_id: 1,
+            next_snapshot: 0,
+            clients: BTreeMap::new(),
+            relevancy_distance: None,
+        }
+    }
+
+    /// Read the networked components of all the replicated entities, giving ids to the new ones.
+    pub(crate) fn capture(&mut self, components: &mut ComponentTable) -> Capture {
+        let mut state = WorldState::new();
+        for (_, replicated) in iterate_over_component_mut!(components; EntityRef; Replicated) {
+            let id = *replicated.network_id.get_or_insert_with(|| {
+                self.next_network_id += 1;
+                self.next_network_id - 1
+            });
+            // entities without networked components are still spawned on the clients
+            state.insert(id, BTreeMap::new());
+        }
+        let positions: BTreeMap<u64, Vector3<f32>> = iterate_over_component!(components; EntityRef; Replicated, Transform)
+            .filter_map(|(_, replicated, transform)| replicated.network_id.map(|id| (id, transform.position())))
+            .collect();
+        let entities = iterate_over_component!(components; EntityRef; Replicated)
+            .filter_map(|(entity, replicated)| replicated.network_id.map(|network_id| CapturedEntity {
+                network_id,
+                entity,
+                replicated: *replicated,
+                position: positions.get(&network_id).copied(),
+            }))
+            .collect();
+        let mut viewers: BTreeMap<u64, Vec<Vector3<f32>>> = BTreeMap::new();
+        for (viewer, transform) in iterate_over_component!(components; Viewer, Transform) {
+            viewers.entry(viewer.client).or_default().push(transform.position());
+        }
+        for (component_id, networked) in self.types.types.iter() {
+            for (id, bytes) in (networked.read)(components) {
+                if let Some(entity) = state.get_mut(&id) {
+                    entity.insert(*component_id, bytes);
+                }
+            }
+        }
+        let snapshot = self.next_snapshot;
+        self.next_snapshot = self.next_snapshot.wrapping_add(1);
+        Capture { snapshot, state: Rc::new(state), entities, viewers }
+    }
+
+    /// Whether an entity is relevant to a client, from its flags and the distance to the viewers of the client.
+    /// Without relevancy distance, every entity is in range. With one, clients without viewer see nothing but the forced entities.
+    pub(crate) fn relevancy(&self, capture: &Capture, client: u64, entity: &CapturedEntity) -> Relevancy {
+        if let Some(relevant) = entity.replicated.forced_relevancy(client) {
+            return Relevancy::Forced(relevant);
+        }
+        let in_range = match (self.relevancy_distance, entity.position) {
+            (Some(distance), Some(position)) => capture.viewers.get(&client)
+                .is_some_and(|viewers| viewers.iter().any(|viewer| (position - viewer).magnitude2() <= distance * distance)),
+            _ => true,
+        };
+        Relevancy::InRange(in_range)
+    }
+
+    /// The snapshot message for a client, relative to what it acknowledged. None if the client is up to date.
+    pub(crate) fn snapshot_for(&mut self, client: u64, snapshot: u32, state: &Rc<WorldState>) -> Option<DefaultNetworkMessages> {
+        let snapshots = self.clients.entry(client).or_insert_with(|| ClientSnapshots { sent: VecDeque::new(), acked: None });
+        let empty = WorldState::new();
+        let (baseline_id, baseline) = match &snapshots.acked {
+            Some((id, baseline)) => (Some(*id), baseline.as_ref()),
+            None => (None, &empty),
+        };
+        let despawned: Vec<u64> = baseline.keys().filter(|id| !state.contains_key(id)).copied().collect();
+        let mut entities = Vec::new();
+        for (id, entity) in state.iter() {
+            let old = baseline.get(id);
+            let components: Vec<(u16, ComponentState)> = entity.iter()
+                .filter(|(component_id, bytes)| old.and_then(|old| old.get(component_id)) != Some(bytes))
+                .map(|(component_id, bytes)| (*component_id, encode(old.and_then(|old| old.get(component_id)), bytes)))
+                .collect();
+            let removed: Vec<u16> = old.iter()
+                .flat_map(|old| old.keys())
+                .filter(|component_id| !entity.contains_key(component_id))
+                .copied()
+                .collect();
+            // new entities are always sent, to be spawned even without components
+            if old.is_none() || !components.is_empty() || !removed.is_empty() {
+                entities.push(EntityState { network_id: *id, components, removed });
+            }
+        }
+        if entities.is_empty() && despawned.is_empty() {
+            return None;
+        }
+        snapshots.sent.push_back((snapshot, state.clone()));
+        if snapshots.sent.len() > MAX_SNAPSHOT_HISTORY {
+            // the client did not ack anything for too long, it may not have the baseline anymore : start again from nothing
+            snapshots.sent.pop_front();
+            snapshots.acked = None;
+        }
+        Some(DefaultNetworkMessages::Snapshot(snapshot, baseline_id, entities, despawned))
+    }
+
+    /// The client acknowledged a snapshot : the next ones will be relative to it.
+    pub(crate) fn acknowledge(&mut self, client: u64, snapshot: u32) {
+        let Some(snapshots) = self.clients.get_mut(&client) else {
+            return;
+        };
+        // acks of older snapshots are not in the sent ones anymore
+        let Some(index) = snapshots.sent.iter().position(|(id, _)| *id == snapshot) else {
+            return;
+        };
+        snapshots.acked = snapshots.sent.drain(..=index).last();
+    }
+
+    pub(crate) fn forget(&mut self, client: u64) {
+        self.clients.remove(&client);
+    }
+}
+
+/// A mirrored entity appeared or disappeared on the client, as it became relevant to it or not.
+pub(crate) enum ReplicationEvent {
+    /// the entity was created, with its networked components
+    Spawned(u64, EntityRef),
+    /// the entity is about to be destroyed
+    Despawned(u64, EntityRef),
+}
+
+/// Client side of the replication : rebuilds the snapshots and mirrors them in the components.
+pub(crate) struct ReplicationClient {
+    pub(crate) types: NetworkedTypes,
+    /// snapshots received, that the server can use as baseline
+    received: BTreeMap<u32, Rc<WorldState>>,
+    /// the snapshot mirrored in the components
+    current: Option<(u32, Rc<WorldState>)>,
+    /// the mirrored entities, by network id
+    entities: BTreeMap<u64, EntityRef>,
+}
+
+impl ReplicationClient {
+    pub(crate) fn new() -> ReplicationClient {
+        ReplicationClient {
+            types: NetworkedTypes::new(),
+            received: BTreeMap::new(),
+            current: None,
+            entities: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn entity(&self, network_id: u64) -> Option<EntityRef> {
+        self.entities.get(&network_id).copied()
+    }
+
+    /// Read a snapshot and mirror it in the components. Returns the snapshot to acknowledge, if it was used.
+    pub(crate) fn receive<F>(&mut self, snapshot: u32, baseline: Option<u32>, entities: Vec<EntityState>, despawned: Vec<u64>, components: &mut ComponentTable, on_event: F) -> Option<u32>
+        where F: FnMut(ReplicationEvent, &mut ComponentTable)
+    {
+        if self.current.as_ref().is_some_and(|(current, _)| *current >= snapshot) {
+            return None; // late
+        }
+        let mut state = match baseline {
+            Some(baseline) => self.received.get(&baseline)?.as_ref().clone(),
+            None => WorldState::new(),
+        };
+        for id in despawned {
+            state.remove(&id);
+        }
+        for entity in entities {
+            let old = state.remove(&entity.network_id).unwrap_or_default();
+            let mut new = old.clone();
+            for component_id in entity.removed {
+                new.remove(&component_id);
+            }
+            for (component_id, component) in entity.components {
+                match decode(old.get(&component_id), &component) {
+                    Some(bytes) => new.insert(component_id, bytes),
+                    None => {
+                        println!("[NETWORK CLIENT] -> Snapshot {snapshot} does not match its baseline, ignoring it.");
+                        return None;
+                    },
+                };
+            }
+            state.insert(entity.network_id, new);
+        }
+        let state = Rc::new(state);
+        self.apply(&state, components, on_event);
+        // the server never goes back to an older baseline than the one it just used
+        self.received.retain(|id, _| baseline.is_none_or(|baseline| *id >= baseline));
+        self.received.insert(snapshot, state.clone());
+        // the baseline is kept on top of the history
+        while self.received.len() > MAX_SNAPSHOT_HISTORY + 1 {
+            self.received.pop_first();
+        }
+        self.current = Some((snapshot, state));
+        Some(snapshot)
+    }
+
+    /// Spawn, change and despawn the mirrored entities to match the state.
+    fn apply<F>(&mut self, state: &WorldState, components: &mut ComponentTable, mut on_event: F)
+        where F: FnMut(ReplicationEvent, &mut ComponentTable)
+    {
+        let empty = WorldState::new();
+        let current = self.current.as_ref().map(|(_, current)| current.as_ref()).unwrap_or(&empty);
+        let gone: Vec<u64> = self.entities.keys().filter(|id| !state.contains_key(id)).copied().collect();
+        for id in gone {
+            if let Some(entity) = self.entities.remove(&id) {
+                on_event(ReplicationEvent::Despawned(id, entity), components);
+                components.destroy_entity(entity);
+            }
+        }
+        let mut spawned = Vec::new();
+        for (id, entity_state) in state.iter() {
+            let entity = *self.entities.entry(*id).or_insert_with(|| {
+                let entity = create_entity!(components; Replicated { network_id: Some(*id), ..Replicated::new() });
+                spawned.push((*id, entity));
+                entity
+            });
+            let old = current.get(id);
+            for (component_id, networked) in self.types.types.iter() {
+                let old = old.and_then(|old| old.get(component_id));
+                match entity_state.get(component_id) {
+                    Some(bytes) if old != Some(bytes) => if let Err(e) = (networked.write)(components, entity, bytes) {
+                        println!("[NETWORK CLIENT] -> Unable to deserialize networked component {component_id} : {e:?}.");
+                    },
+                    Some(_) => {},
+                    None if old.is_some() => (networked.remove)(components, entity),
+                    None => {},
+                }
+            }
+        }
+        // told once the components are there
+        for (id, entity) in spawned {
+            on_event(ReplicationEvent::Spawned(id, entity), components);
+        }
+    }
+
+    /// The connection ended : the mirrored entities are destroyed.
+    pub(crate) fn reset<F>(&mut self, components: &mut ComponentTable, mut on_event: F)
+        where F: FnMut(ReplicationEvent, &mut ComponentTable)
+    {
+        for (id, entity) in std::mem::take(&mut self.entities) {
+            on_event(ReplicationEvent::Despawned(id, entity), components);
+            components.destroy_entity(entity);
+        }
+        self.received.clear();
+        self.current = None;
+    }
+}
@@ -0,0 +1,573 @@
+use std::{collections::BTreeMap, net::{SocketAddr, ToSocketAddrs}};
+
+use foundry::*;
+
+use crate::{NetworkSerializable, NetworkUnserializeError, ByteWriter, ByteReader, guarded_schema_hash};
+
+use super::{transport::{ServerTransport, OsServerTransport}, fragments::MAX_DATAGRAM_SIZE};
+
+/// Default number of ticks between reading a local input and using it. Hides the latency of the peers, at the cost of responsiveness.
+pub const DEFAULT_INPUT_DELAY: u32 = 2;
+/// Default number of ticks we can simulate ahead of the inputs of the peers, predicting them. Further than that, we wait for them.
+pub const DEFAULT_MAX_PREDICTION: u32 = 8;
+/// Default number of ticks between two state checksums sent to the peers.
+pub const DEFAULT_CHECKSUM_INTERVAL: u32 = 30;
+/// Ticks simulated in one update at most, so a slow update does not make the next ones slower.
+const MAX_TICKS_PER_UPDATE: u32 = 4;
+/// Time in seconds between two sends of our inputs when no tick ran, so a lost datagram does not leave stalled peers waiting forever.
+const RESEND_INTERVAL: f64 = 0.05;
+/// Inputs further than this many ticks ahead of the next one we need are not trusted : they are too far to be genuine.
+const MAX_INPUTS_AHEAD: u32 = 1024;
+/// Checksums kept waiting for the one of the other side. A peer further behind than that is not checked.
+const MAX_KEPT_CHECKSUMS: usize = 64;
+
+/// What the peers of a rollback session send each other.
+#[derive(NetworkSerializable)]
+enum RollbackMessage {
+    /// tick of the first input, inputs of the following ticks, next tick we need from the receiver
+    #[network(id = 0)]
+    Inputs(u32, Vec<Vec<u8>>, u32),
+    /// a tick, and the checksum of the state before it
+    #[network(id = 1)]
+    Checksum(u32, u64),
+}
+
+/// A player of a rollback session. Its index in the list of players is the same on all peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollbackPlayer {
+    /// the player of this peer
+    Local,
+    /// the player of another peer, at the given address
+    Remote(SocketAddr),
+}
+
+/// The game run by a rollback session.
+pub trait RollbackHandler {
+    type Input: NetworkSerializable + Clone + PartialEq + Default;
+    /// The input of the local player for this tick. It is used input delay ticks later.
+    fn local_input(&mut self, tick: u32, components: &mut ComponentTable) -> Self::Input;
+    /// Move the game forward of one tick, with the input of each player by index. Inputs of remote players may be predicted.
+    /// Must be deterministic : a tick may run several times, after going back to the saved components.
+    fn step(&mut self, tick: u32, inputs: &[Self::Input], delta: f32, components: &mut ComponentTable);
+    /// Called when the state of a peer differs from ours before the given tick : the game desynchronized. Does nothing by default.
+    fn on_desync(&mut self, _tick: u32, _player: usize, _components: &mut ComponentTable) {}
+}
+
+/// Saves and restores a component type, without knowing it.
+struct RollbackComponent {
+    save: Box<dyn Fn(&mut ComponentTable) -> Vec<(EntityRef, Vec<u8>)>>,
+    restore: Box<dyn Fn(&mut ComponentTable, &[(EntityRef, Vec<u8>)])>,
+}
+
+/// The serialized components, by registered type.
+type SavedState = Vec<Vec<(EntityRef, Vec<u8>)>>;
+
+fn checksum(state: &SavedState) -> u64 {
+    // fnv-1a, the same on every platform
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in state.iter().flatten().flat_map(|(_, bytes)| bytes.iter()) {
+        hash = (hash ^ *byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Peer to peer session of a game with rollback : peers only exchange their inputs, and everyone runs the whole game.
+/// Missing remote inputs are predicted (the last one is repeated), and when the real ones differ, the registered components
+/// go back to the tick of the difference, and the game runs again up to now. H is the game, T the transport of the datagrams.
+pub struct RollbackSession<H: RollbackHandler, T: ServerTransport = OsServerTransport> {
+    handler: H,
+    transport: T,
+    players: Vec<RollbackPlayer>,
+    local_player: usize,
+    tick_delta: f32,
+    /// time since the session started, in seconds
+    time: f64,
+    /// time not simulated yet, in seconds
+    accumulator: f64,
+    /// time we last sent our inputs at
+    last_sent: f64,
+    input_delay: u32,
+    max_prediction: u32,
+    checksum_interval: u32,
+    components: Vec<RollbackComponent>,
+    /// next tick to simulate
+    tick: u32,
+    /// inputs we know for sure, by player then tick
+    inputs: Vec<BTreeMap<u32, H::Input>>,
+    /// next tick we don't have the input of, by player
+    confirmed: Vec<u32>,
+    /// next tick each peer needs from us, by player
+    acked: Vec<u32>,
+    /// inputs used to simulate each tick, to find the wrong predictions
+    used: BTreeMap<u32, Vec<H::Input>>,
+    /// components before each tick that may be rolled back to
+    states: BTreeMap<u32, SavedState>,
+    /// first tick to simulate again, because of a wrong prediction
+    rollback_from: Option<u32>,
+    local_checksums: BTreeMap<u32, u64>,
+    /// checksums of the peers, by tick then player
+    remote_checksums: BTreeMap<u32, Vec<(usize, u64)>>,
+    /// next tick that may need a checksum
+    checked: u32,
+    rollbacks: u64,
+    rolled_back_ticks: u64,
+    stalls: u64,
+    desyncs: u64,
+}
+
+impl<H: RollbackHandler> RollbackSession<H> {
+    /// Create a session talking to the peers from the given address. Every peer must have the same players, in the same order.
+    pub fn new<A: ToSocketAddrs>(handler: H, addr: A, players: Vec<RollbackPlayer>, tick_delta: f32) -> std::io::Result<RollbackSession<H>> {
+        Ok(RollbackSession::with_transport(handler, OsServerTransport::bind(addr)?, players, tick_delta))
+    }
+}
+
+impl<H: RollbackHandler, T: ServerTransport> RollbackSession<H, T> {
+    /// Create a session that sends its datagrams through the given transport. Ticks are tick_delta seconds long.
+    pub fn with_transport(handler: H, transport: T, players: Vec<RollbackPlayer>, tick_delta: f32) -> RollbackSession<H, T> {
+        let local_player = players.iter().position(|player| *player == RollbackPlayer::Local).unwrap_or_else(|| {
+            println!("[NETWORK ROLLBACK] -> No local player in the session, our inputs go nowhere.");
+            players.len()
+        });
+        let player_count = players.len();
+        RollbackSession {
+            handler,
+            transport,
+            players,
+            local_player,
+            tick_delta,
+            time: 0.,
+            accumulator: 0.,
+            last_sent: 0.,
+            input_delay: DEFAULT_INPUT_DELAY,
+            max_prediction: DEFAULT_MAX_PREDICTION,
+            checksum_interval: DEFAULT_CHECKSUM_INTERVAL,
+            components: Vec::new(),
+            tick: 0,
+            inputs: (0..player_count).map(|_| BTreeMap::new()).collect(),
+            confirmed: vec![0; player_count],
+            acked: vec![0; player_count],
+            used: BTreeMap::new(),
+            states: BTreeMap::new(),
+            rollback_from: None,
+            local_checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            checked: 0,
+            rollbacks: 0,
+            rolled_back_ticks: 0,
+            stalls: 0,
+            desyncs: 0,
+        }
+    }
+
+    /// Save the component C on each tick, and restore it when rolling back. Every component the game changes must be registered,
+    /// on all peers in the same order. Only the entities that have it are restored : entities created or destroyed during the
+    /// ticks rolled back are not.
+    pub fn with_component<C: NetworkSerializable + 'static>(mut self) -> RollbackSession<H, T> {
+        self.components.push(RollbackComponent {
+            save: Box::new(|components| {
+                iterate_over_component!(components; EntityRef; C)
+                    .map(|(entity, component)| (entity, component.serialize()))
+                    .collect()
+            }),
+            restore: Box::new(|components, saved| {
+                for (entity, bytes) in saved {
+                    match C::deserialize(bytes) {
+                        Ok(component) => components.add_component(*entity, component),
+                        Err(e) => println!("[NETWORK ROLLBACK] -> Unable to restore a component : {e:?}."),
+                    }
+                }
+            }),
+        });
+        self
+    }
+
+    /// Set the number of ticks between reading a local input and using it. Must be the same on all peers.
+    pub fn with_input_delay(mut self, input_delay: u32) -> RollbackSession<H, T> {
+        self.input_delay = input_delay;
+        self
+    }
+
+    /// Set how many ticks we can run ahead of the inputs of the peers before waiting for them.
+    pub fn with_max_prediction(mut self, max_prediction: u32) -> RollbackSession<H, T> {
+        self.max_prediction = max_prediction.max(1);
+        self
+    }
+
+    /// Set how many ticks there are between two checksums of the state sent to the peers, to detect desyncs.
+    pub fn with_checksum_interval(mut self, checksum_interval: u32) -> RollbackSession<H, T> {
+        self.checksum_interval = checksum_interval.max(1);
+        self
+    }
+
+    /// Next tick to simulate.
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    /// Ticks before this one have the real inputs of all the players, and will not be rolled back.
+    pub fn confirmed_tick(&self) -> u32 {
+        // nobody plays before the input delay
+        self.confirmed.iter().copied().min().unwrap_or(self.tick).max(self.input_delay).min(self.tick)
+    }
+
+    pub fn local_player(&self) -> usize {
+        self.local_player
+    }
+
+    /// Number of times we went back because a prediction was wrong.
+    pub fn rollbacks(&self) -> u64 {
+        self.rollbacks
+    }
+
+    /// Number of ticks simulated again because of the rollbacks.
+    pub fn rolled_back_ticks(&self) -> u64 {
+        self.rolled_back_ticks
+    }
+
+    /// Number of ticks we waited for the inputs of the peers, being too far ahead of them.
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+
+    /// Number of times a peer had another state than ours.
+    pub fn desyncs(&self) -> u64 {
+        self.desyncs
+    }
+
+    /// The input of a player for a tick : the real one if we have it, the last one we know otherwise.
+    fn input_for(&self, player: usize, tick: u32) -> H::Input {
+        // before the input delay, nobody has played yet
+        if tick < self.input_delay {
+            return H::Input::default();
+        }
+        self.inputs[player].range(..=tick).next_back().map(|(_, input)| input.clone()).unwrap_or_default()
+    }
+
+    /// An input of a player arrived. If the tick was simulated with another prediction, it has to run again.
+    fn confirm(&mut self, player: usize, tick: u32, input: H::Input) {
+        // the ticks before the input delay are played without input
+        self.confirmed[player] = self.confirmed[player].max(self.input_delay);
+        // already known, or too far ahead to be genuine. Ticks may come from a peer, so they can be anything.
+        if tick.wrapping_sub(self.confirmed[player]) >= MAX_INPUTS_AHEAD {
+            return;
+        }
+        if self.used.get(&tick).is_some_and(|used| used[player] != input) {
+            self.rollback_from = Some(self.rollback_from.map_or(tick, |from| from.min(tick)));
+        }
+        self.inputs[player].insert(tick, input);
+        while self.inputs[player].contains_key(&self.confirmed[player]) {
+            self.confirmed[player] += 1;
+        }
+    }
+
+    fn receive(&mut self, components: &mut ComponentTable) {
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (size, from) = match self.transport.recv_from(&mut buffer) {
+                Ok(Some(received)) => received,
+                Ok(None) => break,
+                Err(e) => {
+                    println!("[NETWORK ROLLBACK] -> Error while receiving inputs : {e}.");
+                    break;
+                },
+            };
+            // the player is known by its address, not by what it says
+            let Some(player) = self.players.iter().position(|player| *player == RollbackPlayer::Remote(from)) else {
+                continue;
+            };
+            match RollbackMessage::deserialize(&buffer[..size]) {
+                Ok(RollbackMessage::Inputs(first_tick, inputs, ack)) => {
+                    self.acked[player] = self.acked[player].max(ack);
+                    for (index, input) in inputs.iter().enumerate() {
+                        match H::Input::deserialize(input) {
+                            Ok(input) => self.confirm(player, first_tick.wrapping_add(index as u32), input),
+                            Err(e) => println!("[NETWORK ROLLBACK] -> Unable to read an input of player {player} : {e:?}."),
+                        }
+                    }
+                },
+                Ok(RollbackMessage::Checksum(tick, checksum)) => {
+                    self.remote_checksums.entry(tick).or_default().push((player, checksum));
+                    self.compare_checksums(components);
+                },
+                Err(e) => println!("[NETWORK ROLLBACK] -> Unable to read a message of player {player} : {e:?}."),
+            }
+        }
+    }
+
+    fn save(&self, components: &mut ComponentTable) -> SavedState {
+        self.components.iter().map(|component| (component.save)(components)).collect()
+    }
+
+    fn restore(&self, state: &SavedState, components: &mut ComponentTable) {
+        for (component, saved) in self.components.iter().zip(state.iter()) {
+            (component.restore)(components, saved);
+        }
+    }
+
+    /// Save the components, and run a tick with the best inputs we have.
+    fn simulate(&mut self, tick: u32, components: &mut ComponentTable) {
+        let state = self.save(components);
+        self.states.insert(tick, state);
+        let inputs: Vec<H::Input> = (0..self.players.len()).map(|player| self.input_for(player, tick)).collect();
+        self.handler.step(tick, &inputs, self.tick_delta, components);
+        self.used.insert(tick, inputs);
+    }
+
+    /// Go back to the first wrong prediction, and run the ticks again up to now.
+    fn rollback(&mut self, components: &mut ComponentTable) {
+        let Some(from) = self.rollback_from.take() else {
+            return;
+        };
+        let Some(state) = self.states.get(&from) else {
+            println!("[NETWORK ROLLBACK] -> Unable to roll back to tick {from} : the state was not kept.");
+            return;
+        };
+        self.restore(state, components);
+        self.rollbacks += 1;
+        self.rolled_back_ticks += (self.tick - from) as u64;
+        for tick in from..self.tick {
+            self.simulate(tick, components);
+        }
+    }
+
+    /// Send our inputs the peers don't have yet, and tell them what we need from them.
+    fn send_inputs(&mut self) {
+        self.last_sent = self.time;
+        let Some(local_inputs) = self.inputs.get(self.local_player) else {
+            return;
+        };
+        for (player, peer) in self.players.iter().enumerate() {
+            let RollbackPlayer::Remote(addr) = peer else {
+                continue;
+            };
+            let first_tick = self.acked[player].max(self.input_delay);
+            let mut inputs: Vec<Vec<u8>> = local_inputs.range(first_tick..).map(|(_, input)| input.serialize()).collect();
+            let mut message = RollbackMessage::Inputs(first_tick, inputs.clone(), self.confirmed[player]);
+            // the newest inputs matter most, the oldest will be sent again
+            while message.size() > MAX_DATAGRAM_SIZE && inputs.len() > 1 {
+                inputs.pop();
+                message = RollbackMessage::Inputs(first_tick, inputs.clone(), self.confirmed[player]);
+            }
+            if let Err(e) = self.transport.send_to(&message.serialize(), *addr) {
+                println!("[NETWORK ROLLBACK] -> Error while sending inputs to player {player} : {e}.");
+            }
+        }
+    }
+
+    fn send_to_peers(&mut self, message: &RollbackMessage) {
+        let bytes = message.serialize();
+        for peer in self.players.iter() {
+            if let RollbackPlayer::Remote(addr) = peer {
+                if let Err(e) = self.transport.send_to(&bytes, *addr) {
+                    println!("[NETWORK ROLLBACK] -> Error while sending to {addr} : {e}.");
+                }
+            }
+        }
+    }
+
+    /// Send the checksums of the states that will not change anymore, and forget what can't be rolled back to.
+    fn check_confirmed(&mut self, components: &mut ComponentTable) {
+        // the state before a tick is final once all the inputs before it are known
+        let confirmed = self.confirmed_tick();
+        for tick in self.checked..confirmed {
+            if tick % self.checksum_interval != 0 {
+                continue;
+            }
+            if let Some(state) = self.states.get(&tick) {
+                let sum = checksum(state);
+                self.local_checksums.insert(tick, sum);
+                self.send_to_peers(&RollbackMessage::Checksum(tick, sum));
+            }
+        }
+        self.checked = self.checked.max(confirmed);
+        self.compare_checksums(components);
+        // rollbacks never go before the confirmed tick
+        self.states = self.states.split_off(&confirmed);
+        self.used = self.used.split_off(&confirmed);
+        // the inputs before are only needed to predict, and to be sent again to the peers that did not get them
+        let oldest_needed = self.players.iter().zip(self.acked.iter())
+            .filter(|(player, _)| **player != RollbackPlayer::Local)
+            .map(|(_, acked)| *acked)
+            .fold(confirmed.saturating_sub(1), u32::min);
+        for inputs in self.inputs.iter_mut() {
+            *inputs = inputs.split_off(&oldest_needed);
+        }
+    }
+
+    fn compare_checksums(&mut self, components: &mut ComponentTable) {
+        let ticks: Vec<u32> = self.remote_checksums.keys().filter(|tick| self.local_checksums.contains_key(tick)).copied().collect();
+        for tick in ticks {
+            let local = self.local_checksums[&tick];
+            for (player, remote) in self.remote_checksums.remove(&tick).unwrap_or_default() {
+                if remote != local {
+                    println!("[NETWORK ROLLBACK] -> Desync with player {player} at tick {tick}.");
+                    self.desyncs += 1;
+                    self.handler.on_desync(tick, player, components);
+                }
+            }
+        }
+        while self.local_checksums.len() > MAX_KEPT_CHECKSUMS {
+            self.local_checksums.pop_first();
+        }
+        while self.remote_checksums.len() > MAX_KEPT_CHECKSUMS {
+            self.remote_checksums.pop_first();
+        }
+    }
+}
+
+impl<H: RollbackHandler + 'static, T: ServerTransport> Updatable for RollbackSession<H, T> {
+    fn update(&mut self, components: &mut ComponentTable, delta: f32, _user_data: &mut dyn std::any::Any) {
+        self.time += delta as f64;
+        self.accumulator += delta as f64;
+        self.transport.update(self.time);
+        self.receive(components);
+        self.rollback(components);
+        let mut ticks = 0;
+        while self.accumulator >= self.tick_delta as f64 && ticks < MAX_TICKS_PER_UPDATE {
+            if self.tick >= self.confirmed_tick() + self.max_prediction {
+                // too far ahead of a peer : wait for it
+                self.stalls += 1;
+                self.accumulator = self.accumulator.min(self.tick_delta as f64);
+                break;
+            }
+            self.accumulator -= self.tick_delta as f64;
+            ticks += 1;
+            if self.local_player < self.players.len() {
+                let input = self.handler.local_input(self.tick, components);
+                let tick = self.tick + self.input_delay;
+                self.confirm(self.local_player, tick, input);
+            }
+            let tick = self.tick;
+            self.simulate(tick, components);
+            self.tick += 1;
+        }
+        // stalled peers keep sending what they have, in case the other one lost it
+        if ticks > 0 || self.time - self.last_sent >= RESEND_INTERVAL {
+            self.send_inputs();
+        }
+        self.check_confirmed(components);
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::{LoopbackNetwork, LoopbackServerTransport};
+
+    const TICK: f32 = 0.1;
+
+    /// Plays 1 each tick, and remembers what it was asked to do.
+    #[derive(Default)]
+    struct Recorder {
+        /// ticks run, with the inputs of each player
+        steps: Vec<(u32, Vec<u8>)>,
+        desyncs: Vec<(u32, usize)>,
+    }
+
+    impl RollbackHandler for Recorder {
+        type Input = u8;
+        fn local_input(&mut self, _tick: u32, _components: &mut ComponentTable) -> u8 {
+            1
+        }
+        fn step(&mut self, tick: u32, inputs: &[u8], _delta: f32, _components: &mut ComponentTable) {
+            self.steps.push((tick, inputs.to_vec()));
+        }
+        fn on_desync(&mut self, tick: u32, player: usize, _components: &mut ComponentTable) {
+            self.desyncs.push((tick, player));
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)
+    }
+
+    /// A session playing against the returned transport, without input delay.
+    fn session(network: &LoopbackNetwork) -> (RollbackSession<Recorder, LoopbackServerTransport>, LoopbackServerTransport) {
+        let players = vec![RollbackPlayer::Local, RollbackPlayer::Remote(addr(7001))];
+        let session = RollbackSession::with_transport(Recorder::default(), network.listen(addr(7000)).unwrap(), players, TICK)
+            .with_input_delay(0);
+        (session, network.listen(addr(7001)).unwrap())
+    }
+
+    fn send(peer: &mut LoopbackServerTransport, message: RollbackMessage) {
+        peer.send_to(&message.serialize(), addr(7000)).unwrap();
+    }
+
+    fn inputs(first_tick: u32, inputs: &[u8]) -> RollbackMessage {
+        RollbackMessage::Inputs(first_tick, inputs.iter().map(|input| input.serialize()).collect(), 0)
+    }
+
+    /// Checksums the session sent to the peer.
+    fn checksums(peer: &mut LoopbackServerTransport) -> Vec<(u32, u64)> {
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+        let mut result = Vec::new();
+        while let Some((size, _)) = peer.recv_from(&mut buffer).unwrap() {
+            if let Ok(RollbackMessage::Checksum(tick, sum)) = RollbackMessage::deserialize(&buffer[..size]) {
+                result.push((tick, sum));
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn wrong_prediction_rolls_back_to_its_tick() {
+        let network = LoopbackNetwork::new();
+        let (mut session, mut peer) = session(&network);
+        let mut world = World::new();
+        for _ in 0..5 {
+            session.update(&mut world.components, TICK, &mut ());
+        }
+        // nothing from the peer yet : its input is predicted
+        assert_eq!(session.handler.steps, (0..5).map(|tick| (tick, vec![1, 0])).collect::<Vec<_>>());
+
+        // the first two predictions were right, not the next ones
+        send(&mut peer, inputs(0, &[0, 0, 7, 7]));
+        session.update(&mut world.components, TICK, &mut ());
+        assert_eq!((session.rollbacks(), session.rolled_back_ticks()), (1, 3));
+        assert_eq!(session.handler.steps[5..], [(2, vec![1, 7]), (3, vec![1, 7]), (4, vec![1, 7]), (5, vec![1, 7])]);
+
+        // right predictions change nothing
+        send(&mut peer, inputs(4, &[7, 7]));
+        session.update(&mut world.components, TICK, &mut ());
+        assert_eq!(session.rollbacks(), 1);
+        assert_eq!(session.handler.steps.last(), Some(&(6, vec![1, 7])));
+    }
+
+    #[test]
+    fn checksum_mismatch_is_a_desync() {
+        let network = LoopbackNetwork::new();
+        let (mut session, mut peer) = session(&network);
+        let mut world = World::new();
+        for _ in 0..4 {
+            session.update(&mut world.components, TICK, &mut ());
+        }
+        // once the first ticks are confirmed, their checksum is sent
+        send(&mut peer, inputs(0, &[0, 0, 0, 0]));
+        session.update(&mut world.components, TICK, &mut ());
+        let sent = checksums(&mut peer);
+        assert_eq!(sent.len(), 1);
+        let (tick, sum) = sent[0];
+        assert_eq!(tick, 0);
+
+        // same state on the other side
+        send(&mut peer, RollbackMessage::Checksum(tick, sum));
+        session.update(&mut world.components, TICK, &mut ());
+        assert_eq!(session.desyncs(), 0);
+        assert!(session.handler.desyncs.is_empty());
+
+        send(&mut peer, RollbackMessage::Checksum(tick, sum ^ 1));
+        session.update(&mut world.components, TICK, &mut ());
+        assert_eq!(session.desyncs(), 1);
+        assert_eq!(session.handler.desyncs, [(0, 1)]);
+    }
+}
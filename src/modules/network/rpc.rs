@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap};
+
+use foundry::*;
+
+use crate::{NetworkSerializable, NetworkUnserializeError, ByteWriter, ByteReader, guarded_schema_hash, DefaultNetworkMessages};
+
+/// Default time in seconds we wait for the answer to a call.
+pub const DEFAULT_RPC_TIMEOUT: f64 = 5.;
+
+/// A request that can be sent to the peer, which answers with a response.
+/// Both sides must give the same method id to the same request : ids are explicit, so they don't depend on the build.
+pub trait Rpc: NetworkSerializable {
+    type Response: NetworkSerializable;
+    const METHOD: u32;
+}
+
+/// Why a call got no response.
+#[derive(NetworkSerializable)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcError {
+    /// The peer did not answer in time.
+    #[network(id = 0)]
+    TimedOut,
+    /// The peer has no responder for this method.
+    #[network(id = 1)]
+    UnknownMethod,
+    /// The peer could not read the request.
+    #[network(id = 2)]
+    InvalidRequest,
+    /// We could not read the response.
+    #[network(id = 3)]
+    InvalidResponse,
+    /// The connection ended before the answer came, or there was no connection.
+    #[network(id = 4)]
+    Disconnected,
+}
+
+type Callback = Box<dyn FnOnce(Result<Vec<u8>, RpcError>, &mut ComponentTable)>;
+/// Reads a request sent by the given peer, and builds the response.
+type Responder = Box<dyn FnMut(u64, &[u8], &mut ComponentTable) -> Result<Vec<u8>, RpcError>>;
+
+/// A call to make on the peer : the request, and what to do with the answer.
+/// The callback runs during the update of the server or client that made the call, with the components.
+pub struct RpcCall {
+    method: u32,
+    request: Vec<u8>,
+    timeout: f64,
+    callback: Callback,
+}
+
+impl RpcCall {
+    pub fn new<R, F>(request: &R, callback: F) -> RpcCall
+        where R: Rpc, F: FnOnce(Result<R::Response, RpcError>, &mut ComponentTable) + 'static
+    {
+        RpcCall {
+            method: R::METHOD,
+            request: request.serialize(),
+            timeout: DEFAULT_RPC_TIMEOUT,
+            callback: Box::new(move |result, components| {
+                let result = result.and_then(|response| R::Response::deserialize(&response).map_err(|_| RpcError::InvalidResponse));
+                callback(result, components);
+            }),
+        }
+    }
+
+    /// Set how long we wait for the answer before calling back with `RpcError::TimedOut` (in seconds).
+    pub fn with_timeout(mut self, timeout: f64) -> RpcCall {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Call waiting for its answer.
+struct InFlight {
+    /// the only one allowed to answer
+    peer: u64,
+    deadline: f64,
+    callback: Callback,
+}
+
+/// Calls made to the peers and waiting for an answer, and the responders to the calls of the peers.
+/// Calls are identified by an id given by the caller, sent back with the answer.
+pub(crate) struct RpcEndpoint {
+    next_call: u32,
+    in_flight: BTreeMap<u32, InFlight>,
+    responders: HashMap<u32, Responder>,
+}
+
+impl RpcEndpoint {
+    pub(crate) fn new() -> RpcEndpoint {
+        RpcEndpoint {
+            next_call: 0,
+            in_flight: BTreeMap::new(),
+            responders: HashMap::new(),
+        }
+    }
+
+    /// Answer the calls to R with the responder, which gets the id of the caller.
+    pub(crate) fn register<R, F>(&mut self, mut responder: F)
+        where R: Rpc, F: FnMut(u64, R, &mut ComponentTable) -> R::Response + 'static
+    {
+        self.responders.insert(R::METHOD, Box::new(move |peer, request, components| {
+            let request = R::deserialize(request).map_err(|_| RpcError::InvalidRequest)?;
+            Ok(responder(peer, request, components).serialize())
+        }));
+    }
+
+    /// Start a call to the peer, and returns the request to send it.
+    pub(crate) fn start(&mut self, call: RpcCall, peer: u64, time: f64) -> DefaultNetworkMessages {
+        let id = self.next_call;
+        self.next_call = self.next_call.wrapping_add(1);
+        self.in_flight.insert(id, InFlight {
+            peer,
+            deadline: time + call.timeout,
+            callback: call.callback,
+        });
+        DefaultNetworkMessages::RpcRequest(id, call.method, call.request)
+    }
+
+    /// Answer a call of the peer, and returns the answer to send back.
+    pub(crate) fn respond(&mut self, peer: u64, id: u32, method: u32, request: &[u8], components: &mut ComponentTable) -> DefaultNetworkMessages {
+        let result = match self.responders.get_mut(&method) {
+            Some(responder) => responder(peer, request, components),
+            None => Err(RpcError::UnknownMethod),
+        };
+        match result {
+            Ok(response) => DefaultNetworkMessages::RpcResponse(id, response),
+            Err(error) => DefaultNetworkMessages::RpcFailed(id, error),
+        }
+    }
+
+    /// The peer answered one of our calls : run its callback.
+    pub(crate) fn complete(&mut self, peer: u64, id: u32, result: Result<Vec<u8>, RpcError>, components: &mut ComponentTable) {
+        match self.in_flight.get(&id) {
+            Some(call) if call.peer == peer => {},
+            _ => return, // timed out already, or not a call to this peer
+        }
+        if let Some(call) = self.in_flight.remove(&id) {
+            (call.callback)(result, components);
+        }
+    }
+
+    /// Call back the calls that waited too long.
+    pub(crate) fn expire(&mut self, time: f64, components: &mut ComponentTable) {
+        let expired: Vec<u32> = self.in_flight.iter()
+            .filter(|(_, call)| time > call.deadline)
+            .map(|(id, _)| *id)
+            .collect();
+        self.fail(expired, RpcError::TimedOut, components);
+    }
+
+    /// The connection with the peer ended : its calls will never be answered.
+    pub(crate) fn disconnected(&mut self, peer: u64, components: &mut ComponentTable) {
+        let lost: Vec<u32> = self.in_flight.iter()
+            .filter(|(_, call)| call.peer == peer)
+            .map(|(id, _)| *id)
+            .collect();
+        self.fail(lost, RpcError::Disconnected, components);
+    }
+
+    fn fail(&mut self, calls: Vec<u32>, error: RpcError, components: &mut ComponentTable) {
+        for id in calls {
+            if let Some(call) = self.in_flight.remove(&id) {
+                (call.callback)(Err(error), components);
+            }
+        }
+    }
+}
@@ -1,19 +1,177 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use cgmath::{Matrix2, Matrix3, Matrix4, Quaternion, Vector2, Vector3, Vector4};
+
+use crate::{Color, Transform};
+
+/// Longest collection of zero sized elements accepted : they take no data, so the received data can't bound them.
+const MAX_ZERO_SIZED_LENGTH: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NetworkUnserializeError {
     InvalidId,
     IncompleteData,
+    /// The bytes were all there, but do not represent a valid value (bad utf8, bad tag, ...)
+    InvalidData,
+}
+
+/// Anything bytes can be written to. Serialization writes directly in it, without intermediate allocations.
+pub trait ByteWriter {
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+impl ByteWriter for Vec<u8> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+/// Cursor over a byte slice, that deserialization reads from.
+/// Each read moves the cursor forward, so values can be read one after the other.
+pub struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> ByteReader<'a> {
+        ByteReader {
+            data,
+            position: 0,
+        }
+    }
+
+    /// How many bytes have been read since the creation of the reader.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// How many bytes are left to read.
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Read the given amount of bytes, without copying them.
+    pub fn read_bytes(&mut self, amount: usize) -> Result<&'a [u8], NetworkUnserializeError> {
+        match amount <= self.remaining() {
+            true => {
+                let result = &self.data[self.position..self.position + amount];
+                self.position += amount;
+                Ok(result)
+            },
+            false => Err(NetworkUnserializeError::IncompleteData),
+        }
+    }
+
+    /// Read a fixed amount of bytes.
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], NetworkUnserializeError> {
+        // can't fail, the slice is of size N
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    /// Read a u64 length prefix, as written before strings, vectors and maps.
+    fn read_length(&mut self) -> Result<usize, NetworkUnserializeError> {
+        match usize::try_from(u64::from_le_bytes(self.read_array()?)) {
+            Ok(length) => Ok(length),
+            Err(_) => Err(NetworkUnserializeError::InvalidData),
+        }
+    }
+
+    /// Read the length prefix of a collection of T, and check it is not longer than the remaining data.
+    /// The length is sent by the peer and can't be trusted : as elements take at least a byte,
+    /// this bounds the number of elements by the size of the received data. Zero sized elements take no data,
+    /// so their collections are bounded by `MAX_ZERO_SIZED_LENGTH` instead. See `capacity_for` for the allocation.
+    fn read_length_checked<T>(&mut self) -> Result<usize, NetworkUnserializeError> {
+        let length = self.read_length()?;
+        match std::mem::size_of::<T>() {
+            0 if length <= MAX_ZERO_SIZED_LENGTH => Ok(length),
+            0 => Err(NetworkUnserializeError::InvalidData),
+            _ if length <= self.remaining() => Ok(length),
+            _ => Err(NetworkUnserializeError::IncompleteData),
+        }
+    }
+
+    /// Capacity to reserve for a collection of T with the given length. Elements are bigger in memory than on the network,
+    /// so the length alone could reserve much more than the received data : the capacity is bounded by what the data can hold
+    /// if each of its bytes was an element, and the collection grows if the elements are really there.
+    fn capacity_for<T>(&self, length: usize) -> usize {
+        length.min(self.remaining() / std::mem::size_of::<T>().max(1))
+    }
 }
 
-// todo : use only u8 slices and arrays, as vectors allocate memory and we don't need it
 pub trait NetworkSerializable {
+    /// Number of bytes the value takes once serialized.
     fn size(&self) -> usize;
-    fn serialize(self) -> Vec<u8>;
-    fn deserialize(data: Vec<u8>) -> Result<Self, NetworkUnserializeError> where Self: Sized;
+    /// Write the value at the end of the buffer.
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W);
+    /// Read a value from the reader, and returns it with the number of bytes that were used.
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> where Self: Sized;
+
+    /// Serialize the value in a new vector.
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.size());
+        self.serialize_into(&mut result);
+        result
+    }
+
+    /// Hash describing how the type is laid out on the network.
+    /// Derived types compute it from their variant ids and field types, containers (vectors, options, tuples...) from the types
+    /// they hold, so a change in a message nested in a container changes the hash of its parents. Other types return 0.
+    fn schema_hash() -> u64 where Self: Sized {
+        0
+    }
+
+    /// Deserialize a value that must use all the given data.
+    fn deserialize(data: &[u8]) -> Result<Self, NetworkUnserializeError> where Self: Sized {
+        let mut reader = ByteReader::new(data);
+        let (result, _) = Self::deserialize_from(&mut reader)?;
+        match reader.is_empty() {
+            true => Ok(result),
+            false => Err(NetworkUnserializeError::InvalidData),
+        }
+    }
+}
+
+
+thread_local! {
+    /// Derived types whose schema hash is being computed, to stop recursive messages from recursing forever.
+    static HASHING: RefCell<Vec<&'static str>> = RefCell::new(Vec::new());
 }
 
+/// Schema hash of a derived type, computed by `hash`. Used by the derive macro.
+/// A type met again while its own hash is computed is a recursive message (through a box, a vector...) :
+/// it is hashed by its name there, instead of recursing forever.
+pub fn guarded_schema_hash<T: ?Sized, F: FnOnce() -> u64>(name: &str, hash: F) -> u64 {
+    let id = std::any::type_name::<T>();
+    if HASHING.with(|hashing| hashing.borrow().contains(&id)) {
+        return composed_schema_hash(&format!("recursive {name}"), &[]);
+    }
+    HASHING.with(|hashing| hashing.borrow_mut().push(id));
+    let result = hash();
+    HASHING.with(|hashing| hashing.borrow_mut().pop());
+    result
+}
 
+/// Schema hash of a type holding other types : fnv-1a of its name, mixed with the hashes of the types it holds.
+fn composed_schema_hash(name: &str, inner: &[u64]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in name.bytes() {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    for inner in inner {
+        hash = (hash ^ inner).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
 
-// As the NetworkSerializable rely on itself to be implemented on enums, 
-// let's implement it on all default data types. 
+// As the NetworkSerializable rely on itself to be implemented on enums,
+// let's implement it on all default data types.
 
 macro_rules! implement_network_serialization_for {
     ($type:ident) => {
@@ -21,14 +179,11 @@ macro_rules! implement_network_serialization_for {
             fn size(&self) -> usize {
                 std::mem::size_of::<Self>()
             }
-            fn serialize(self) -> Vec<u8> {
-                self.to_le_bytes().to_vec()
+            fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+                buffer.write_bytes(&self.to_le_bytes());
             }
-            fn deserialize(data: Vec<u8>) -> Result<Self, NetworkUnserializeError> {
-                match TryInto::<[u8; std::mem::size_of::<Self>()]>::try_into(data) {
-                    Ok(val) => Ok(Self::from_le_bytes(val)),
-                    Err(_data) => Err(NetworkUnserializeError::IncompleteData)
-                }
+            fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+                Ok((Self::from_le_bytes(reader.read_array()?), std::mem::size_of::<Self>()))
             }
         }
     }
@@ -51,23 +206,582 @@ impl NetworkSerializable for bool {
     fn size(&self) -> usize {
         1 // bool is 1 byte
     }
-    fn serialize(self) -> Vec<u8> {
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        match self {
+            true => buffer.write_bytes(&[255]),
+            false => buffer.write_bytes(&[0]),
+        }
+    }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        let [val] = reader.read_array::<1>()?;
+        // todo : actually count the number of one's and use this ? but it's safe enough
+        Ok((val >= 127, 1))
+    }
+}
+
+impl NetworkSerializable for String {
+    fn size(&self) -> usize {
+        std::mem::size_of::<u64>() + self.len()
+    }
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        (self.len() as u64).serialize_into(buffer);
+        buffer.write_bytes(self.as_bytes());
+    }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        let start = reader.position();
+        let length = reader.read_length()?;
+        match std::str::from_utf8(reader.read_bytes(length)?) {
+            Ok(string) => Ok((string.to_string(), reader.position() - start)),
+            Err(_) => Err(NetworkUnserializeError::InvalidData),
+        }
+    }
+}
+
+impl<T: NetworkSerializable> NetworkSerializable for Vec<T> {
+    fn size(&self) -> usize {
+        std::mem::size_of::<u64>() + self.iter().map(|value| value.size()).sum::<usize>()
+    }
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        (self.len() as u64).serialize_into(buffer);
+        for value in self {
+            value.serialize_into(buffer);
+        }
+    }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        let start = reader.position();
+        let length = reader.read_length_checked::<T>()?;
+        let mut result = Vec::with_capacity(reader.capacity_for::<T>(length));
+        for _ in 0..length {
+            result.push(T::deserialize_from(reader)?.0);
+        }
+        Ok((result, reader.position() - start))
+    }
+    fn schema_hash() -> u64 {
+        composed_schema_hash("Vec", &[T::schema_hash()])
+    }
+}
+
+impl<T: NetworkSerializable> NetworkSerializable for Option<T> {
+    fn size(&self) -> usize {
+        match self {
+            Some(value) => 1 + value.size(),
+            None => 1,
+        }
+    }
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
         match self {
-            true => vec![255],
-            false => vec![0],
-        }
-    }
-    fn deserialize(data: Vec<u8>) -> Result<Self, NetworkUnserializeError> {
-        match TryInto::<[u8; 1]>::try_into(data) {
-            Ok(val) => {
-                if val[0] >= 127 { // todo : actually count the number of one's and use this ? but it's safe enough
-                    Ok(true)
-                }
-                else {
-                    Ok(false)
-                }
+            Some(value) => {
+                buffer.write_bytes(&[1]);
+                value.serialize_into(buffer);
             },
-            Err(_data) => Err(NetworkUnserializeError::IncompleteData)
+            None => buffer.write_bytes(&[0]),
         }
     }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        match reader.read_array::<1>()? {
+            [0] => Ok((None, 1)),
+            [1] => {
+                let (value, size) = T::deserialize_from(reader)?;
+                Ok((Some(value), 1 + size))
+            },
+            _ => Err(NetworkUnserializeError::InvalidData),
+        }
+    }
+    fn schema_hash() -> u64 {
+        composed_schema_hash("Option", &[T::schema_hash()])
+    }
+}
+
+impl<T: NetworkSerializable> NetworkSerializable for Box<T> {
+    fn size(&self) -> usize {
+        self.as_ref().size()
+    }
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        self.as_ref().serialize_into(buffer);
+    }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        let (value, size) = T::deserialize_from(reader)?;
+        Ok((Box::new(value), size))
+    }
+    // same bytes as the boxed value
+    fn schema_hash() -> u64 {
+        T::schema_hash()
+    }
+}
+
+impl<T: NetworkSerializable, const N: usize> NetworkSerializable for [T; N] {
+    fn size(&self) -> usize {
+        self.iter().map(|value| value.size()).sum()
+    }
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        for value in self {
+            value.serialize_into(buffer);
+        }
+    }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        let start = reader.position();
+        let mut values = Vec::with_capacity(N);
+        for _ in 0..N {
+            values.push(T::deserialize_from(reader)?.0);
+        }
+        match values.try_into() {
+            Ok(result) => Ok((result, reader.position() - start)),
+            Err(_) => Err(NetworkUnserializeError::IncompleteData), // can't happen, we pushed N values
+        }
+    }
+    fn schema_hash() -> u64 {
+        composed_schema_hash(&format!("[{N}]"), &[T::schema_hash()])
+    }
+}
+
+impl<K: NetworkSerializable + Eq + Hash, V: NetworkSerializable> NetworkSerializable for HashMap<K, V> {
+    fn size(&self) -> usize {
+        std::mem::size_of::<u64>() + self.iter().map(|(key, value)| key.size() + value.size()).sum::<usize>()
+    }
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        (self.len() as u64).serialize_into(buffer);
+        for (key, value) in self {
+            key.serialize_into(buffer);
+            value.serialize_into(buffer);
+        }
+    }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        let start = reader.position();
+        let length = reader.read_length_checked::<(K, V)>()?;
+        let mut result = HashMap::with_capacity(reader.capacity_for::<(K, V)>(length));
+        for _ in 0..length {
+            let (key, _) = K::deserialize_from(reader)?;
+            let (value, _) = V::deserialize_from(reader)?;
+            result.insert(key, value);
+        }
+        Ok((result, reader.position() - start))
+    }
+    fn schema_hash() -> u64 {
+        composed_schema_hash("HashMap", &[K::schema_hash(), V::schema_hash()])
+    }
+}
+
+macro_rules! implement_network_serialization_for_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: NetworkSerializable),+> NetworkSerializable for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn size(&self) -> usize {
+                let ($($name,)+) = self;
+                0 $(+ $name.size())+
+            }
+            #[allow(non_snake_case)]
+            fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+                let ($($name,)+) = self;
+                $($name.serialize_into(buffer);)+
+            }
+            #[allow(non_snake_case)]
+            fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+                let start = reader.position();
+                $(let ($name, _) = $name::deserialize_from(reader)?;)+
+                Ok((($($name,)+), reader.position() - start))
+            }
+            fn schema_hash() -> u64 {
+                composed_schema_hash("tuple", &[$($name::schema_hash()),+])
+            }
+        }
+    }
+}
+
+implement_network_serialization_for_tuple!(A);
+implement_network_serialization_for_tuple!(A, B);
+implement_network_serialization_for_tuple!(A, B, C);
+implement_network_serialization_for_tuple!(A, B, C, D);
+implement_network_serialization_for_tuple!(A, B, C, D, E);
+implement_network_serialization_for_tuple!(A, B, C, D, E, F);
+implement_network_serialization_for_tuple!(A, B, C, D, E, F, G);
+implement_network_serialization_for_tuple!(A, B, C, D, E, F, G, H);
+
+
+// cgmath types are serialized field by field, as they would be in a tuple.
+
+macro_rules! implement_network_serialization_for_cgmath {
+    ($type:ident, $($field:ident),+) => {
+        impl<S: NetworkSerializable> NetworkSerializable for $type<S> {
+            fn size(&self) -> usize {
+                0 $(+ self.$field.size())+
+            }
+            fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+                $(self.$field.serialize_into(buffer);)+
+            }
+            fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+                let start = reader.position();
+                let result = $type {
+                    $($field: NetworkSerializable::deserialize_from(reader)?.0,)+
+                };
+                Ok((result, reader.position() - start))
+            }
+            fn schema_hash() -> u64 {
+                composed_schema_hash(stringify!($type), &[S::schema_hash()])
+            }
+        }
+    }
+}
+
+implement_network_serialization_for_cgmath!(Vector2, x, y);
+implement_network_serialization_for_cgmath!(Vector3, x, y, z);
+implement_network_serialization_for_cgmath!(Vector4, x, y, z, w);
+implement_network_serialization_for_cgmath!(Matrix2, x, y);
+implement_network_serialization_for_cgmath!(Matrix3, x, y, z);
+implement_network_serialization_for_cgmath!(Matrix4, x, y, z, w);
+implement_network_serialization_for_cgmath!(Quaternion, s, v);
+
+
+impl NetworkSerializable for Color {
+    fn size(&self) -> usize {
+        self.as_vector().size()
+    }
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        self.as_vector().serialize_into(buffer);
+    }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        let (rgb, size) = Vector3::<f32>::deserialize_from(reader)?;
+        Ok((Color::from_rgb(rgb.x, rgb.y, rgb.z), size))
+    }
+}
+
+/// Only the local position, rotation and scale are sent : parenting is not shared through the network.
+impl NetworkSerializable for Transform {
+    fn size(&self) -> usize {
+        self.position().size() + self.rotation().size() + self.get_scale().size()
+    }
+    fn serialize_into<W: ByteWriter + ?Sized>(&self, buffer: &mut W) {
+        self.position().serialize_into(buffer);
+        self.rotation().serialize_into(buffer);
+        self.get_scale().serialize_into(buffer);
+    }
+    fn deserialize_from(reader: &mut ByteReader) -> Result<(Self, usize), NetworkUnserializeError> {
+        let start = reader.position();
+        let (position, _) = Vector3::<f32>::deserialize_from(reader)?;
+        let (rotation, _) = Quaternion::<f32>::deserialize_from(reader)?;
+        let (scale, _) = Vector3::<f32>::deserialize_from(reader)?;
+        let mut result = Transform::origin();
+        result.set_position(position);
+        result.set_rotation(rotation);
+        result.set_scale(scale);
+        Ok((result, reader.position() - start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NetworkSerializable;
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    enum Tree {
+        Leaf(u8),
+        Node(Box<Tree>, Box<Tree>),
+    }
+
+    /// Recursive through another message.
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Folder {
+        name: String,
+        content: Vec<Entry>,
+    }
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    enum Entry {
+        File(String),
+        Folder(Folder),
+    }
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Named {
+        id: u32,
+        #[network(skip)]
+        cache: Vec<u8>,
+        name: String,
+    }
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Unnamed(u8, #[network(skip)] u64, i16);
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Unit;
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Pair<T> {
+        first: T,
+        second: T,
+    }
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    enum Shapes {
+        Empty,
+        Circle(f32),
+        Rectangle { width: f32, height: f32 },
+        Labeled { shape: Box<Shapes>, #[network(skip)] selected: bool, label: String },
+    }
+
+    /// First version of a message.
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct SettingsV1 {
+        volume: u8,
+    }
+
+    /// Same message, with fields added at the end.
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct SettingsV2 {
+        volume: u8,
+        #[network(default)]
+        muted: bool,
+        #[network(default)]
+        nickname: String,
+    }
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    enum Commands {
+        #[network(id = 10)]
+        Move(f32),
+        Stop,
+        #[network(id = 2)]
+        Jump,
+    }
+
+    /// Same ids as `Commands`, declared in another order.
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    enum CommandsReordered {
+        #[network(id = 2)]
+        Jump,
+        #[network(id = 10)]
+        Move(f32),
+        Stop,
+    }
+
+    /// Same variants as `Commands`, without the ids.
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    enum CommandsInOrder {
+        Move(f32),
+        Stop,
+        Jump,
+    }
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Position {
+        x: f32,
+    }
+
+    /// Same as `Position` with another name.
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Location {
+        x: f32,
+    }
+
+    /// `Position` with another field type.
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct PrecisePosition {
+        x: f64,
+    }
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct Path {
+        points: Vec<Position>,
+    }
+
+    #[derive(NetworkSerializable)]
+    #[derive(Debug, Clone, PartialEq)]
+    struct PrecisePath {
+        points: Vec<PrecisePosition>,
+    }
+
+    /// Checks the value goes through, and that its size is the bytes written and read.
+    fn round_trip<T: NetworkSerializable + std::fmt::Debug + PartialEq>(value: T) {
+        let bytes = value.serialize();
+        assert_eq!(bytes.len(), value.size());
+        let mut reader = ByteReader::new(&bytes);
+        let (read, size) = T::deserialize_from(&mut reader).unwrap();
+        assert_eq!(size, bytes.len());
+        assert_eq!(reader.remaining(), 0);
+        assert_eq!(read, value);
+        assert_eq!(T::deserialize(&bytes), Ok(value));
+    }
+
+    #[test]
+    fn recursive_messages_have_a_schema_hash() {
+        assert_eq!(Tree::schema_hash(), Tree::schema_hash());
+        assert_ne!(Tree::schema_hash(), Folder::schema_hash());
+        assert_eq!(Folder::schema_hash(), Folder::schema_hash());
+        assert_ne!(Entry::schema_hash(), 0);
+        // and the messages still go through
+        let tree = Tree::Node(Box::new(Tree::Leaf(1)), Box::new(Tree::Node(Box::new(Tree::Leaf(2)), Box::new(Tree::Leaf(3)))));
+        assert_eq!(Tree::deserialize(&tree.serialize()), Ok(tree));
+        let folder = Folder { name: "root".to_string(), content: vec![Entry::File("a".to_string()), Entry::Folder(Folder { name: "sub".to_string(), content: Vec::new() })] };
+        assert_eq!(Folder::deserialize(&folder.serialize()), Ok(folder));
+    }
+
+    #[test]
+    fn length_prefix_does_not_reserve_more_than_the_data() {
+        // a prefix of 1000 big elements, with 1000 bytes behind it
+        let mut data = 1000u64.to_le_bytes().to_vec();
+        data.extend(vec![0u8; 1000]);
+        let mut reader = ByteReader::new(&data);
+        let length = reader.read_length_checked::<[u64; 64]>().unwrap();
+        assert_eq!(length, 1000);
+        assert!(reader.capacity_for::<[u64; 64]>(length) * std::mem::size_of::<[u64; 64]>() <= 1000);
+        // the elements are not there : it fails without reserving for all of them
+        assert!(Vec::<[u64; 64]>::deserialize(&data).is_err());
+        // small elements get their whole capacity
+        assert_eq!(reader.capacity_for::<u8>(length), 1000);
+    }
+
+    #[test]
+    fn containers_round_trip() {
+        round_trip(true);
+        round_trip(false);
+        round_trip(-3i8);
+        round_trip(0xbeefu16);
+        round_trip(1.5f32);
+        round_trip(-2.25f64);
+        round_trip(String::from("héllo"));
+        round_trip(String::new());
+        round_trip(vec![1u32, 2, 3]);
+        round_trip(Vec::<String>::new());
+        round_trip(Some(7u64));
+        round_trip(None::<u64>);
+        round_trip(Box::new(vec![Some(1i16), None]));
+        round_trip([4u8, 5, 6]);
+        round_trip([String::from("a"), String::from("b")]);
+        round_trip(HashMap::from([(1u8, String::from("one")), (2u8, String::from("two"))]));
+        round_trip(HashMap::<u64, u64>::new());
+    }
+
+    #[test]
+    fn tuples_round_trip() {
+        round_trip((1u8,));
+        round_trip((1u8, 2u16));
+        round_trip((1u8, 2u16, 3u32));
+        round_trip((1u8, 2u16, 3u32, 4u64));
+        round_trip((1u8, 2u16, 3u32, 4u64, String::from("five")));
+        round_trip((1u8, 2u16, 3u32, 4u64, String::from("five"), true));
+        round_trip((1u8, 2u16, 3u32, 4u64, String::from("five"), true, Some(7i32)));
+        round_trip((1u8, 2u16, 3u32, 4u64, String::from("five"), true, Some(7i32), vec![8i64]));
+    }
+
+    #[test]
+    fn values_are_read_one_after_the_other() {
+        let mut bytes = Vec::new();
+        String::from("first").serialize_into(&mut bytes);
+        vec![2u16, 3].serialize_into(&mut bytes);
+        (4u8, false).serialize_into(&mut bytes);
+        let mut reader = ByteReader::new(&bytes);
+        assert_eq!(String::deserialize_from(&mut reader), Ok((String::from("first"), 8 + 5)));
+        assert_eq!(Vec::<u16>::deserialize_from(&mut reader), Ok((vec![2, 3], 8 + 4)));
+        assert_eq!(<(u8, bool)>::deserialize_from(&mut reader), Ok(((4, false), 2)));
+        assert_eq!(reader.remaining(), 0);
+    }
+
+    #[test]
+    fn truncated_and_trailing_data_are_refused() {
+        let bytes = vec![1u32, 2, 3].serialize();
+        assert!(Vec::<u32>::deserialize(&bytes[..bytes.len() - 1]).is_err());
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(Vec::<u32>::deserialize(&longer).is_err());
+        // an option tag can only be 0 or 1
+        assert_eq!(Option::<u8>::deserialize(&[2, 0]), Err(NetworkUnserializeError::InvalidData));
+    }
+
+    #[test]
+    fn derived_structs_and_variants_round_trip() {
+        round_trip(Named { id: 3, cache: Vec::new(), name: String::from("three") });
+        round_trip(Unnamed(1, 0, -2));
+        round_trip(Unit);
+        round_trip(Pair { first: 1u16, second: 2u16 });
+        round_trip(Pair { first: Some(String::from("a")), second: None });
+        round_trip(Shapes::Empty);
+        round_trip(Shapes::Circle(1.));
+        round_trip(Shapes::Rectangle { width: 2., height: 3. });
+        round_trip(Shapes::Labeled { shape: Box::new(Shapes::Circle(4.)), selected: false, label: String::from("four") });
+        // enums are prefixed by the variant id, in declaration order
+        assert_eq!(Shapes::Empty.serialize(), 0u64.to_le_bytes());
+        assert_eq!(&Shapes::Circle(1.).serialize()[..8], 1u64.to_le_bytes());
+        assert_eq!(Shapes::deserialize(&4u64.to_le_bytes()), Err(NetworkUnserializeError::InvalidId));
+    }
+
+    #[test]
+    fn skipped_fields_are_not_sent() {
+        let named = Named { id: 3, cache: vec![1, 2, 3], name: String::from("three") };
+        assert_eq!(named.size(), 4 + 8 + 5);
+        assert_eq!(Named::deserialize(&named.serialize()), Ok(Named { cache: Vec::new(), ..named }));
+        assert_eq!(Unnamed::deserialize(&Unnamed(1, 99, -2).serialize()), Ok(Unnamed(1, 0, -2)));
+        let labeled = Shapes::Labeled { shape: Box::new(Shapes::Empty), selected: true, label: String::new() };
+        assert_eq!(Shapes::deserialize(&labeled.serialize()), Ok(Shapes::Labeled { shape: Box::new(Shapes::Empty), selected: false, label: String::new() }));
+    }
+
+    #[test]
+    fn default_fields_can_be_missing_at_the_end() {
+        round_trip(SettingsV2 { volume: 5, muted: true, nickname: String::from("new") });
+        // an older peer stops before the added fields
+        let old = SettingsV1 { volume: 5 }.serialize();
+        assert_eq!(SettingsV2::deserialize(&old), Ok(SettingsV2 { volume: 5, muted: false, nickname: String::new() }));
+        // only the last one missing
+        let mut partial = old.clone();
+        true.serialize_into(&mut partial);
+        assert_eq!(SettingsV2::deserialize(&partial), Ok(SettingsV2 { volume: 5, muted: true, nickname: String::new() }));
+        // required fields can't be missing
+        assert!(SettingsV1::deserialize(&[]).is_err());
+    }
+
+    #[test]
+    fn explicit_ids_are_sent() {
+        assert_eq!(&Commands::Move(1.).serialize()[..8], 10u64.to_le_bytes());
+        // the following variants count up from the explicit id
+        assert_eq!(Commands::Stop.serialize(), 11u64.to_le_bytes());
+        assert_eq!(Commands::Jump.serialize(), 2u64.to_le_bytes());
+        round_trip(Commands::Move(1.));
+        round_trip(Commands::Stop);
+        round_trip(Commands::Jump);
+        // reordering the variants keeps the bytes
+        assert_eq!(Commands::Move(1.).serialize(), CommandsReordered::Move(1.).serialize());
+        assert_eq!(CommandsReordered::deserialize(&Commands::Stop.serialize()), Ok(CommandsReordered::Stop));
+        assert_eq!(Commands::deserialize(&3u64.to_le_bytes()), Err(NetworkUnserializeError::InvalidId));
+    }
+
+    #[test]
+    fn schema_hash_is_stable() {
+        // fnv-1a, the same on every build and platform
+        assert_eq!(composed_schema_hash("a", &[]), 0xaf63dc4c8601ec8c);
+        assert_eq!(Commands::schema_hash(), Commands::schema_hash());
+        assert_eq!(Commands::schema_hash(), CommandsReordered::schema_hash());
+        // the name is not sent, so it is not part of the hash
+        assert_eq!(Position::schema_hash(), Location::schema_hash());
+        assert_eq!(Vec::<Position>::schema_hash(), Vec::<Location>::schema_hash());
+    }
+
+    #[test]
+    fn schema_hash_changes_with_the_layout() {
+        assert_ne!(Commands::schema_hash(), CommandsInOrder::schema_hash());
+        assert_ne!(Position::schema_hash(), PrecisePosition::schema_hash());
+        assert_ne!(SettingsV1::schema_hash(), SettingsV2::schema_hash());
+        // changes in nested messages reach their parents, through containers too
+        assert_ne!(Vec::<Position>::schema_hash(), Vec::<PrecisePosition>::schema_hash());
+        assert_ne!(Path::schema_hash(), PrecisePath::schema_hash());
+        assert_ne!(Option::<Position>::schema_hash(), Option::<PrecisePosition>::schema_hash());
+        assert_ne!(<(u8, Position)>::schema_hash(), <(u8, PrecisePosition)>::schema_hash());
+        assert_ne!(Pair::<Position>::schema_hash(), Pair::<PrecisePosition>::schema_hash());
+        assert_ne!(Vec::<Position>::schema_hash(), Option::<Position>::schema_hash());
+    }
 }
@@ -1,287 +1,864 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet},
     net::{
-        UdpSocket, TcpStream, TcpListener, SocketAddr,
+        TcpStream, SocketAddr, IpAddr, ToSocketAddrs,
     },
     io::{Write},
 };
 
 use foundry::*;
+use rand_core::{OsRng, RngCore};
 
-use crate::{NetworkSerializable, DefaultNetworkMessages};
+use crate::{NetworkSerializable, DefaultNetworkMessages, ProtocolVersion, RejectReason, DisconnectReason, DEFAULT_TICK_RATE, DEFAULT_INTERPOLATION_DELAY};
 
-use super::{packet::Packet, buffer::{TcpBuffer, UdpBuffer}};
+use super::{packet::Packet, buffer::{TcpBuffer, UdpBuffer, OutgoingBuffer, FrameError, DEFAULT_MAX_PACKET_SIZE, DEFAULT_MAX_QUEUED_BYTES}, channels::{Channel, ChannelEndpoint, Stream}, fragments::FragmentStats, transport::{ServerTransport, OsServerTransport, TransportStream}, heartbeat::{Heartbeat, ConnectionQuality, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_IDLE_TIMEOUT}, encryption::{KeyExchange, TcpCipher}, priority::{Priority, Bandwidth, DEFAULT_UNRELIABLE_MAX_AGE}, rpc::{Rpc, RpcCall, RpcEndpoint}, replication::{Networked, ReplicationServer, Relevancy}, prediction::{Predicted, PredictionServer}, lag_compensation::{LagCompensation, capture_lag_compensated}, stats::{NetworkStats, ConnectionStats, StatsRecorder}};
 
 /// Server system. When created, will start to listen tcp connections and create Connection when receiving them.
-/// H is the server handler
-pub struct Server<H: ServerHandler> {
+/// H is the server handler, T the transport used to talk to the clients (os sockets by default).
+pub struct Server<H: ServerHandler, T: ServerTransport = OsServerTransport> {
     server_handler: H,
-    connections: HashMap<u64, Connection>,
+    // sorted, so clients are always processed in the same order
+    connections: BTreeMap<u64, Connection<T::Stream>>,
     max_client_count: u64,
     current_client_count: u64,
     next_available_id: u64,
-    tcp_listener: TcpListener,
-    udp_socket: UdpSocket,
+    transport: T,
+    udp_buffer: UdpBuffer,
+    protocol: ProtocolVersion,
+    max_packet_size: usize,
+    /// time since the server started, in seconds. Used for the resend timers of the udp channels.
+    time: f64,
+    tick_rate: f64,
+    /// tick of the current update, put in the packets we send
+    tick: u32,
+    heartbeat_interval: f64,
+    idle_timeout: f64,
+    banned_ips: BTreeSet<IpAddr>,
+    banned_ids: BTreeSet<u64>,
+    /// udp datagrams thrown away because they were not sent by the client they claim to be from
+    rejected_datagrams: u64,
+    /// only accept encrypted connections
+    encryption: bool,
+    /// bytes that can wait to be sent to a client before the handler is told it is too slow
+    max_queued_bytes: usize,
+    /// bytes per second budget of the new connections, if any
+    bandwidth_budget: Option<u64>,
+    unreliable_max_age: f64,
+    /// calls made to the clients, and responders to theirs
+    rpc: RpcEndpoint,
+    /// snapshots of the replicated entities sent to each client
+    replication: ReplicationServer,
+    /// authoritative states of the clients predicting their inputs, if any
+    prediction: Option<PredictionServer>,
+    /// seconds of transforms kept for lag compensation, if enabled
+    lag_history: Option<f64>,
+    /// how far in the past the clients show the remote entities (see `Interpolated`)
+    client_interpolation_delay: f64,
 }
 
 impl<H: ServerHandler> Server<H> {
-    pub fn new(server_handler: H, port: u64, max_client_count: u64) -> std::io::Result<Server<H>> {
-        Ok(Server { 
+    /// Create a server listening on the given address, like `"0.0.0.0:7777"` or `"[::]:7777"`.
+    pub fn new<A: ToSocketAddrs>(server_handler: H, addr: A, max_client_count: u64) -> std::io::Result<Server<H>> {
+        Ok(Server::with_transport(server_handler, OsServerTransport::bind(addr)?, max_client_count))
+    }
+}
+
+impl<H: ServerHandler, T: ServerTransport> Server<H, T> {
+    /// Create a server that talks to its clients through the given transport.
+    pub fn with_transport(server_handler: H, transport: T, max_client_count: u64) -> Server<H, T> {
+        Server { 
             server_handler: server_handler,
-            connections: HashMap::new(),
+            connections: BTreeMap::new(),
             max_client_count: max_client_count,
             current_client_count: 0,
             next_available_id: 1,
-            tcp_listener: {
-                let listener = TcpListener::bind(format!("0.0.0.0:{port}"))?; // todo : ip adress management
-                listener.set_nonblocking(true)?;
-                listener
-            },
-            udp_socket: {
-                let socket = UdpSocket::bind(format!("0.0.0.0:{port}"))?;
-                socket.set_nonblocking(true)?;
-                socket
+            transport,
+            udp_buffer: UdpBuffer::new(),
+            protocol: ProtocolVersion::new::<H::ServerMessages, H::ClientsMessages>(H::PROTOCOL_VERSION),
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            time: 0.,
+            tick_rate: DEFAULT_TICK_RATE,
+            tick: 0,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            banned_ips: BTreeSet::new(),
+            banned_ids: BTreeSet::new(),
+            rejected_datagrams: 0,
+            encryption: false,
+            max_queued_bytes: DEFAULT_MAX_QUEUED_BYTES,
+            bandwidth_budget: None,
+            unreliable_max_age: DEFAULT_UNRELIABLE_MAX_AGE,
+            rpc: RpcEndpoint::new(),
+            replication: ReplicationServer::new(),
+            prediction: None,
+            lag_history: None,
+            client_interpolation_delay: DEFAULT_INTERPOLATION_DELAY,
+        }
+    }
+
+    /// Only accept clients that encrypt the connection (see `Client::with_encryption`).
+    /// Keys are agreed on during the handshake, then tcp and udp packets are encrypted and authenticated,
+    /// and replayed udp datagrams are dropped. Clients without encryption are rejected.
+    pub fn with_encryption(mut self) -> Server<H, T> {
+        self.encryption = true;
+        self
+    }
+
+    /// Set the maximum size of the packets clients can send. Clients sending bigger packets are disconnected.
+    /// Only applies to connections accepted after the call.
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Server<H, T> {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Set how many bytes can wait to be sent to a client (tcp bytes the os did not take, udp messages not acknowledged)
+    /// before the handler is told with `on_client_backpressure`.
+    pub fn with_max_queued_bytes(mut self, max_queued_bytes: usize) -> Server<H, T> {
+        self.max_queued_bytes = max_queued_bytes;
+        self
+    }
+
+    /// Limit what is sent to each client to this many bytes per second. Udp messages that don't fit wait, by priority and age
+    /// (see `Priority`), and unreliable ones that waited too long are dropped. Tcp messages are counted, but never held back.
+    /// Only applies to connections accepted after the call, see `set_bandwidth_budget` for the others.
+    pub fn with_bandwidth_budget(mut self, bytes_per_second: u64) -> Server<H, T> {
+        self.bandwidth_budget = Some(bytes_per_second);
+        self
+    }
+
+    /// Set how long a normal priority unreliable message can wait for bandwidth before being dropped (in seconds).
+    /// Low priority messages wait half of it, high priority ones twice it. Only applies to connections accepted after the call.
+    pub fn with_unreliable_max_age(mut self, max_age: f64) -> Server<H, T> {
+        self.unreliable_max_age = max_age;
+        self
+    }
+
+    /// Answer the calls to R made by the clients (see `Client::call`). The responder gets the id of the calling client.
+    /// Calls to a method without responder fail with `RpcError::UnknownMethod`.
+    pub fn with_rpc<R, F>(mut self, responder: F) -> Server<H, T>
+        where R: Rpc, F: FnMut(u64, R, &mut ComponentTable) -> R::Response + 'static
+    {
+        self.rpc.register(responder);
+        self
+    }
+
+    /// Call a method on a client. The callback is run with the response when it comes,
+    /// or with an error if the client can't answer, does not in time, or disconnects.
+    pub fn call(&mut self, client: u64, call: RpcCall, components: &mut ComponentTable) {
+        let request = self.rpc.start(call, client, self.time);
+        match self.connections.get(&client) {
+            Some(connection) if connection.handshake_done => self.send_default_message(client, &request),
+            _ => {
+                println!("[NETWORK SERVER] -> Unable to call client {client} : not connected.");
+                self.rpc.disconnected(client, components);
             },
-        })
+        }
+    }
+
+    /// Replicate the component C of the entities with a `Replicated` component. `Transform` is always replicated.
+    /// The clients must register the same components (see `Client::with_replicated`).
+    pub fn with_replicated<C: Networked>(mut self) -> Server<H, T> {
+        self.replication.types.register::<C>();
+        self
+    }
+
+    /// Only send to a client the replicated entities closer than this to one of its viewers (see `Viewer`).
+    /// Entities without transform, always relevant or owned ones are sent whatever the distance,
+    /// and the handler has the last word on the others (see `ServerHandler::is_relevant`).
+    pub fn with_relevancy_distance(mut self, distance: f32) -> Server<H, T> {
+        self.replication.relevancy_distance = Some(distance);
+        self
+    }
+
+    /// Let the clients predict the state P with their inputs (see `Client::with_prediction`). Each client gets a state from spawn
+    /// when it joins, in the `Authority` singleton. Its inputs are applied to it as they come, each one being a tick of tick_delta seconds,
+    /// and it is sent back to the client when it changed.
+    pub fn with_prediction<P, F>(mut self, tick_delta: f32, spawn: F) -> Server<H, T>
+        where P: Predicted, F: FnMut(u64, &mut ComponentTable) -> P + 'static
+    {
+        self.prediction = Some(PredictionServer::new(tick_delta, spawn));
+        self
+    }
+
+    /// Record the transforms of the `LagCompensated` entities on each update, for the given time (in seconds, see `DEFAULT_LAG_HISTORY`).
+    /// They are in the `LagCompensation` singleton, which can rewind queries to what a client saw.
+    pub fn with_lag_compensation(mut self, history: f64) -> Server<H, T> {
+        self.lag_history = Some(history);
+        self
+    }
+
+    /// Set how far in the past the clients show the remote entities (in seconds), to know what they saw for lag compensation.
+    /// It should be the delay of their `Interpolated` components.
+    pub fn with_client_interpolation_delay(mut self, delay: f64) -> Server<H, T> {
+        self.client_interpolation_delay = delay;
+        self
+    }
+
+    /// Change the bytes per second budget of a client, None to remove it.
+    pub fn set_bandwidth_budget(&mut self, client: u64, bytes_per_second: Option<u64>) {
+        match self.connections.get_mut(&client) {
+            Some(connection) => connection.bandwidth = Bandwidth::new(bytes_per_second),
+            None => println!("[NETWORK SERVER] -> Unable to set the bandwidth of client {client} : not registered."),
+        }
+    }
+
+    /// Set how long we wait for the fragments of a big udp message, and how much memory incomplete messages can use.
+    pub fn with_reassembly_limits(mut self, timeout: f64, max_memory: usize) -> Server<H, T> {
+        self.udp_buffer = UdpBuffer::with_reassembly_limits(timeout, max_memory);
+        self
+    }
+
+    /// Set how often clients are pinged, and how long a client can stay silent before being disconnected (in seconds).
+    /// Only applies to connections accepted after the call.
+    pub fn with_heartbeat(mut self, interval: f64, idle_timeout: f64) -> Server<H, T> {
+        self.heartbeat_interval = interval;
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Set the number of ticks per second. The tick of the server is its time times the rate : it is put in the packets
+    /// sent to the clients, and they can estimate it (see `NetworkTime`).
+    pub fn with_tick_rate(mut self, tick_rate: f64) -> Server<H, T> {
+        self.tick_rate = tick_rate;
+        self
+    }
+
+    /// Time since the server started, in seconds.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Tick of the current update.
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    /// Get the connection of a client, to look at its state.
+    pub fn connection(&self, client: u64) -> Option<&Connection<T::Stream>> {
+        self.connections.get(&client)
+    }
+
+    /// The transport used to talk to the clients.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Number of udp datagrams thrown away because they had a wrong session token, an unknown sender,
+    /// or came from another address than the one of the client.
+    pub fn rejected_datagrams(&self) -> u64 {
+        self.rejected_datagrams
+    }
+
+    /// Statistics about the fragmented udp messages received from all clients.
+    pub fn fragment_stats(&self) -> FragmentStats {
+        self.udp_buffer.fragment_stats()
+    }
+
+    /// Refuse the connections from this address. Clients already connected from it are not kicked (see `ban`).
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        self.banned_ips.insert(ip);
+    }
+
+    pub fn unban_ip(&mut self, ip: IpAddr) {
+        self.banned_ips.remove(&ip);
+    }
+
+    /// Refuse the connection with this client id. Ids are given by the server and never reused while it runs.
+    pub fn ban_id(&mut self, client: u64) {
+        self.banned_ids.insert(client);
+    }
+
+    pub fn unban_id(&mut self, client: u64) {
+        self.banned_ids.remove(&client);
+    }
+
+    pub fn is_banned(&self, client: u64, ip: IpAddr) -> bool {
+        self.banned_ids.contains(&client) || self.banned_ips.contains(&ip)
+    }
+
+    /// Remove a client from the server. It is told the reason before the connection is closed.
+    pub fn kick(&mut self, client: u64, reason: &str, components: &mut ComponentTable) {
+        if !self.connections.contains_key(&client) {
+            println!("[NETWORK SERVER] -> Unable to kick client {client} : not registered.");
+            return;
+        }
+        println!("[NETWORK SERVER] -> Kicking client {client} : {reason}.");
+        self.send_default_message(client, &DefaultNetworkMessages::Kicked(reason.to_string()));
+        self.disconnect_client(client, DisconnectReason::ServerKick(reason.to_string()), components);
+    }
+
+    /// Kick the client, and ban its id and address.
+    pub fn ban(&mut self, client: u64, reason: &str, components: &mut ComponentTable) {
+        if let Some(Ok(addr)) = self.connections.get(&client).map(|connection| connection.peer_addr()) {
+            self.banned_ips.insert(addr.ip());
+        }
+        self.banned_ids.insert(client);
+        self.kick(client, reason, components);
     }
 
-    pub fn handle_incoming_connection(&mut self, stream: TcpStream, adress: SocketAddr, components: &mut ComponentTable) -> Vec<ServerMessage<H::ServerMessages>>{
+    /// Register a new connection. The connection is not given to the handler until it sent a valid hello message.
+    pub fn handle_incoming_connection(&mut self, mut stream: T::Stream, adress: SocketAddr) {
         println!("[NETWORK SERVER] -> incoming connection : {adress}.");
+        if self.banned_ips.contains(&adress.ip()) {
+            println!("[NETWORK SERVER] -> rejected connection : {} is banned.", adress.ip());
+            let bytes = Packet::from_default(&DefaultNetworkMessages::Rejected(RejectReason::Banned), 0).stamped(self.tick).as_bytes();
+            if let Err(e) = stream.write_all(&bytes) {
+                println!("[NETWORK SERVER] -> Unable to send rejection to {adress} : {e}.");
+            }
+            return;
+        }
         if self.current_client_count < self.max_client_count {
-            println!("[NETWORK SERVER] -> accepted connection as Connection {}.", self.next_available_id);
-            let client = match Connection::new(self.next_available_id, stream) {
-                Ok(client) => {
-                    client
-                },
-                Err(e) => {
-                    println!("[NETWORK SERVER] -> Error while handling incoming connection : {e}.");
-                    return Vec::new();
-                }
-            };
-            // send the welcome message
-            let result = self.server_handler.on_client_connected(client.id, components);
+            println!("[NETWORK SERVER] -> accepted connection as Connection {}, waiting for handshake.", self.next_available_id);
+            let mut client = Connection::new(self.next_available_id, stream, self.max_packet_size);
+            client.heartbeat = Heartbeat::new(self.heartbeat_interval, self.idle_timeout, self.time);
+            client.channels.set_token(new_session_token());
+            client.channels.set_unreliable_max_age(self.unreliable_max_age);
+            client.bandwidth = Bandwidth::new(self.bandwidth_budget);
             self.connections.insert(self.next_available_id, client);
-            self.send_default_message(self.next_available_id, DefaultNetworkMessages::Welcome(self.next_available_id), components);
             self.current_client_count += 1;
             self.next_available_id += 1;
-            result
         }
         else {
             println!("[NETWORK SERVER] -> rejected connection : server full.");
-            // stream.shutdown(std::net::Shutdown::Both);
-            Vec::new()
+            let bytes = Packet::from_default(&DefaultNetworkMessages::Rejected(RejectReason::ServerFull), 0).stamped(self.tick).as_bytes();
+            match stream.write_all(&bytes) {
+                Ok(_) => {},
+                Err(e) => println!("[NETWORK SERVER] -> Unable to send rejection to {adress} : {e}."),
+            }
+            // stream is dropped here, closing the connection
+        }
+    }
+
+    /// Handle the first message of a connection : check the protocol. The client is then expected to log in.
+    fn handle_hello(&mut self, client: u64, protocol: ProtocolVersion, components: &mut ComponentTable) {
+        match self.connections.get(&client) {
+            Some(connection) if !connection.hello_received => {},
+            Some(_) => {
+                println!("[NETWORK SERVER] -> Client {client} sent hello twice, ignoring it.");
+                return;
+            },
+            None => return,
+        }
+        if protocol != self.protocol {
+            println!("[NETWORK SERVER] -> Rejected connection {client} : protocol mismatch (client : {protocol:?}, server : {:?}).", self.protocol);
+            self.reject(client, RejectReason::ProtocolMismatch(self.protocol), DisconnectReason::ProtocolMismatch(protocol), components);
+            return;
+        }
+        if let Some(connection) = self.connections.get_mut(&client) {
+            connection.hello_received = true;
+        }
+    }
+
+    /// Handle the login of a connection : check the ban list, ask the handler, and welcome the client if it is accepted.
+    fn handle_login(&mut self, client: u64, login: Vec<u8>, components: &mut ComponentTable) -> Vec<ServerMessage<H::ServerMessages>> {
+        let addr = match self.connections.get(&client) {
+            Some(connection) if connection.hello_received && !connection.handshake_done => match connection.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => return Vec::new(), // the tcp read will find out the connection is dead
+            },
+            Some(_) => {
+                println!("[NETWORK SERVER] -> Client {client} sent a login out of the handshake, ignoring it.");
+                return Vec::new();
+            },
+            None => return Vec::new(),
+        };
+        if self.encryption && self.connections.get(&client).is_some_and(|connection| connection.tcp_cipher.is_none()) {
+            println!("[NETWORK SERVER] -> Rejected connection {client} : encryption is required.");
+            self.reject(client, RejectReason::EncryptionRequired, DisconnectReason::EncryptionRequired, components);
+            return Vec::new();
+        }
+        if self.is_banned(client, addr.ip()) {
+            println!("[NETWORK SERVER] -> Rejected connection {client} : banned.");
+            self.reject(client, RejectReason::Banned, DisconnectReason::Banned, components);
+            return Vec::new();
+        }
+        match self.server_handler.on_connection_request(addr, &login, components) {
+            ConnectionResponse::Accept => {},
+            ConnectionResponse::Reject(reason) => {
+                println!("[NETWORK SERVER] -> Rejected connection {client} : {reason}.");
+                self.reject(client, RejectReason::Refused(reason.clone()), DisconnectReason::Refused(reason), components);
+                return Vec::new();
+            },
+        }
+        if let Some(connection) = self.connections.get_mut(&client) {
+            connection.handshake_done = true;
+        }
+        println!("[NETWORK SERVER] -> Connection {client} completed handshake.");
+        let token = self.connections.get(&client).map(|connection| connection.channels.token()).unwrap_or_default();
+        self.send_default_message(client, &DefaultNetworkMessages::Welcome(client, self.protocol, token));
+        self.send_default_message(client, &DefaultNetworkMessages::TickRate(self.tick_rate));
+        if let Some(prediction) = &mut self.prediction {
+            prediction.connect(client, components);
+        }
+        self.server_handler.on_client_connected(client, components)
+    }
+
+    /// The client wants to encrypt the connection : answer with our key, and encrypt everything from now on.
+    fn handle_public_key(&mut self, client: u64, key: [u8; 32], components: &mut ComponentTable) {
+        match self.connections.get(&client) {
+            Some(connection) if connection.hello_received && !connection.handshake_done && connection.tcp_cipher.is_none() => {},
+            Some(_) => {
+                println!("[NETWORK SERVER] -> Client {client} sent a key out of the handshake, ignoring it.");
+                return;
+            },
+            None => return,
+        }
+        if !self.encryption {
+            println!("[NETWORK SERVER] -> Rejected connection {client} : it wants encryption, which is not enabled.");
+            self.reject(client, RejectReason::EncryptionUnsupported, DisconnectReason::EncryptionUnsupported, components);
+            return;
+        }
+        let exchange = KeyExchange::new();
+        let public_key = exchange.public_key();
+        let keys = match exchange.finish(key, false) {
+            Some(keys) => keys,
+            None => {
+                println!("[NETWORK SERVER] -> Client {client} sent an invalid key. Disconnecting client.");
+                self.disconnect_client(client, DisconnectReason::InvalidPacket, components);
+                return;
+            }
+        };
+        // our key goes in the clear, the client needs it to decrypt anything
+        self.send_default_message(client, &DefaultNetworkMessages::PublicKey(public_key));
+        if let Some(connection) = self.connections.get_mut(&client) {
+            connection.tcp_cipher = Some(keys.tcp);
+            connection.channels.set_cipher(keys.udp);
+        }
+    }
+
+    /// Tell a connection why it is refused, and close it.
+    fn reject(&mut self, client: u64, reason: RejectReason, disconnect_reason: DisconnectReason, components: &mut ComponentTable) {
+        self.send_default_message(client, &DefaultNetworkMessages::Rejected(reason));
+        self.disconnect_client(client, disconnect_reason, components);
+    }
+
+    /// Write everything that was queued for the clients this tick : the tcp packets, then the udp messages coalesced in as few
+    /// datagrams as possible, with the reliable messages to resend and the acks. What the os could not take is written on the next flush.
+    fn flush_connections(&mut self, components: &mut ComponentTable) {
+        let mut lost = Vec::new();
+        for (id, connection) in self.connections.iter_mut() {
+            connection.bandwidth.refill(self.time);
+            if let Err(e) = connection.outgoing.flush(&mut connection.tcp_connection) {
+                println!("[NETWORK SERVER] -> Error while sending data to client {id} ({e}). Disconnecting client {id}.");
+                lost.push(*id);
+                continue;
+            }
+            if !connection.handshake_done {
+                continue;
+            }
+            let addr = match connection.udp_addr() {
+                Ok(addr) => addr,
+                Err(_) => continue, // the tcp read will find out the connection is dead
+            };
+            for datagram in connection.channels.flush(self.time, 0, &mut connection.bandwidth, &mut connection.stats) {
+                // the datagrams that did not make it are lost like any other, reliable messages will be resent
+                if let Err(e) = self.transport.send_to(&datagram, addr) {
+                    println!("[NETWORK SERVER] -> Error while sending udp data to client {id} ({e}).");
+                    break;
+                }
+                connection.stats.datagram_sent(datagram.len());
+            }
+        }
+        for client in lost {
+            self.disconnect_client(client, DisconnectReason::ConnectionLost, components);
+        }
+    }
+
+    /// Tell the handler about the clients that have more data waiting to be sent than allowed.
+    /// It is told once, when the queue goes over the limit. The queue has to go back under it to be reported again.
+    fn check_backpressure(&mut self, components: &mut ComponentTable) -> Vec<ServerMessage<H::ServerMessages>> {
+        let mut result = Vec::new();
+        let mut congested = Vec::new();
+        for (id, connection) in self.connections.iter_mut() {
+            let queued = connection.queued_bytes();
+            let over_limit = queued > self.max_queued_bytes;
+            if over_limit && !connection.backpressure && connection.handshake_done {
+                congested.push((*id, queued));
+            }
+            connection.backpressure = over_limit;
+        }
+        for (client, queued) in congested {
+            println!("[NETWORK SERVER] -> Client {client} is too slow : {queued} bytes are waiting to be sent.");
+            result.append(&mut self.server_handler.on_client_backpressure(client, queued, components));
+        }
+        result
+    }
+
+    /// Ping the clients, and disconnect the ones we did not hear from for too long.
+    fn update_heartbeats(&mut self, components: &mut ComponentTable) {
+        let timed_out: Vec<u64> = self.connections.iter()
+            .filter(|(_, connection)| connection.heartbeat.timed_out(self.time))
+            .map(|(id, _)| *id)
+            .collect();
+        for client in timed_out {
+            println!("[NETWORK SERVER] -> Client {client} timed out.");
+            self.disconnect_client(client, DisconnectReason::TimedOut, components);
+        }
+        let pings: Vec<(u64, u32)> = self.connections.iter_mut()
+            .filter(|(_, connection)| connection.handshake_done)
+            .filter_map(|(id, connection)| connection.heartbeat.ping(self.time).map(|ping| (*id, ping)))
+            .collect();
+        for (client, ping) in pings {
+            self.send_heartbeat(client, &DefaultNetworkMessages::Ping(ping));
+        }
+    }
+
+    /// Send a ping or a pong over udp, or over tcp if udp does not get through from this client.
+    fn send_heartbeat(&mut self, client: u64, message: &DefaultNetworkMessages) {
+        match self.connections.get(&client).is_some_and(|connection| connection.heartbeat.udp_works(self.time)) {
+            true => self.send_default_udp(client, message),
+            false => self.send_default_message(client, message),
+        }
+    }
+
+    /// Send the state of the relevant replicated entities to the clients, each relative to the last snapshot it acknowledged.
+    /// Entities that stopped being relevant to a client are despawned on it.
+    fn send_snapshots(&mut self, components: &mut ComponentTable) {
+        let capture = self.replication.capture(components);
+        let clients: Vec<u64> = self.connections.iter()
+            .filter(|(_, connection)| connection.handshake_done)
+            .map(|(id, _)| *id)
+            .collect();
+        for client in clients {
+            let mut relevant = BTreeSet::new();
+            for entity in capture.entities.iter() {
+                let is_relevant = match self.replication.relevancy(&capture, client, entity) {
+                    Relevancy::Forced(relevant) => relevant,
+                    Relevancy::InRange(in_range) => self.server_handler.is_relevant(client, entity.network_id, entity.entity, in_range, components),
+                };
+                if is_relevant {
+                    relevant.insert(entity.network_id);
+                }
+            }
+            let state = capture.state_for(&relevant);
+            let (Some(message), Some(connection)) = (self.replication.snapshot_for(client, capture.snapshot, &state), self.connections.get_mut(&client)) else {
+                continue;
+            };
+            // an older snapshot is useless once a newer one arrived
+            connection.channels.send(Stream::Snapshots, &Packet::from_default(&message, 0).stamped(self.tick), Priority::Normal, self.time);
+        }
+    }
+
+    /// Apply the inputs a client predicted. Only clients that completed the handshake have a state.
+    fn handle_prediction_inputs(&mut self, client: u64, first_tick: u32, inputs: Vec<Vec<u8>>, components: &mut ComponentTable) {
+        let Some(prediction) = &self.prediction else {
+            println!("[NETWORK SERVER] -> Client {client} sent predicted inputs, but prediction is not enabled.");
+            return;
+        };
+        if !self.connections.get(&client).is_some_and(|connection| connection.handshake_done) {
+            return;
+        }
+        if let Err(e) = prediction.apply(client, first_tick, &inputs, components) {
+            println!("[NETWORK SERVER] -> Unable to read the predicted inputs of client {client} : {e:?}.");
+        }
+    }
+
+    /// Send the authoritative states that changed to their clients, for them to check their prediction.
+    fn send_prediction_states(&mut self, components: &mut ComponentTable) {
+        let Some(prediction) = &self.prediction else {
+            return;
+        };
+        for (client, tick, state) in prediction.changed(components) {
+            if let Some(connection) = self.connections.get_mut(&client) {
+                // an older state is useless once a newer one arrived
+                let packet = Packet::from_default(&DefaultNetworkMessages::PredictionState(tick, state), 0).stamped(self.tick);
+                connection.channels.send(Stream::PredictionStates, &packet, Priority::High, self.time);
+            }
+        }
+    }
+
+    /// Tell the lag compensation how far in the past each client sees the world, before the messages of this update are handled.
+    fn update_lag_compensation(&mut self, components: &mut ComponentTable) {
+        let Some(history) = self.lag_history else {
+            return;
+        };
+        let view_delays = self.connections.iter()
+            .filter(|(_, connection)| connection.handshake_done)
+            .map(|(id, connection)| (*id, connection.rtt() + self.client_interpolation_delay))
+            .collect();
+        if components.get_singleton::<LagCompensation>().is_none() {
+            components.add_singleton(LagCompensation::new(history));
+        }
+        if let Some(lag_compensation) = components.get_singleton_mut::<LagCompensation>() {
+            lag_compensation.set_view_delays(self.time, view_delays);
+        }
+    }
+
+    /// Remember where the lag compensated entities are at the end of this update.
+    fn record_lag_compensation(&mut self, components: &mut ComponentTable) {
+        if self.lag_history.is_none() {
+            return;
+        }
+        let entities = capture_lag_compensated(components);
+        if let Some(lag_compensation) = components.get_singleton_mut::<LagCompensation>() {
+            lag_compensation.record(entities);
+        }
+    }
+
+    fn deserialization_failed(&mut self, client: u64) {
+        if let Some(connection) = self.connections.get_mut(&client) {
+            connection.stats.deserialization_failed();
+        }
+    }
+
+    /// Copy the statistics of the connections in the `NetworkStats` singleton.
+    fn update_stats(&mut self, components: &mut ComponentTable) {
+        let clients = self.connections.iter_mut()
+            .map(|(id, connection)| (*id, connection.stats(self.time)))
+            .collect();
+        if components.get_singleton::<NetworkStats>().is_none() {
+            components.add_singleton(NetworkStats::default());
+        }
+        if let Some(stats) = components.get_singleton_mut::<NetworkStats>() {
+            stats.clients = clients;
+        }
+    }
+
+    /// Send a default message on the unreliable udp channel, for the ones that don't care about being lost (pings).
+    /// They go first, so waiting for bandwidth does not change the measured round trip time.
+    fn send_default_udp(&mut self, to: u64, message: &DefaultNetworkMessages) {
+        if let Some(connection) = self.connections.get_mut(&to) {
+            connection.channels.send(Channel::Unreliable, &Packet::from_default(message, 0).stamped(self.tick), Priority::High, self.time);
         }
     }
 
     /// Disconnect the given client.
-    fn disconnect_client(&mut self, client: u64, components: &mut ComponentTable) {
+    /// The handler is only told about clients that completed the handshake, as it never saw the others.
+    fn disconnect_client(&mut self, client: u64, reason: DisconnectReason, components: &mut ComponentTable) {
         match self.connections.remove(&client) {
-            Some(_) => println!("[NETWORK SERVER] -> Disconnected client {client}."),
+            Some(mut connection) => {
+                println!("[NETWORK SERVER] -> Disconnected client {client} for reason : {reason:?}.");
+                // last chance for what was queued (a kick or a rejection) to reach the client, it does not matter if it fails
+                let _ = connection.outgoing.flush(&mut connection.tcp_connection);
+                self.current_client_count -= 1;
+                self.replication.forget(client);
+                if connection.handshake_done {
+                    self.rpc.disconnected(client, components);
+                    if let Some(prediction) = &self.prediction {
+                        prediction.disconnect(client, components);
+                    }
+                    self.server_handler.on_client_disconnected(client, reason, components);
+                }
+            },
             None => println!("[NETWORK SERVER] -> Unable to disconnect client {client} : not registered."),
         }
-        self.server_handler.on_client_disconnected(client, components);
     }
 
-    pub fn send_default_message(&mut self, to: u64, message: DefaultNetworkMessages, components: &mut ComponentTable) {
-        let bytes = Packet::from_default(message, 0).as_bytes();
+    pub fn send_default_message(&mut self, to: u64, message: &DefaultNetworkMessages) {
+        let packet = Packet::from_default(message, 0).stamped(self.tick);
         match self.connections.get_mut(&to) {
-            Some(client) => {
-                match client.tcp_connection.write(&bytes) {
-                    Ok(_amount_written) => { /* all good */}, // todo : check amount written is enough, or re-write
-                    Err(e) => {
-                        println!("[NETWORK SERVER] -> Error while sending data to client {to} ({e}). Disconnecting client {to}.");
-                        self.disconnect_client(to, components);
-                    },
-                };
-            },
+            Some(client) => client.queue_packet(&packet),
             None => println!("[NETWORK SERVER] -> Attempted to send packet to Connection {to} but Connection was not found."),
         };
     }
 
-    pub fn send_tcp_to_client(&mut self, to: u64, message: H::ServerMessages, components: &mut ComponentTable) {
-        let packet = Packet::from(message, 0);
+    pub fn send_tcp_to_client(&mut self, to: u64, message: &H::ServerMessages) {
+        let packet = Packet::from(message, 0).stamped(self.tick);
         match self.connections.get_mut(&to) {
-            Some(client) => {
-                match client.tcp_connection.write(&packet.as_bytes()) {
-                    Ok(_amount_written) => {/* all good */}, // todo : check amount written is enough, or re-write
-                    Err(e) => {
-                        println!("[NETWORK SERVER] -> Error while sending data to client {to} ({e}). Disconnecting client {to}.");
-                        self.disconnect_client(to, components);
-                    },
-                };
-            },
+            Some(client) => client.queue_packet(&packet),
             None => println!("[NETWORK SERVER] -> Attempted to send packet to Connection {to} but Connection was not found."),
         };
     }
 
-    pub fn send_tcp_to_all(&mut self, message: H::ServerMessages, components: &mut ComponentTable) {
-        // this gets a little tricky :
-        // as if connections fail, we have to disconnect the client, use retain method to loop through all values
-        // and use the retain closure to send messages, and if fail, do not retain the connection
-        let packet = Packet::from(message, 0);
-        let bytes = packet.as_bytes();
-        self.connections.retain(|id, connection| {
-            match connection.tcp_connection.write(&bytes) {
-                Ok(_amount_written) => true, // all good
-                Err(e) => {
-                    println!("[NETWORK SERVER] -> Error while sending data to client {id} ({e}). Disconnecting client {id}.");
-                    self.server_handler.on_client_disconnected(*id, components);
-                    false // don't retain connection
-                },
-            }
-        });
-    }
-
-    pub fn send_tcp_to_all_except(&mut self, except: u64, message: H::ServerMessages, components: &mut ComponentTable) {
-        // this gets a little tricky :
-        // as if connections fail, we have to disconnect the client, use retain method to loop through all values
-        // and use the retain closure to send messages, and if fail, do not retain the connection
-        let packet = Packet::from(message, 0);
-        let bytes = packet.as_bytes();
-        self.connections.retain(|id, connection| {
-            match *id == except {
-                true => true, // if it's the id of the skipped client, don't send data and retain
-                false => match connection.tcp_connection.write(&bytes) {
-                    Ok(_amount_written) => true, // all good
-                    Err(e) => {
-                        println!("[NETWORK SERVER] -> Error while sending data to client {id} ({e}). Disconnecting client {id}.");
-                        self.server_handler.on_client_disconnected(*id, components);
-                        false // don't retain conenction
-                    },
-                }
-            }
-        });
+    /// Send a message to every client. Connections that did not complete the handshake don't get game messages.
+    pub fn send_tcp_to_all(&mut self, message: &H::ServerMessages) {
+        // the packet is built once, and queued for each client
+        let packet = Packet::from(message, 0).stamped(self.tick);
+        for connection in self.connections.values_mut().filter(|connection| connection.handshake_done) {
+            connection.queue_packet(&packet);
+        }
+    }
+
+    pub fn send_tcp_to_all_except(&mut self, except: u64, message: &H::ServerMessages) {
+        let packet = Packet::from(message, 0).stamped(self.tick);
+        for (_, connection) in self.connections.iter_mut().filter(|(id, connection)| **id != except && connection.handshake_done) {
+            connection.queue_packet(&packet);
+        }
     }
 
-    pub fn send_udp_to_client(&mut self, to: u64, message: H::ServerMessages, components: &mut ComponentTable) {
-        let packet = Packet::from(message, 0);
+    pub fn send_udp_to_client(&mut self, to: u64, channel: Channel, priority: Priority, message: &H::ServerMessages) {
+        let packet = Packet::from(message, 0).stamped(self.tick);
         match self.connections.get_mut(&to) {
-            Some(client) => {
-                match client.tcp_connection.peer_addr() {
-                    Ok(addr) => {
-                        // send data 
-                        match self.udp_socket.send_to(&packet.as_bytes(), addr) {
-                            Ok(_) => {/* all good */},
-                            Err(e) => {
-                                println!("[NETWORK SERVER] -> Error while sending data to client {to} ({e}). Disconnecting client {to}.");
-                                self.disconnect_client(to, components);
-                            },
-                        }
-                    },
-                    Err(_) => {
-                        println!("[NETWORK SERVER] -> Unable to send udp to client {to} (Unable to find address). Disconnecting client {to}");
-                        self.disconnect_client(to, components);
-                    },
-                };
-            },
+            Some(client) => client.channels.send(channel, &packet, priority, self.time),
             None => println!("[NETWORK SERVER] -> Attempted to send packet to Connection {to} but Connection was not found."),
         };
     }
 
-    pub fn send_udp_to_all(&mut self, message: H::ServerMessages, components: &mut ComponentTable) {
-        // this gets a little tricky :
-        // as if connections fail, we have to disconnect the client, use retain method to loop through all values
-        // and use the retain closure to send messages, and if fail, do not retain the connection
-        let packet = Packet::from(message, 0);
-        let bytes = packet.as_bytes();
-        self.connections.retain(|id, connection| {
-            match connection.tcp_connection.peer_addr() {
-                Ok(addr) => {
-                    // send data 
-                    match self.udp_socket.send_to(&bytes, addr) {
-                        Ok(_) => true,
-                        Err(e) => {
-                            println!("[NETWORK SERVER] -> Error while sending data to client {id} ({e}). Disconnecting client {id}.");
-                            self.server_handler.on_client_disconnected(*id, components);
-                        false // don't retain connection
-                        },
-                    }
-                },
-                Err(_) => {
-                    println!("[NETWORK SERVER] -> Unable to send udp to client {id} (Unable to find address). Disconnecting client {id}");
-                    self.server_handler.on_client_disconnected(*id, components);
-                        false // don't retain connection
-                },
-            }
-        });
-    }
-
-    pub fn send_udp_to_all_except(&mut self, except: u64, message: H::ServerMessages, components: &mut ComponentTable) {
-        // this gets a little tricky :
-        // as if connections fail, we have to disconnect the client, use retain method to loop through all values
-        // and use the retain closure to send messages, and if fail, do not retain the connection
-        let packet = Packet::from(message, 0);
-        let bytes = packet.as_bytes();
-        self.connections.retain(|id, connection| {
-            match *id == except {
-                true => true, // if it's the id of the skipped client, don't send data and retain
-                false => match connection.tcp_connection.peer_addr() {
-                    Ok(addr) => {
-                        // send data 
-                        match self.udp_socket.send_to(&bytes, addr) {
-                            Ok(_) => true,
-                            Err(e) => {
-                                println!("[NETWORK SERVER] -> Error while sending data to client {id} ({e}). Disconnecting client {id}.");
-                                self.server_handler.on_client_disconnected(*id, components);
-                            false // don't retain connection
-                            },
-                        }
-                    },
-                    Err(_) => {
-                        println!("[NETWORK SERVER] -> Unable to send udp to client {id} (Unable to find address). Disconnecting client {id}");
-                        self.server_handler.on_client_disconnected(*id, components);
-                            false // don't retain connection
-                    },
-                }
-            }
-        });
+    pub fn send_udp_to_all(&mut self, channel: Channel, priority: Priority, message: &H::ServerMessages) {
+        // the packet is built once, but each connection has its own channel headers
+        let packet = Packet::from(message, 0).stamped(self.tick);
+        for connection in self.connections.values_mut().filter(|connection| connection.handshake_done) {
+            connection.channels.send(channel, &packet, priority, self.time);
+        }
+    }
+
+    pub fn send_udp_to_all_except(&mut self, except: u64, channel: Channel, priority: Priority, message: &H::ServerMessages) {
+        let packet = Packet::from(message, 0).stamped(self.tick);
+        for (_, connection) in self.connections.iter_mut().filter(|(id, connection)| **id != except && connection.handshake_done) {
+            connection.channels.send(channel, &packet, priority, self.time);
+        }
+    }
+
+    /// Send a message returned by the handler. Udp messages go with the given priority, unless the message sets its own.
+    fn send_message(&mut self, message: ServerMessage<H::ServerMessages>, priority: Priority, components: &mut ComponentTable) {
+        match message {
+            ServerMessage::TcpToClient(client_id, message) => self.send_tcp_to_client(client_id, &message),
+            ServerMessage::TcpToAll(message) => self.send_tcp_to_all(&message),
+            ServerMessage::TcpToExcept(except_id, message) => self.send_tcp_to_all_except(except_id, &message),
+            ServerMessage::UdpToClient(client_id, message) => self.send_udp_to_client(client_id, Channel::Unreliable, priority, &message),
+            ServerMessage::UdpToAll(message) => self.send_udp_to_all(Channel::Unreliable, priority, &message),
+            ServerMessage::UdpToExcept(except_id, message) => self.send_udp_to_all_except(except_id, Channel::Unreliable, priority, &message),
+            ServerMessage::ChannelToClient(client_id, channel, message) => self.send_udp_to_client(client_id, channel, priority, &message),
+            ServerMessage::ChannelToAll(channel, message) => self.send_udp_to_all(channel, priority, &message),
+            ServerMessage::ChannelToExcept(except_id, channel, message) => self.send_udp_to_all_except(except_id, channel, priority, &message),
+            ServerMessage::Prioritized(priority, message) => self.send_message(*message, priority, components),
+            ServerMessage::Call(client_id, call) => self.call(client_id, call, components),
+            ServerMessage::Kick(client_id, reason) => self.kick(client_id, &reason, components),
+        }
+    }
+
+    /// Answer a call of a client, or complete one of ours. Only clients that completed the handshake can take part in calls.
+    fn handle_rpc(&mut self, client: u64, message: DefaultNetworkMessages, components: &mut ComponentTable) {
+        if !self.connections.get(&client).is_some_and(|connection| connection.handshake_done) {
+            println!("[NETWORK SERVER] -> Connection {client} sent a call before the handshake, ignoring it.");
+            return;
+        }
+        match message {
+            DefaultNetworkMessages::RpcRequest(id, method, request) => {
+                let answer = self.rpc.respond(client, id, method, &request, components);
+                self.send_default_message(client, &answer);
+            },
+            DefaultNetworkMessages::RpcResponse(id, response) => self.rpc.complete(client, id, Ok(response), components),
+            DefaultNetworkMessages::RpcFailed(id, error) => self.rpc.complete(client, id, Err(error), components),
+            _ => {},
+        }
     }
 
-    fn handle_default(&mut self, client: u64, default_message: DefaultNetworkMessages, components: &mut ComponentTable) {
+    fn handle_default(&mut self, client: u64, default_message: DefaultNetworkMessages, components: &mut ComponentTable) -> Vec<ServerMessage<H::ServerMessages>> {
         match default_message {
             DefaultNetworkMessages::Disconnecting => {
                 // disconnect client
-                self.disconnect_client(client, components);
+                self.disconnect_client(client, DisconnectReason::ClientShutDown, components);
+                Vec::new()
+            }
+            DefaultNetworkMessages::Ping(ping) => {
+                self.send_heartbeat(client, &DefaultNetworkMessages::Pong(ping, self.time));
+                Vec::new()
+            }
+            DefaultNetworkMessages::Pong(ping, _) => {
+                if let Some(connection) = self.connections.get_mut(&client) {
+                    connection.heartbeat.pong(ping, self.time);
+                }
+                Vec::new()
+            }
+            DefaultNetworkMessages::Hello(protocol) => {
+                self.handle_hello(client, protocol, components);
+                Vec::new()
+            }
+            DefaultNetworkMessages::Login(login) => self.handle_login(client, login, components),
+            DefaultNetworkMessages::PublicKey(key) => {
+                self.handle_public_key(client, key, components);
+                Vec::new()
             }
-            DefaultNetworkMessages::Welcome(_) => {}, // this is a server -> client message
+            DefaultNetworkMessages::SnapshotAck(snapshot) => {
+                self.replication.acknowledge(client, snapshot);
+                Vec::new()
+            }
+            DefaultNetworkMessages::PredictionInputs(first_tick, inputs) => {
+                self.handle_prediction_inputs(client, first_tick, inputs, components);
+                Vec::new()
+            }
+            message @ (DefaultNetworkMessages::RpcRequest(_, _, _) |
+            DefaultNetworkMessages::RpcResponse(_, _) |
+            DefaultNetworkMessages::RpcFailed(_, _)) => {
+                self.handle_rpc(client, message, components);
+                Vec::new()
+            }
+            // these are server -> client messages
+            DefaultNetworkMessages::Welcome(_, _, _) |
+            DefaultNetworkMessages::Rejected(_) |
+            DefaultNetworkMessages::Kicked(_) |
+            DefaultNetworkMessages::Snapshot(_, _, _, _) |
+            DefaultNetworkMessages::PredictionState(_, _) |
+            DefaultNetworkMessages::TickRate(_) => Vec::new(),
         }
     }
 
     fn get_incoming_udp(&mut self) -> Result<Vec<(u64, Packet)>, std::io::Error> {
-        let mut buffer = UdpBuffer::new();
         let mut result = Vec::new();
-        while let Some((packet, from)) = buffer.read_tcp(&mut self.udp_socket)? {
-            // check the packet is sent by correct user
-            match self.connections.get(&packet.get_sender()) {
-                Some(connection) => {
-                    if connection.peer_addr()?.ip() == from.ip() {
-                        result.push((packet.get_sender(), packet));
-                    }
-                    else {
-                        println!("[NETWORK SERVER] -> Packet was signed by id of another client !");
-                    }
+        while let Some((header, payload, from)) = self.udp_buffer.read_udp(
+            |buffer| self.transport.recv_from(buffer),
+            // fragments are checked before being kept, so nobody else can fill the reassembly memory
+            |token, sender, from| {
+                let genuine = self.connections.get(&sender).is_some_and(|connection| connection.handshake_done && connection.genuine(token, from));
+                if !genuine {
+                    self.rejected_datagrams += 1;
                 }
-                None => println!("[NETWORK SERVER] -> Packet was signed by unregistered id !"),
-            }
+                genuine
+            },
+            self.time,
+        )? {
+            // check the datagram is sent by correct user
+            let sender = header.sender();
+            let connection = match self.connections.get_mut(&sender) {
+                Some(connection) if connection.handshake_done => connection,
+                Some(_) => {
+                    println!("[NETWORK SERVER] -> Packet was signed by a connection that did not complete the handshake !");
+                    self.rejected_datagrams += 1;
+                    continue;
+                },
+                None => {
+                    println!("[NETWORK SERVER] -> Packet was signed by unregistered id !");
+                    self.rejected_datagrams += 1;
+                    continue;
+                },
+            };
+            let valid = connection.genuine(header.token(), from);
+            // encrypted connections also check the datagram was not changed, or replayed
+            // the channel gives back the packets that can be handled now
+            let packets = match valid {
+                true => connection.channels.receive(header, &payload, &mut connection.stats),
+                false => None,
+            };
+            let packets = match packets {
+                Some(packets) => packets,
+                None => {
+                    println!("[NETWORK SERVER] -> Packet was signed by id of another client !");
+                    connection.rejected_datagrams += 1;
+                    self.rejected_datagrams += 1;
+                    continue;
+                },
+            };
+            connection.udp_addr = Some(from);
+            connection.heartbeat.udp_received(self.time);
+            result.extend(packets.into_iter().map(|packet| (sender, packet)));
         };
         Ok(result)
     }
 
 }
 
-impl<H: ServerHandler + 'static> Updatable for Server<H> {
+impl<H: ServerHandler + 'static, T: ServerTransport> Updatable for Server<H, T> {
     fn update(&mut self, components: &mut ComponentTable, delta: f32, _user_data: &mut dyn std::any::Any) {
-        
+        self.time += delta as f64;
+        self.tick = (self.time * self.tick_rate) as u32;
+        self.transport.update(self.time);
+        self.update_lag_compensation(components);
+        if let Some(prediction) = &self.prediction {
+            prediction.elapse(delta as f64, components);
+        }
         // create a vec of any incoming messages to send
         let mut to_send_messages = Vec::new();
         let mut default_messages = Vec::new();
         // process any incoming requests
         loop {
-            match self.tcp_listener.accept() {
-                Ok((stream, adress)) => {
-                    to_send_messages.append(&mut self.handle_incoming_connection(stream, adress, components));
+            match self.transport.accept() {
+                Ok(Some((stream, adress))) => self.handle_incoming_connection(stream, adress),
+                Ok(None) => break, // no more incoming connections for now
+                Err(e) => {
+                    println!("[NETWORK SERVER] -> Error while accepting connection : {e}.");
+                    break;
                 },
-                Err(_e) => break, // no more incoming connections for now
             }
         }
 
@@ -295,18 +872,29 @@ impl<H: ServerHandler + 'static> Updatable for Server<H> {
                 Ok(packet) => packet,
                 Err(e) => {
                     println!("[NETWOTK SERVER] -> Error while receiving packets ({e}) from client {client_id}. Disconnectin client.");
-                    disconnecting.push(*client_id);
+                    disconnecting.push((*client_id, match e {
+                        FrameError::PacketTooLarge { .. } | FrameError::Corrupted => DisconnectReason::InvalidPacket,
+                        FrameError::ConnectionClosed | FrameError::Io(_) => DisconnectReason::ConnectionLost,
+                    }));
                     break;
                 },
             } {
+                connection.heartbeat.received(self.time);
                 match packet.is_default() {
                     true => match packet.into::<DefaultNetworkMessages>() {
                         Ok(message) => default_messages.push((*client_id, message)),
-                        Err(_e) => println!("[NETWORK CLIENT] -> Unable to deserialize packet !"),
+                        Err(_e) => {
+                            println!("[NETWORK CLIENT] -> Unable to deserialize packet !");
+                            connection.stats.deserialization_failed();
+                        },
                     }
+                    false if !connection.handshake_done => println!("[NETWORK SERVER] -> Connection {client_id} sent a message before the handshake, ignoring it."),
                     false => match packet.into() {
                         Ok(data) => to_send_messages.append(&mut self.server_handler.handle_message(*client_id, data, components)),
-                        Err(_) => println!("[NETWORK CLIENT] -> Unable to deserialize packet !"),
+                        Err(_) => {
+                            println!("[NETWORK CLIENT] -> Unable to deserialize packet !");
+                            connection.stats.deserialization_failed();
+                        },
                     }
                 }
             }
@@ -317,11 +905,17 @@ impl<H: ServerHandler + 'static> Updatable for Server<H> {
                 match packet.is_default() {
                     true => match packet.into::<DefaultNetworkMessages>() {
                         Ok(message) => default_messages.push((client, message)),
-                        Err(_e) => println!("[NETWORK CLIENT] -> Unable to deserialize packet !"),
+                        Err(_e) => {
+                            println!("[NETWORK CLIENT] -> Unable to deserialize packet !");
+                            self.deserialization_failed(client);
+                        },
                     }
                     false => match packet.into() {
                         Ok(data) => to_send_messages.append(&mut self.server_handler.handle_message(client, data, components)),
-                        Err(_) => println!("[NETWORK CLIENT] -> Unable to deserialize packet !"),
+                        Err(_) => {
+                            println!("[NETWORK CLIENT] -> Unable to deserialize packet !");
+                            self.deserialization_failed(client);
+                        },
                     }
                 }
             },
@@ -329,14 +923,18 @@ impl<H: ServerHandler + 'static> Updatable for Server<H> {
         };
 
         // disconnect error clients
-        for client_id in disconnecting.into_iter() {
-            self.disconnect_client(client_id, components);
+        for (client_id, reason) in disconnecting.into_iter() {
+            self.disconnect_client(client_id, reason, components);
         }
         // handle defaults
         for (client_id, message) in default_messages.into_iter() {
-            self.handle_default(client_id, message, components)
+            to_send_messages.append(&mut self.handle_default(client_id, message, components));
         }
 
+        self.update_heartbeats(components);
+        self.rpc.expire(self.time, components);
+        to_send_messages.append(&mut self.check_backpressure(components));
+
         // user update
         to_send_messages.append(&mut self.server_handler.update(components, delta));
         
@@ -344,16 +942,15 @@ impl<H: ServerHandler + 'static> Updatable for Server<H> {
 
         // send all messages we got from our diverse calls
         for return_message in to_send_messages {
-            match return_message {
-                ServerMessage::TcpToClient(client_id, message) => self.send_tcp_to_client(client_id, message, components),
-                ServerMessage::TcpToAll(message) => self.send_tcp_to_all(message, components),
-                ServerMessage::TcpToExcept(except_id, message) => self.send_tcp_to_all_except(except_id, message, components),
-                ServerMessage::UdpToClient(client_id, message) => self.send_udp_to_client(client_id, message, components),
-                ServerMessage::UdpToAll(message) => self.send_udp_to_all(message, components),
-                ServerMessage::UdpToExcept(except_id, message) => self.send_udp_to_all_except(except_id, message, components),
-            }
+            self.send_message(return_message, Priority::Normal, components);
         }
 
+        self.record_lag_compensation(components);
+        self.send_prediction_states(components);
+        self.send_snapshots(components);
+
+        self.flush_connections(components);
+        self.update_stats(components);
     }
 
     fn as_any(&self) -> &dyn std::any::Any {
@@ -365,85 +962,224 @@ impl<H: ServerHandler + 'static> Updatable for Server<H> {
     }
 }
 
+/// Random token for a new connection, from the os generator : it pins the udp address of the client, so it must not be guessed.
+fn new_session_token() -> u64 {
+    OsRng.next_u64()
+}
+
 pub enum ServerMessage<E: NetworkSerializable> {
     TcpToClient(u64, E),
     TcpToAll(E),
     TcpToExcept(u64, E),
+    /// Udp messages are sent on the unreliable channel.
     UdpToClient(u64, E),
     UdpToAll(E),
     UdpToExcept(u64, E),
+    /// Udp messages with the given delivery guarantees.
+    ChannelToClient(u64, Channel, E),
+    ChannelToAll(Channel, E),
+    ChannelToExcept(u64, Channel, E),
+    /// Send the udp message with the given priority, instead of the normal one (see `Priority`).
+    Prioritized(Priority, Box<ServerMessage<E>>),
+    /// Call a method on the client (see `Server::call`).
+    Call(u64, RpcCall),
+    /// Remove the client from the server, telling it the reason.
+    Kick(u64, String),
+}
+
+/// Answer of the server handler to a client asking to join.
+pub enum ConnectionResponse {
+    Accept,
+    /// Refuse the client. The reason is sent to it.
+    Reject(String),
 }
 
 pub trait ServerHandler where Self: Sized {
     type ServerMessages: NetworkSerializable;
     type ClientsMessages: NetworkSerializable;
+    /// Version of the game protocol. Clients with another version (or other messages) are rejected.
+    const PROTOCOL_VERSION: u32 = 0;
+    /// Called when a client with our protocol asks to join, with its address and the login it sent (see `Client::with_login`).
+    /// Banned clients are refused before getting here. Everyone is accepted by default.
+    fn on_connection_request(&mut self, _addr: SocketAddr, _login: &[u8], _components: &mut ComponentTable) -> ConnectionResponse {
+        ConnectionResponse::Accept
+    }
     fn on_client_connected(&mut self, client: u64, components: &mut ComponentTable) -> Vec<ServerMessage<Self::ServerMessages>>;
-    fn on_client_disconnected(&mut self, client: u64, components: &mut ComponentTable) -> Vec<ServerMessage<Self::ServerMessages>>;
+    fn on_client_disconnected(&mut self, client: u64, reason: DisconnectReason, components: &mut ComponentTable) -> Vec<ServerMessage<Self::ServerMessages>>;
+    /// Called when more bytes are waiting to be sent to a client than allowed (see `Server::with_max_queued_bytes`) :
+    /// it does not read fast enough. Nothing is done by default, the data keeps waiting.
+    fn on_client_backpressure(&mut self, _client: u64, _queued_bytes: usize, _components: &mut ComponentTable) -> Vec<ServerMessage<Self::ServerMessages>> {
+        Vec::new()
+    }
+    /// Called for each replicated entity and client, to decide if the entity is sent to the client.
+    /// `in_range` tells if it is close enough to a viewer of the client (see `Server::with_relevancy_distance`).
+    /// Entities that are always relevant, or only relevant to their owner, don't get here. Follows the distance by default.
+    fn is_relevant(&mut self, _client: u64, _network_id: u64, _entity: EntityRef, in_range: bool, _components: &mut ComponentTable) -> bool {
+        in_range
+    }
     fn update(&mut self, components: &mut ComponentTable, delta: f32) -> Vec<ServerMessage<Self::ServerMessages>>;
     fn handle_message(&mut self, client: u64, message: Self::ClientsMessages, components: &mut ComponentTable) -> Vec<ServerMessage<Self::ServerMessages>>;
 }
 
 
-/// Server representation of a Client
-pub struct Connection {
+/// Server representation of a Client. S is the stream of the transport.
+pub struct Connection<S: TransportStream = TcpStream> {
     id: u64,
-    tcp_connection: TcpStream,
+    tcp_connection: S,
     tcp_buffer: TcpBuffer,
-    incoming_packet: Option<Packet>,
+    /// tcp bytes waiting for the next flush
+    outgoing: OutgoingBuffer,
+    /// more bytes are waiting to be sent than the server allows
+    backpressure: bool,
+    bandwidth: Bandwidth,
+    /// the client sent a hello with our protocol, and can log in
+    hello_received: bool,
+    handshake_done: bool,
+    channels: ChannelEndpoint,
+    heartbeat: Heartbeat,
+    /// address the client sends its udp from, known after its first valid datagram
+    udp_addr: Option<SocketAddr>,
+    rejected_datagrams: u64,
+    /// encrypts the tcp packets once the keys are agreed on
+    tcp_cipher: Option<TcpCipher>,
+    stats: StatsRecorder,
 }
 
-impl Connection {
-    pub fn new(id: u64, tcp_connection: TcpStream) -> Result<Connection, std::io::Error> {
-        let udp = UdpSocket::bind(tcp_connection.local_addr()?)?;
-        udp.connect(tcp_connection.peer_addr()?)?;
-        udp.set_nonblocking(true)?;
-        Ok(Connection { 
+impl<S: TransportStream> Connection<S> {
+    /// The stream must not block : the transport takes care of it.
+    pub fn new(id: u64, tcp_connection: S, max_packet_size: usize) -> Connection<S> {
+        // udp goes through the server transport
+        Connection { 
             id: id,
             tcp_connection,
-            tcp_buffer: TcpBuffer::new(),
-            incoming_packet: None,
-        })
+            tcp_buffer: TcpBuffer::new(max_packet_size),
+            outgoing: OutgoingBuffer::new(),
+            backpressure: false,
+            bandwidth: Bandwidth::new(None),
+            hello_received: false,
+            handshake_done: false,
+            channels: ChannelEndpoint::new(),
+            heartbeat: Heartbeat::new(DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_IDLE_TIMEOUT, 0.),
+            udp_addr: None,
+            rejected_datagrams: 0,
+            tcp_cipher: None,
+            stats: StatsRecorder::new(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
     }
 
     pub fn peer_addr(&self) -> Result<SocketAddr, std::io::Error> {
         self.tcp_connection.peer_addr()
     }
 
-    pub fn get_incoming_packets(&mut self) -> Result<Vec<Packet>, std::io::Error> {
-         // start by reading if there are any incoming data
-         if self.tcp_buffer.read_tcp(&mut self.tcp_connection)? {
-            // let's read !
-            // check if there is an unfinished packet to read
-            let mut result = Vec::new();
-            if let Some(mut packet) = self.incoming_packet.take() {
-                // try complete the packet
-                if self.tcp_buffer.try_complete_packet(&mut packet) {
-                    result.push(packet);
-                }
-            }
-            loop {
-                let new_packet = self.tcp_buffer.try_read_packet();
-                match new_packet {
-                    Some(packet) => {
-                        if packet.awaiting_size() == 0 {
-                            // packet complete, keep getting more !
-                            result.push(packet)
-                        }
-                        else {
-                            // put the packet as awaiting and break
-                            self.incoming_packet = Some(packet);
-                            break;
-                        }
-                    },
-                    None => break,
-                }
-            }
-            Ok(result)
+    /// Address to send udp to : the one the client sends from once we know it, the one of its tcp stream before.
+    fn udp_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        match self.udp_addr {
+            Some(addr) => Ok(addr),
+            None => self.tcp_connection.peer_addr(),
         }
-        else {
-            // no new messages.
-            Ok(Vec::new())
+    }
+
+    /// Whether a datagram with this session token, from this address, comes from the client.
+    /// The first valid datagram pins the address of the client : it must come from the ip of its tcp stream.
+    fn genuine(&self, token: u64, from: SocketAddr) -> bool {
+        token == self.channels.token() && match self.udp_addr {
+            Some(addr) => addr == from,
+            None => self.peer_addr().is_ok_and(|addr| addr.ip() == from.ip()),
+        }
+    }
+
+    /// Number of udp datagrams signed with the id of this client that were thrown away,
+    /// because they had a wrong session token or came from another address.
+    pub fn rejected_datagrams(&self) -> u64 {
+        self.rejected_datagrams
+    }
+
+    /// Round trip time and other measures of the connection, estimated with pings.
+    pub fn quality(&self) -> ConnectionQuality {
+        self.heartbeat.quality()
+    }
+
+    /// Smoothed round trip time, in seconds.
+    pub fn rtt(&self) -> f64 {
+        self.heartbeat.quality().rtt
+    }
+
+    /// Variation of the round trip time, in seconds.
+    pub fn jitter(&self) -> f64 {
+        self.heartbeat.quality().jitter
+    }
+
+    /// Ratio of the recent pings that were lost, between 0 and 1.
+    pub fn packet_loss(&self) -> f64 {
+        self.heartbeat.quality().loss
+    }
+
+    /// Number of reliable udp messages sent to this connection that were not acknowledged yet.
+    pub fn pending_reliable_count(&self) -> usize {
+        self.channels.pending_count()
+    }
+
+    pub fn get_incoming_packets(&mut self) -> Result<Vec<Packet>, FrameError> {
+        let packets = self.tcp_buffer.read_packets(&mut self.tcp_connection)?;
+        let packets: Vec<Packet> = match &mut self.tcp_cipher {
+            Some(cipher) => packets.iter().map(|packet| cipher.open(packet).ok_or(FrameError::Corrupted)).collect::<Result<_, _>>()?,
+            None => packets,
+        };
+        for packet in packets.iter() {
+            self.stats.tcp_received(packet);
         }
+        Ok(packets)
+    }
+
+    /// Whether the connection is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.tcp_cipher.is_some()
+    }
+
+    /// Number of bytes waiting to be sent to the client : tcp bytes the os did not take yet, and udp messages not acknowledged yet.
+    pub fn queued_bytes(&self) -> usize {
+        self.outgoing.len() + self.channels.queued_bytes()
+    }
+
+    /// Whether more bytes are waiting to be sent than the server allows.
+    pub fn is_backpressured(&self) -> bool {
+        self.backpressure
+    }
+
+    /// Bytes per second budget of the connection, if any.
+    pub fn bandwidth_budget(&self) -> Option<u64> {
+        self.bandwidth.budget()
+    }
+
+    /// Number of unreliable messages to this client dropped because they waited too long for bandwidth.
+    pub fn dropped_messages(&self) -> u64 {
+        self.channels.dropped_messages()
+    }
+
+    /// Statistics of the connection now, the server time measuring the rates.
+    fn stats(&mut self, time: f64) -> ConnectionStats {
+        let mut stats = self.stats.snapshot(time);
+        stats.quality = self.heartbeat.quality();
+        stats.pending_reliable = self.channels.pending_count();
+        stats.queued_bytes = self.queued_bytes();
+        stats.dropped_messages = self.channels.dropped_messages();
+        stats.rejected_datagrams = self.rejected_datagrams;
+        stats
+    }
+
+    /// Queue a packet for the tcp stream, encrypted if the connection is. It is written on the next flush.
+    fn queue_packet(&mut self, packet: &Packet) {
+        self.stats.tcp_sent(packet);
+        // sealed now, so the packets are encrypted in the order they are written
+        let bytes = match &mut self.tcp_cipher {
+            Some(cipher) => self.outgoing.push(&cipher.seal(packet)),
+            None => self.outgoing.push(packet),
+        };
+        self.bandwidth.consume(bytes);
     }
 
 }
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+
+use crate::{NetworkSerializable, ByteReader};
+
+use super::{packet::Packet, channels::{Channel, CHANNEL_COUNT}, heartbeat::ConnectionQuality, interpolation::InterpolationStats};
+
+/// Time in seconds over which the per second rates are measured.
+const RATE_WINDOW: f64 = 1.;
+
+/// What a message is, to count them by type. Messages are enums, and the id is the one of the variant (see `NetworkSerializable`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MessageType {
+    /// a message of the engine, like pings or snapshots
+    Default(u64),
+    /// a message of the game
+    Game(u64),
+}
+
+impl MessageType {
+    fn of(packet: &Packet) -> MessageType {
+        // the variant id goes first. Messages that are not enums are counted by whatever their first bytes are.
+        let id = u64::deserialize_from(&mut ByteReader::new(&packet.body)).map(|(id, _)| id).unwrap_or(0);
+        match packet.is_default() {
+            true => MessageType::Default(id),
+            false => MessageType::Game(id),
+        }
+    }
+}
+
+/// Bytes and packets that went through a connection, or one of its channels.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrafficStats {
+    pub bytes_in: u64,
+    pub packets_in: u64,
+    pub bytes_out: u64,
+    pub packets_out: u64,
+    /// rates over the last second
+    pub bytes_in_per_second: f64,
+    pub packets_in_per_second: f64,
+    pub bytes_out_per_second: f64,
+    pub packets_out_per_second: f64,
+}
+
+impl TrafficStats {
+    fn received(&mut self, bytes: usize) {
+        self.bytes_in += bytes as u64;
+        self.packets_in += 1;
+    }
+
+    fn sent(&mut self, bytes: usize) {
+        self.bytes_out += bytes as u64;
+        self.packets_out += 1;
+    }
+
+    /// Measure the rates from the totals at the start of the window.
+    fn update_rates(&mut self, start: &TrafficStats, elapsed: f64) {
+        self.bytes_in_per_second = (self.bytes_in - start.bytes_in) as f64 / elapsed;
+        self.packets_in_per_second = (self.packets_in - start.packets_in) as f64 / elapsed;
+        self.bytes_out_per_second = (self.bytes_out - start.bytes_out) as f64 / elapsed;
+        self.packets_out_per_second = (self.packets_out - start.packets_out) as f64 / elapsed;
+    }
+}
+
+/// Statistics of a connection, since it was opened.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// packets of the tcp stream, without the encryption
+    pub tcp: TrafficStats,
+    /// udp datagrams, headers included. Fragments are counted one by one when sent, and once rebuilt when received.
+    pub udp: TrafficStats,
+    /// messages of each udp channel, resends included. See `ConnectionStats::channel`.
+    pub channels: [TrafficStats; CHANNEL_COUNT],
+    pub quality: ConnectionQuality,
+    /// reliable udp messages sent again because they were not acknowledged in time
+    pub resends: u64,
+    /// reliable udp messages waiting for their ack
+    pub pending_reliable: usize,
+    /// bytes waiting to be sent : tcp bytes the os did not take yet, and udp messages not acknowledged yet
+    pub queued_bytes: usize,
+    /// unreliable udp messages dropped because they waited too long for bandwidth
+    pub dropped_messages: u64,
+    /// udp datagrams thrown away, because they were not genuine
+    pub rejected_datagrams: u64,
+    /// messages received that could not be read as a message of the protocol
+    pub deserialization_failures: u64,
+    /// number of messages received, by type
+    pub messages_in: BTreeMap<MessageType, u64>,
+    /// number of messages sent, by type. Resends are not counted again.
+    pub messages_out: BTreeMap<MessageType, u64>,
+    /// on a client : underruns and overruns of the `Interpolated` entities
+    pub interpolation: InterpolationStats,
+}
+
+impl ConnectionStats {
+    /// The messages of a udp channel.
+    pub fn channel(&self, channel: Channel) -> &TrafficStats {
+        &self.channels[channel.id() as usize]
+    }
+
+    /// Number of messages of the given type received.
+    pub fn received(&self, message: MessageType) -> u64 {
+        self.messages_in.get(&message).copied().unwrap_or(0)
+    }
+
+    /// Number of messages of the given type sent.
+    pub fn sent(&self, message: MessageType) -> u64 {
+        self.messages_out.get(&message).copied().unwrap_or(0)
+    }
+
+    fn traffic_mut(&mut self) -> impl Iterator<Item = &mut TrafficStats> + '_ {
+        [&mut self.tcp, &mut self.udp].into_iter().chain(self.channels.iter_mut())
+    }
+}
+
+/// Singleton with the statistics of the connections, updated by the server and the client on each update.
+/// Both can run in the same world : the server fills the clients, the client fills the server.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkStats {
+    /// on a server : the connections of the clients, by id
+    pub clients: BTreeMap<u64, ConnectionStats>,
+    /// on a client : the connection to the server, while there is one
+    pub server: Option<ConnectionStats>,
+}
+
+impl NetworkStats {
+    pub fn client(&self, client: u64) -> Option<&ConnectionStats> {
+        self.clients.get(&client)
+    }
+
+    pub fn server(&self) -> Option<&ConnectionStats> {
+        self.server.as_ref()
+    }
+}
+
+/// Counts what goes through a connection. The counters not kept here (quality, queues...) are filled by the owner of the connection.
+pub(crate) struct StatsRecorder {
+    stats: ConnectionStats,
+    /// time the rate window started at, and the totals at that time
+    window_start: Option<f64>,
+    window_totals: Vec<TrafficStats>,
+}
+
+impl StatsRecorder {
+    pub(crate) fn new() -> StatsRecorder {
+        StatsRecorder {
+            stats: ConnectionStats::default(),
+            window_start: None,
+            window_totals: Vec::new(),
+        }
+    }
+
+    pub(crate) fn tcp_received(&mut self, packet: &Packet) {
+        self.stats.tcp.received(Packet::header_size() + packet.body.len());
+        *self.stats.messages_in.entry(MessageType::of(packet)).or_default() += 1;
+    }
+
+    pub(crate) fn tcp_sent(&mut self, packet: &Packet) {
+        self.stats.tcp.sent(Packet::header_size() + packet.body.len());
+        *self.stats.messages_out.entry(MessageType::of(packet)).or_default() += 1;
+    }
+
+    pub(crate) fn datagram_received(&mut self, bytes: usize) {
+        self.stats.udp.received(bytes);
+    }
+
+    pub(crate) fn datagram_sent(&mut self, bytes: usize) {
+        self.stats.udp.sent(bytes);
+    }
+
+    pub(crate) fn message_received(&mut self, channel: Channel, packet: &Packet, bytes: usize) {
+        self.stats.channels[channel.id() as usize].received(bytes);
+        *self.stats.messages_in.entry(MessageType::of(packet)).or_default() += 1;
+    }
+
+    pub(crate) fn message_sent(&mut self, channel: Channel, packet: &Packet, bytes: usize, resend: bool) {
+        self.stats.channels[channel.id() as usize].sent(bytes);
+        match resend {
+            true => self.stats.resends += 1,
+            false => *self.stats.messages_out.entry(MessageType::of(packet)).or_default() += 1,
+        }
+    }
+
+    pub(crate) fn deserialization_failed(&mut self) {
+        self.stats.deserialization_failures += 1;
+    }
+
+    /// The statistics now, with the rates measured again if the window is over.
+    pub(crate) fn snapshot(&mut self, time: f64) -> ConnectionStats {
+        match self.window_start {
+            Some(start) if time - start >= RATE_WINDOW => {
+                let elapsed = time - start;
+                for (traffic, start) in self.stats.traffic_mut().zip(self.window_totals.iter()) {
+                    traffic.update_rates(start, elapsed);
+                }
+                self.window_start = Some(time);
+                self.window_totals = self.stats.traffic_mut().map(|traffic| *traffic).collect();
+            },
+            Some(_) => {},
+            None => {
+                self.window_start = Some(time);
+                self.window_totals = self.stats.traffic_mut().map(|traffic| *traffic).collect();
+            },
+        }
+        self.stats.clone()
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream, UdpSocket, SocketAddr, Ipv4Addr, ToSocketAddrs},
+    sync::{Arc, Mutex, mpsc::{self, Sender, Receiver, TryRecvError}},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Reliable, ordered byte stream between a client and the server (tcp).
+/// Reads must never block : when there is no data, they return a `WouldBlock` error.
+pub trait TransportStream: Read + Write + 'static {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// What the server needs to talk to its clients : accept streams, and exchange datagrams with all of them.
+/// None of the methods can block.
+pub trait ServerTransport: 'static {
+    type Stream: TransportStream;
+    /// Called by the server at the start of each update, with the time since it started in seconds.
+    fn update(&mut self, _time: f64) {}
+    /// Returns the next incoming connection, if any.
+    fn accept(&mut self) -> io::Result<Option<(Self::Stream, SocketAddr)>>;
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()>;
+    /// Returns the next received datagram, if any. Datagrams bigger than the buffer are truncated.
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>>;
+}
+
+/// What a client needs to talk to the server : a stream, and datagrams with the server only.
+/// None of the methods can block.
+pub trait ClientTransport: 'static {
+    type Stream: TransportStream;
+    /// Called by the client at the start of each update, with the time since it started in seconds.
+    fn update(&mut self, _time: f64) {}
+    /// Start connecting to the server, trying the addresses in order until one works, for at most `timeout` seconds.
+    /// The result is given by `poll_connect`.
+    fn connect(&mut self, addrs: &[SocketAddr], timeout: f64);
+    /// Returns the stream to the server once the connection is done, or why it failed. None while still connecting.
+    fn poll_connect(&mut self) -> Option<io::Result<Self::Stream>>;
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()>;
+    /// Returns the next datagram received from the server, if any. Datagrams bigger than the buffer are truncated.
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>>;
+    /// Forget about the server : stop receiving its datagrams, and drop any connection in progress.
+    fn disconnect(&mut self);
+}
+
+/// Turn the would block errors of non blocking sockets into None.
+fn non_blocking<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl TransportStream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+/// Server transport over the os sockets : a tcp listener and a udp socket on the same address.
+pub struct OsServerTransport {
+    tcp_listener: TcpListener,
+    udp_socket: UdpSocket,
+}
+
+impl OsServerTransport {
+    /// Listen on the given address, like `"0.0.0.0:7777"` or `"[::]:7777"`.
+    /// If it resolves to several addresses, the first one that can be bound is used.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<OsServerTransport> {
+        let tcp_listener = TcpListener::bind(addr)?;
+        tcp_listener.set_nonblocking(true)?;
+        // same address, even if the port was picked by the os
+        let udp_socket = UdpSocket::bind(tcp_listener.local_addr()?)?;
+        udp_socket.set_nonblocking(true)?;
+        Ok(OsServerTransport {
+            tcp_listener,
+            udp_socket,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_listener.local_addr()
+    }
+}
+
+impl ServerTransport for OsServerTransport {
+    type Stream = TcpStream;
+
+    fn accept(&mut self) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        match non_blocking(self.tcp_listener.accept())? {
+            Some((stream, addr)) => {
+                stream.set_nonblocking(true)?;
+                Ok(Some((stream, addr)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.udp_socket.send_to(datagram, addr).map(|_| ())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        non_blocking(self.udp_socket.recv_from(buffer))
+    }
+}
+
+/// Client transport over the os sockets.
+/// Connecting is done on another thread, and the udp socket is bound on the same address as the tcp stream.
+pub struct OsClientTransport {
+    connecting_thread: Option<JoinHandle<io::Result<TcpStream>>>,
+    udp_connection: Option<UdpSocket>,
+}
+
+impl OsClientTransport {
+    pub fn new() -> OsClientTransport {
+        OsClientTransport {
+            connecting_thread: None,
+            udp_connection: None,
+        }
+    }
+
+    fn create_udp_from_tcp(tcp: &TcpStream) -> io::Result<UdpSocket> {
+        let udp = UdpSocket::bind(tcp.local_addr()?)?;
+        udp.connect(tcp.peer_addr()?)?;
+        udp.set_nonblocking(true)?;
+        Ok(udp)
+    }
+}
+
+impl Default for OsClientTransport {
+    fn default() -> Self {
+        OsClientTransport::new()
+    }
+}
+
+impl ClientTransport for OsClientTransport {
+    type Stream = TcpStream;
+
+    fn connect(&mut self, addrs: &[SocketAddr], timeout: f64) {
+        let addrs = addrs.to_vec();
+        // timeouts too big for a duration (like infinity) mean no timeout
+        let deadline = Duration::try_from_secs_f64(timeout.max(0.)).ok().and_then(|timeout| Instant::now().checked_add(timeout));
+        self.connecting_thread = Some(thread::spawn(move || {
+            let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+            for addr in addrs {
+                let result = match deadline {
+                    Some(deadline) => {
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        if remaining.is_zero() {
+                            return Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
+                        }
+                        TcpStream::connect_timeout(&addr, remaining)
+                    },
+                    None => TcpStream::connect(addr),
+                };
+                match result {
+                    Ok(stream) => {
+                        if let Err(e) = stream.set_nonblocking(true) {
+                            println!("[NETWORK CLIENT] -> Unable to set client as nonblocking ({e}). All client actions may freeze the engine.");
+                        }
+                        return Ok(stream);
+                    },
+                    Err(e) => error = e,
+                }
+            }
+            Err(error)
+        }));
+    }
+
+    fn poll_connect(&mut self) -> Option<io::Result<TcpStream>> {
+        match self.connecting_thread.take() {
+            Some(handle) if handle.is_finished() => {
+                let stream = match handle.join() {
+                    Ok(result) => result,
+                    Err(_) => Err(io::Error::other("connecting thread panicked")),
+                };
+                if let Ok(stream) = &stream {
+                    match OsClientTransport::create_udp_from_tcp(stream) {
+                        Ok(udp) => self.udp_connection = Some(udp),
+                        Err(e) => println!("[NETWORK CLIENT] -> Fail to use udp with the server : {e}."),
+                    }
+                }
+                Some(stream)
+            },
+            // move the thread back, as we moved it to get ownership of it
+            Some(handle) => {
+                self.connecting_thread = Some(handle);
+                None
+            },
+            None => None,
+        }
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        match &self.udp_connection {
+            Some(connection) => connection.send(datagram).map(|_| ()),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "no active udp connection")),
+        }
+    }
+
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match &self.udp_connection {
+            Some(connection) => non_blocking(connection.recv_from(buffer)),
+            None => Ok(None),
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.udp_connection = None;
+        // the thread can't be stopped, but its result will be dropped
+        self.connecting_thread = None;
+    }
+}
+
+/// In memory network, to run a server and clients in the same process without any socket.
+/// Everything sent is delivered on the next read, in order, so stepping the systems always gives the same result.
+/// Clones share the same network.
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    state: Arc<Mutex<LoopbackState>>,
+}
+
+#[derive(Default)]
+struct LoopbackState {
+    listeners: HashMap<SocketAddr, Sender<(LoopbackStream, SocketAddr)>>,
+    datagrams: HashMap<SocketAddr, Sender<(Vec<u8>, SocketAddr)>>,
+    next_port: u16,
+}
+
+/// First port given to the clients of a loopback network.
+const LOOPBACK_FIRST_CLIENT_PORT: u16 = 49152;
+
+impl LoopbackNetwork {
+    pub fn new() -> LoopbackNetwork {
+        LoopbackNetwork::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, LoopbackState> {
+        // the state is always left consistent, so a panic somewhere else does not matter
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Create a server transport listening on the given address of the network.
+    pub fn listen(&self, addr: SocketAddr) -> io::Result<LoopbackServerTransport> {
+        let mut state = self.state();
+        if state.listeners.contains_key(&addr) || state.datagrams.contains_key(&addr) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{addr} is already used on the loopback network")));
+        }
+        let (connection_sender, connections) = mpsc::channel();
+        let (datagram_sender, datagrams) = mpsc::channel();
+        state.listeners.insert(addr, connection_sender);
+        state.datagrams.insert(addr, datagram_sender);
+        Ok(LoopbackServerTransport {
+            network: self.clone(),
+            addr,
+            connections,
+            datagrams,
+        })
+    }
+
+    /// Create a client transport, with its own address on the network.
+    pub fn client(&self) -> LoopbackClientTransport {
+        let mut state = self.state();
+        let addr = loop {
+            let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), LOOPBACK_FIRST_CLIENT_PORT.wrapping_add(state.next_port));
+            state.next_port = state.next_port.wrapping_add(1);
+            if !state.datagrams.contains_key(&addr) && !state.listeners.contains_key(&addr) {
+                break addr;
+            }
+        };
+        let (datagram_sender, datagrams) = mpsc::channel();
+        state.datagrams.insert(addr, datagram_sender);
+        LoopbackClientTransport {
+            network: self.clone(),
+            addr,
+            server: None,
+            datagrams,
+            connect_result: None,
+        }
+    }
+
+    /// Deliver a datagram. Like udp, it is lost if nobody listens on the address.
+    fn send_datagram(&self, datagram: &[u8], from: SocketAddr, to: SocketAddr) {
+        if let Some(sender) = self.state().datagrams.get(&to) {
+            let _ = sender.send((datagram.to_vec(), from));
+        }
+    }
+
+    fn connect(&self, from: SocketAddr, to: SocketAddr) -> io::Result<LoopbackStream> {
+        let state = self.state();
+        let listener = match state.listeners.get(&to) {
+            Some(listener) => listener,
+            None => return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("nobody listens on {to}"))),
+        };
+        let (client_sender, server_receiver) = mpsc::channel();
+        let (server_sender, client_receiver) = mpsc::channel();
+        let server_stream = LoopbackStream::new(server_receiver, server_sender, from);
+        listener.send((server_stream, from)).map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(LoopbackStream::new(client_receiver, client_sender, to))
+    }
+
+    fn release(&self, addr: SocketAddr) {
+        let mut state = self.state();
+        state.listeners.remove(&addr);
+        state.datagrams.remove(&addr);
+    }
+}
+
+/// Stream of a loopback network. It is closed when the other end is dropped.
+pub struct LoopbackStream {
+    incoming: Receiver<Vec<u8>>,
+    outgoing: Sender<Vec<u8>>,
+    /// bytes received but not read yet
+    pending: Vec<u8>,
+    peer: SocketAddr,
+}
+
+impl LoopbackStream {
+    fn new(incoming: Receiver<Vec<u8>>, outgoing: Sender<Vec<u8>>, peer: SocketAddr) -> LoopbackStream {
+        LoopbackStream {
+            incoming,
+            outgoing,
+            pending: Vec::new(),
+            peer,
+        }
+    }
+}
+
+impl Read for LoopbackStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut closed = false;
+        loop {
+            match self.incoming.try_recv() {
+                Ok(bytes) => self.pending.extend_from_slice(&bytes),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    closed = true;
+                    break;
+                },
+            }
+        }
+        match (self.pending.is_empty(), closed) {
+            (true, true) => Ok(0),
+            (true, false) => Err(io::ErrorKind::WouldBlock.into()),
+            (false, _) => {
+                let amount = buf.len().min(self.pending.len());
+                buf[..amount].copy_from_slice(&self.pending[..amount]);
+                self.pending.drain(..amount);
+                Ok(amount)
+            },
+        }
+    }
+}
+
+impl Write for LoopbackStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.outgoing.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TransportStream for LoopbackStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+/// Server side of a loopback network.
+pub struct LoopbackServerTransport {
+    network: LoopbackNetwork,
+    addr: SocketAddr,
+    connections: Receiver<(LoopbackStream, SocketAddr)>,
+    datagrams: Receiver<(Vec<u8>, SocketAddr)>,
+}
+
+impl LoopbackServerTransport {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Copy a received datagram in the read buffer, truncating it like udp does.
+fn receive_datagram(received: Result<(Vec<u8>, SocketAddr), TryRecvError>, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+    match received {
+        Ok((datagram, from)) => {
+            let amount = buffer.len().min(datagram.len());
+            buffer[..amount].copy_from_slice(&datagram[..amount]);
+            Ok(Some((amount, from)))
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+impl ServerTransport for LoopbackServerTransport {
+    type Stream = LoopbackStream;
+
+    fn accept(&mut self) -> io::Result<Option<(LoopbackStream, SocketAddr)>> {
+        Ok(self.connections.try_recv().ok())
+    }
+
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.network.send_datagram(datagram, self.addr, addr);
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        receive_datagram(self.datagrams.try_recv(), buffer)
+    }
+}
+
+impl Drop for LoopbackServerTransport {
+    fn drop(&mut self) {
+        self.network.release(self.addr);
+    }
+}
+
+/// Client side of a loopback network. Connections are instant.
+pub struct LoopbackClientTransport {
+    network: LoopbackNetwork,
+    addr: SocketAddr,
+    server: Option<SocketAddr>,
+    datagrams: Receiver<(Vec<u8>, SocketAddr)>,
+    connect_result: Option<io::Result<LoopbackStream>>,
+}
+
+impl LoopbackClientTransport {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl ClientTransport for LoopbackClientTransport {
+    type Stream = LoopbackStream;
+
+    fn connect(&mut self, addrs: &[SocketAddr], _timeout: f64) {
+        let mut result = Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"));
+        for addr in addrs {
+            result = self.network.connect(self.addr, *addr);
+            if result.is_ok() {
+                self.server = Some(*addr);
+                break;
+            }
+        }
+        self.connect_result = Some(result);
+    }
+
+    fn poll_connect(&mut self) -> Option<io::Result<LoopbackStream>> {
+        self.connect_result.take()
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        match self.server {
+            Some(server) => {
+                self.network.send_datagram(datagram, self.addr, server);
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected to a server")),
+        }
+    }
+
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        loop {
+            match self.datagrams.try_recv() {
+                // like a connected udp socket, only listen to the server
+                Ok((_, from)) if Some(from) != self.server => continue,
+                received => return receive_datagram(received, buffer),
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.server = None;
+        self.connect_result = None;
+    }
+}
+
+impl Drop for LoopbackClientTransport {
+    fn drop(&mut self) {
+        self.network.release(self.addr);
+    }
+}
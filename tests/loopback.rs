@@ -0,0 +1,201 @@
+use std::net::{SocketAddr, Ipv4Addr};
+
+use gear::*;
+use gear_macros_derive::NetworkSerializable;
+
+/// Time stepped on each update of the world.
+const DELTA: f32 = 0.02;
+/// Updates after which we give up waiting for the messages.
+const MAX_UPDATES: usize = 500;
+
+#[derive(NetworkSerializable)]
+#[derive(Debug, Clone, PartialEq)]
+enum ChatMessages {
+    /// server to clients : everyone is here
+    Ready,
+    /// client to server : say a value to the others
+    Say(u64),
+    /// server to clients : the client said the value
+    Said(u64, u64),
+}
+
+/// Messages received by the clients of the world : (name of the client, message).
+#[derive(Default)]
+struct Inbox(Vec<(&'static str, ChatMessages)>);
+
+/// Tells everyone when all the clients are here, and relays what they say to the others.
+struct ChatServer {
+    expected: usize,
+    connected: usize,
+    ready_sent: bool,
+}
+
+impl ChatServer {
+    fn expecting(expected: usize) -> ChatServer {
+        ChatServer { expected, connected: 0, ready_sent: false }
+    }
+}
+
+impl ServerHandler for ChatServer {
+    type ServerMessages = ChatMessages;
+    type ClientsMessages = ChatMessages;
+
+    fn on_client_connected(&mut self, _client: u64, _components: &mut ComponentTable) -> Vec<ServerMessage<ChatMessages>> {
+        self.connected += 1;
+        Vec::new()
+    }
+
+    fn on_client_disconnected(&mut self, _client: u64, _reason: DisconnectReason, _components: &mut ComponentTable) -> Vec<ServerMessage<ChatMessages>> {
+        Vec::new()
+    }
+
+    fn update(&mut self, _components: &mut ComponentTable, _delta: f32) -> Vec<ServerMessage<ChatMessages>> {
+        match self.connected == self.expected && !self.ready_sent {
+            true => {
+                self.ready_sent = true;
+                vec![ServerMessage::TcpToAll(ChatMessages::Ready)]
+            },
+            false => Vec::new(),
+        }
+    }
+
+    fn handle_message(&mut self, client: u64, message: ChatMessages, _components: &mut ComponentTable) -> Vec<ServerMessage<ChatMessages>> {
+        match message {
+            ChatMessages::Say(value) => vec![ServerMessage::TcpToExcept(client, ChatMessages::Said(client, value))],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Says its value once the server is ready, and keeps what it receives in the inbox.
+struct ChatClient {
+    name: &'static str,
+    value: u64,
+}
+
+impl ClientHandler for ChatClient {
+    type ServerMessages = ChatMessages;
+    type ClientsMessages = ChatMessages;
+
+    fn on_connected(&mut self, _components: &mut ComponentTable) -> Vec<ClientMessage<ChatMessages>> {
+        Vec::new()
+    }
+
+    fn on_connection_failed(&mut self, _components: &mut ComponentTable) {
+        panic!("{} could not connect to the loopback server", self.name);
+    }
+
+    fn on_disconected(&mut self, _reason: DisconnectReason, _components: &mut ComponentTable) {}
+
+    fn update(&mut self, _components: &mut ComponentTable, _delta: f32) -> Vec<ClientMessage<ChatMessages>> {
+        Vec::new()
+    }
+
+    fn handle_message(&mut self, message: ChatMessages, components: &mut ComponentTable) -> Vec<ClientMessage<ChatMessages>> {
+        let result = match message {
+            ChatMessages::Ready => vec![ClientMessage::Tcp(ChatMessages::Say(self.value))],
+            _ => Vec::new(),
+        };
+        if let Some(inbox) = components.get_singleton_mut::<Inbox>() {
+            inbox.0.push((self.name, message));
+        }
+        result
+    }
+}
+
+fn server_addr() -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 7777)
+}
+
+fn client(network: &LoopbackNetwork, name: &'static str, value: u64) -> Client<ChatClient, LoopbackClientTransport> {
+    let mut client = Client::with_transport(ChatClient { name, value }, network.client());
+    client.try_connect(server_addr()).expect("the loopback address is valid");
+    client
+}
+
+#[test]
+fn two_clients_exchange_messages_through_the_server() {
+    let network = LoopbackNetwork::new();
+    let mut world = World::new();
+    world.components.add_singleton(Inbox::default());
+
+    let server = Server::with_transport(ChatServer::expecting(2), network.listen(server_addr()).unwrap(), 8);
+    world.register_system(System::new(Box::new(server), UpdateFrequency::PerFrame), 0);
+    world.register_system(System::new(Box::new(client(&network, "alice", 17)), UpdateFrequency::PerFrame), 1);
+    world.register_system(System::new(Box::new(client(&network, "bob", 42)), UpdateFrequency::PerFrame), 2);
+
+    let heard = |world: &World, name: &'static str| world.components.get_singleton::<Inbox>().unwrap().0.iter()
+        .find_map(|(receiver, message)| match (*receiver == name, message) {
+            (true, ChatMessages::Said(_, value)) => Some(*value),
+            _ => None,
+        });
+    for _ in 0..MAX_UPDATES {
+        world.update(DELTA, &mut EngineMessage::None);
+        if heard(&world, "alice").is_some() && heard(&world, "bob").is_some() {
+            break;
+        }
+    }
+    // each client heard the other one, and only once
+    assert_eq!(heard(&world, "alice"), Some(42));
+    assert_eq!(heard(&world, "bob"), Some(17));
+    let inbox = &world.components.get_singleton::<Inbox>().unwrap().0;
+    assert_eq!(inbox.iter().filter(|(_, message)| matches!(message, ChatMessages::Said(..))).count(), 2);
+}
+
+#[test]
+fn stats_count_the_messages_and_bytes() {
+    let network = LoopbackNetwork::new();
+    // the server and the client fill the same singleton : give them a world each
+    let mut server_world = World::new();
+    let mut client_world = World::new();
+    client_world.components.add_singleton(Inbox::default());
+
+    // no pings, so nothing else goes through once the messages are exchanged
+    let server = Server::with_transport(ChatServer::expecting(1), network.listen(server_addr()).unwrap(), 8)
+        .with_heartbeat(1000., 2000.);
+    let client = client(&network, "alice", 17).with_heartbeat(1000., 2000.);
+    server_world.register_system(System::new(Box::new(server), UpdateFrequency::PerFrame), 0);
+    client_world.register_system(System::new(Box::new(client), UpdateFrequency::PerFrame), 0);
+
+    let said = |world: &World| world.components.get_singleton::<NetworkStats>()
+        .and_then(|stats| stats.clients.values().next().map(|client| client.received(MessageType::Game(1))))
+        .unwrap_or(0);
+    for _ in 0..MAX_UPDATES {
+        server_world.update(DELTA, &mut EngineMessage::None);
+        client_world.update(DELTA, &mut EngineMessage::None);
+        if said(&server_world) > 0 {
+            break;
+        }
+    }
+    // let everything in flight arrive
+    for _ in 0..10 {
+        server_world.update(DELTA, &mut EngineMessage::None);
+        client_world.update(DELTA, &mut EngineMessage::None);
+    }
+
+    let server_stats = server_world.components.get_singleton::<NetworkStats>().unwrap();
+    let client_stats = client_world.components.get_singleton::<NetworkStats>().unwrap();
+    assert_eq!(server_stats.clients.len(), 1);
+    let on_server = server_stats.clients.values().next().unwrap();
+    let on_client = client_stats.server().expect("the client is connected");
+
+    // the server sent Ready (variant 0), the client answered with Say (variant 1)
+    assert_eq!(on_server.sent(MessageType::Game(0)), 1);
+    assert_eq!(on_client.received(MessageType::Game(0)), 1);
+    assert_eq!(on_client.sent(MessageType::Game(1)), 1);
+    assert_eq!(on_server.received(MessageType::Game(1)), 1);
+    assert_eq!(on_server.sent(MessageType::Game(1)) + on_server.sent(MessageType::Game(2)), 0);
+    assert_eq!(on_server.deserialization_failures + on_client.deserialization_failures, 0);
+
+    // every tcp packet sent was received, handshake included
+    assert_eq!(on_server.tcp.bytes_in, on_client.tcp.bytes_out);
+    assert_eq!(on_server.tcp.packets_in, on_client.tcp.packets_out);
+    assert_eq!(on_client.tcp.bytes_in, on_server.tcp.bytes_out);
+    assert_eq!(on_client.tcp.packets_in, on_server.tcp.packets_out);
+    // the messages counted are the packets that went through
+    assert_eq!(on_server.messages_in.values().sum::<u64>(), on_server.tcp.packets_in + on_server.channels.iter().map(|channel| channel.packets_in).sum::<u64>());
+    assert_eq!(on_client.messages_out.values().sum::<u64>(), on_client.tcp.packets_out + on_client.channels.iter().map(|channel| channel.packets_out).sum::<u64>() - on_client.resends);
+    // at least the game messages and their headers
+    let say_bytes = (Packet::header_size() + ChatMessages::Say(17).size()) as u64;
+    assert!(on_server.tcp.bytes_in >= say_bytes);
+}